@@ -0,0 +1,26 @@
+use std::process::Command;
+
+// 把编译时的git提交写入ENGINE_GIT_COMMIT，运行清单中记录引擎版本用
+// 工作区有未提交的修改时加上-dirty，不在git仓库中时不设置
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-changed=src");
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    };
+    let commit = match git(&["rev-parse", "--short=12", "HEAD"]) {
+        Some(commit) if !commit.is_empty() => commit,
+        _ => return,
+    };
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+        .map(|s| !s.is_empty())
+        .unwrap_or(false);
+    let suffix = if dirty { "-dirty" } else { "" };
+    println!("cargo:rustc-env=ENGINE_GIT_COMMIT={}{}", commit, suffix);
+}
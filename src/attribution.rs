@@ -0,0 +1,136 @@
+use std::fmt::Display;
+
+use super::strategy::order;
+
+// 订单盈亏归因，区分信号本身的收益和执行的成本
+// 理论盈亏 = (到达卖出时间时的中间价 - 信号产生时的中间价) * 数量
+// 买入滑点 = (买入均价 - 信号中间价) * 数量，逐档吃掉卖盘和延迟都会增加滑点
+// 卖出滑点 = 卖出时间的中间价 * 数量 - 卖出收入，卖出均价取整后会有误差，所以用实际收入
+// 净盈亏 = 理论盈亏 - 买入滑点 - 卖出滑点 - 税费
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Attribution {
+    pub theoretical: i128,
+    pub entry_slippage: i128,
+    pub exit_slippage: i128,
+    pub fees: i128,
+}
+
+impl Attribution {
+    pub fn net(&self) -> i128 {
+        self.theoretical - self.entry_slippage - self.exit_slippage - self.fees
+    }
+
+    pub fn add(&mut self, other: &Attribution) {
+        self.theoretical += other.theoretical;
+        self.entry_slippage += other.entry_slippage;
+        self.exit_slippage += other.exit_slippage;
+        self.fees += other.fees;
+    }
+}
+
+impl Display for Attribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "theoretical:{} entry slippage:{} exit slippage:{} fees:{} net:{}",
+            self.theoretical,
+            self.entry_slippage,
+            self.exit_slippage,
+            self.fees,
+            self.net()
+        )
+    }
+}
+
+// 只计算已经卖完的订单
+pub fn of(o: &order) -> Option<Attribution> {
+    if o.left > 0 || o.signal_mid == 0 || o.exit_mid == 0 {
+        return None;
+    }
+    let volume = o.volume as i128;
+    let revenue = o.profit + o.open_price as i128 * volume;
+    Some(Attribution {
+        theoretical: (o.exit_mid as i128 - o.signal_mid as i128) * volume,
+        entry_slippage: (o.open_price as i128 - o.signal_mid as i128) * volume,
+        exit_slippage: o.exit_mid as i128 * volume - revenue,
+        fees: (o.tax + o.commission) as i128,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::record_sell;
+    use crate::tick::default_dt;
+
+    fn bought(open_price: u64, volume: usize, signal_mid: u64) -> order {
+        order {
+            code: "601012.SH".to_string(),
+            open_price,
+            time: default_dt(),
+            selt_time: default_dt(),
+            volume,
+            sell_price: 0,
+            sell_arrive: default_dt(),
+            sell_volume: volume,
+            amending: false,
+            cancelled: false,
+            want_sell_all: false,
+            sell_price_avg: 0,
+            left: volume,
+            profit: 0,
+            tax: 0,
+            commission: 0,
+            signal_mid,
+            exit_mid: 0,
+        }
+    }
+
+    #[test]
+    fn open_order_has_no_attribution() {
+        let mut o = bought(400100, 1000, 400000);
+        o.exit_mid = 401000;
+        record_sell(&mut o, 400, 401000, default_dt());
+        assert!(of(&o).is_none());
+    }
+
+    #[test]
+    fn net_reconciles_with_profit() {
+        let mut o = bought(400100, 1000, 400000);
+        o.exit_mid = 402050;
+        record_sell(&mut o, 400, 402000, default_dt());
+        record_sell(&mut o, 600, 401900, default_dt());
+        let a = of(&o).unwrap();
+        assert_eq!(a.theoretical, 2050 * 1000);
+        assert_eq!(a.entry_slippage, 100 * 1000);
+        assert_eq!(a.exit_slippage, 50 * 400 + 150 * 600);
+        assert_eq!(a.fees, (o.tax + o.commission) as i128);
+        assert_eq!(a.net(), o.profit - (o.tax + o.commission) as i128);
+    }
+
+    #[test]
+    fn net_reconciles_when_average_is_truncated() {
+        // 卖出均价除不尽
+        let mut o = bought(400100, 300, 400000);
+        o.exit_mid = 401000;
+        record_sell(&mut o, 100, 401001, default_dt());
+        record_sell(&mut o, 200, 401000, default_dt());
+        assert_eq!(o.sell_price_avg, 401000);
+        let a = of(&o).unwrap();
+        assert_eq!(a.net(), o.profit - (o.tax + o.commission) as i128);
+    }
+
+    #[test]
+    fn add_sums_orders() {
+        let mut total = Attribution::default();
+        for (open, mid) in [(400100, 401000), (400200, 399000)] {
+            let mut o = bought(open, 1000, 400000);
+            o.exit_mid = mid;
+            record_sell(&mut o, 1000, mid, default_dt());
+            total.add(&of(&o).unwrap());
+        }
+        assert_eq!(total.theoretical, 0);
+        assert_eq!(total.entry_slippage, (100 + 200) * 1000);
+        assert_eq!(total.exit_slippage, 0);
+    }
+}
@@ -0,0 +1,263 @@
+use chrono::{DateTime, Duration, FixedOffset};
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt::Display;
+
+use super::tick::Tick;
+
+// 由若干tick聚合成的K线
+// 开高低收取自nPrice，成交量/成交额取自TotalVolume/TotalTurnover的增量
+#[derive(Debug, Clone)]
+pub struct Bar {
+    pub code: String,
+    pub start: DateTime<FixedOffset>, // 第一个tick的时间，时间K线为所在周期的起点
+    pub end: DateTime<FixedOffset>,   // 最后一个tick的时间
+    pub open: u64,
+    pub high: u64,
+    pub low: u64,
+    pub close: u64,
+    pub volume: u64,
+    pub turnover: u64,
+    pub ticks: u64,
+}
+
+// 聚合方式
+// time: 按时间，size为秒数，如1/60/300
+// volume: 按成交量，size为股数
+// tick: 按tick个数
+// turnover: 按成交额，单位与TotalTurnover一致
+#[derive(Debug, Deserialize, Clone)]
+pub struct BarConfig {
+    pub kind: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BarKind {
+    Time(i64),
+    Volume(u64),
+    Ticks(u64),
+    Turnover(u64),
+}
+
+pub fn new_bar_kind(conf: &BarConfig) -> Result<BarKind, Box<dyn Error>> {
+    if conf.size == 0 {
+        return Err(format!("bar size of {} must be greater than 0", conf.kind).into());
+    }
+    match conf.kind.as_str() {
+        "time" => Ok(BarKind::Time(conf.size as i64)),
+        "volume" => Ok(BarKind::Volume(conf.size)),
+        "tick" => Ok(BarKind::Ticks(conf.size)),
+        "turnover" => Ok(BarKind::Turnover(conf.size)),
+        _ => Err(format!("unknown bar kind: {}", conf.kind).into()),
+    }
+}
+
+pub struct BarAggregator {
+    pub kind: BarKind,
+    current: Option<Bar>,
+    last_volume: Option<u64>,
+    last_turnover: Option<u64>,
+}
+
+pub fn new_bar_aggregator(kind: BarKind) -> BarAggregator {
+    BarAggregator {
+        kind,
+        current: None,
+        last_volume: None,
+        last_turnover: None,
+    }
+}
+
+impl Display for Bar {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}~{} open:{} high:{} low:{} close:{} volume:{} turnover:{} ticks:{}",
+            self.code,
+            self.start,
+            self.end,
+            self.open,
+            self.high,
+            self.low,
+            self.close,
+            self.volume,
+            self.turnover,
+            self.ticks
+        )
+    }
+}
+
+impl BarAggregator {
+    // 时间K线所在周期的起点
+    fn period_start(&self, dt: DateTime<FixedOffset>, secs: i64) -> DateTime<FixedOffset> {
+        dt - Duration::seconds(dt.timestamp().rem_euclid(secs))
+            - Duration::nanoseconds(dt.timestamp_subsec_nanos() as i64)
+    }
+
+    // 输入一个tick，返回已经完成的K线
+    pub fn update(&mut self, tick: &Tick) -> Option<Bar> {
+        // 成交量/成交额取累计值的增量，第一个tick取累计值本身
+        let volume = tick
+            .TotalVolume
+            .saturating_sub(self.last_volume.unwrap_or(0));
+        let turnover = tick
+            .TotalTurnover
+            .saturating_sub(self.last_turnover.unwrap_or(0));
+        self.last_volume = Some(tick.TotalVolume);
+        self.last_turnover = Some(tick.TotalTurnover);
+        // 集合竞价期间没有成交价
+        if tick.nPrice == 0 {
+            return None;
+        }
+
+        let mut finished = None;
+        if let BarKind::Time(secs) = self.kind {
+            let start = self.period_start(tick.dt, secs);
+            if let Some(bar) = &self.current {
+                if bar.start != start {
+                    finished = self.current.take();
+                }
+            }
+            if self.current.is_none() {
+                self.current = Some(self.new_bar(tick, start));
+            }
+        } else if self.current.is_none() {
+            self.current = Some(self.new_bar(tick, tick.dt));
+        }
+
+        let bar = self.current.as_mut().unwrap();
+        bar.end = tick.dt;
+        bar.high = bar.high.max(tick.nPrice);
+        bar.low = bar.low.min(tick.nPrice);
+        bar.close = tick.nPrice;
+        bar.volume += volume;
+        bar.turnover += turnover;
+        bar.ticks += 1;
+
+        let full = match self.kind {
+            BarKind::Time(_) => false,
+            BarKind::Volume(size) => bar.volume >= size,
+            BarKind::Ticks(size) => bar.ticks >= size,
+            BarKind::Turnover(size) => bar.turnover >= size,
+        };
+        if full {
+            finished = self.current.take();
+        }
+        finished
+    }
+
+    // 数据结束时取出未完成的K线
+    pub fn flush(&mut self) -> Option<Bar> {
+        self.current.take()
+    }
+
+    fn new_bar(&self, tick: &Tick, start: DateTime<FixedOffset>) -> Bar {
+        Bar {
+            code: tick.chWindCode.clone(),
+            start,
+            end: tick.dt,
+            open: tick.nPrice,
+            high: tick.nPrice,
+            low: tick.nPrice,
+            close: tick.nPrice,
+            volume: 0,
+            turnover: 0,
+            ticks: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::empty_tick;
+    use crate::tick::{get_time, trade_date};
+
+    fn tick(ntime: u64, price: u64, volume: u64) -> Tick {
+        let mut t = empty_tick("601012.SH");
+        t.nTime = ntime;
+        t.dt = get_time(trade_date(0).unwrap(), ntime).unwrap();
+        t.nPrice = price;
+        t.TotalVolume = volume;
+        t.TotalTurnover = volume * price / 10000;
+        t
+    }
+
+    fn kind(kind: &str, size: u64) -> Result<BarKind, Box<dyn Error>> {
+        new_bar_kind(&BarConfig {
+            kind: kind.to_string(),
+            size,
+        })
+    }
+
+    #[test]
+    fn bar_kinds() {
+        assert_eq!(kind("time", 60).unwrap(), BarKind::Time(60));
+        assert_eq!(kind("volume", 1000).unwrap(), BarKind::Volume(1000));
+        assert_eq!(kind("tick", 10).unwrap(), BarKind::Ticks(10));
+        assert_eq!(kind("turnover", 100).unwrap(), BarKind::Turnover(100));
+        assert!(kind("time", 0).is_err());
+        assert!(kind("range", 10).is_err());
+    }
+
+    #[test]
+    fn time_bars() {
+        let mut agg = new_bar_aggregator(BarKind::Time(60));
+        assert!(agg.update(&tick(93000000, 400000, 100)).is_none());
+        assert!(agg.update(&tick(93020000, 410000, 300)).is_none());
+        assert!(agg.update(&tick(93050000, 390000, 600)).is_none());
+        let bar = agg.update(&tick(93100000, 395000, 1000)).unwrap();
+        assert_eq!(bar.start, tick(93000000, 0, 0).dt);
+        assert_eq!(bar.end, tick(93050000, 0, 0).dt);
+        assert_eq!((bar.open, bar.high, bar.low, bar.close), (400000, 410000, 390000, 390000));
+        assert_eq!((bar.volume, bar.ticks), (600, 3));
+        // 周期起点对齐到整分钟，不是第一个tick的时间
+        let bar = agg.update(&tick(93203000, 400000, 1200)).unwrap();
+        assert_eq!(bar.start, tick(93100000, 0, 0).dt);
+        assert_eq!((bar.volume, bar.ticks), (400, 1));
+        let bar = agg.flush().unwrap();
+        assert_eq!(bar.start, tick(93200000, 0, 0).dt);
+        assert_eq!(bar.end, tick(93203000, 0, 0).dt);
+        assert!(agg.flush().is_none());
+    }
+
+    #[test]
+    fn auction_ticks_are_skipped() {
+        let mut agg = new_bar_aggregator(BarKind::Ticks(2));
+        // 集合竞价的成交量不计入第一根K线
+        assert!(agg.update(&tick(92500000, 0, 500)).is_none());
+        assert!(agg.update(&tick(93000000, 400000, 600)).is_none());
+        let bar = agg.update(&tick(93003000, 401000, 800)).unwrap();
+        assert_eq!((bar.open, bar.close, bar.volume, bar.ticks), (400000, 401000, 300, 2));
+    }
+
+    #[test]
+    fn volume_bars() {
+        let mut agg = new_bar_aggregator(BarKind::Volume(1000));
+        assert!(agg.update(&tick(93000000, 400000, 600)).is_none());
+        let bar = agg.update(&tick(93003000, 401000, 1500)).unwrap();
+        assert_eq!((bar.volume, bar.ticks), (1500, 2));
+        // 超出的部分不会拆到下一根K线
+        assert!(agg.update(&tick(93006000, 402000, 1600)).is_none());
+        let bar = agg.flush().unwrap();
+        assert_eq!((bar.open, bar.volume, bar.ticks), (402000, 100, 1));
+    }
+
+    #[test]
+    fn turnover_bars() {
+        let mut agg = new_bar_aggregator(BarKind::Turnover(50000));
+        assert!(agg.update(&tick(93000000, 400000, 1000)).is_none());
+        let bar = agg.update(&tick(93003000, 400000, 2000)).unwrap();
+        assert_eq!((bar.turnover, bar.ticks), (80000, 2));
+    }
+
+    #[test]
+    fn volume_drop_counts_as_zero() {
+        let mut agg = new_bar_aggregator(BarKind::Ticks(3));
+        agg.update(&tick(93000000, 400000, 1000));
+        agg.update(&tick(93003000, 400000, 900));
+        let bar = agg.update(&tick(93006000, 400000, 1200)).unwrap();
+        assert_eq!(bar.volume, 1300);
+    }
+}
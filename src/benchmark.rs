@@ -0,0 +1,275 @@
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt::Display;
+
+use super::feed::open_tick_feed;
+use super::schema::SchemaConfig;
+
+// 和基准比较策略的收益
+// 基准默认为每个交易的股票从开盘价开始持有不动，每个股票分别比较，也可以是指数的tick文件
+// 收益率按资金曲线的采样间隔计算：策略收益率 = 盈亏的变化 / 本金，基准收益率 = 价格的变化 / 价格
+// 没有基准价格的采样（股票还没有tick，或者指数还没有开始）跳过，盈亏的变化计入下一个采样
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct BenchmarkConfig {
+    pub index_data: String, // 指数tick文件，为空时和持有不动比较
+    pub capital: u64,       // 计算策略收益率的本金，0表示按开盘价买入buy_volume股的金额
+}
+
+// 一个股票持有不动的基准，第一个tick的开盘价买入
+// prices为资金曲线每次采样时这个股票最新的价格，和资金曲线一一对应，还没有tick时为None
+pub struct BuyAndHold {
+    pub open: u64,
+    pub prices: Vec<Option<u64>>,
+}
+
+// samples为之前资金曲线的采样次数，这些采样时还没有这个股票的价格
+pub fn new_buy_and_hold(open: u64, samples: usize) -> BuyAndHold {
+    BuyAndHold {
+        open,
+        prices: vec![None; samples],
+    }
+}
+
+// 一年的交易日数，用于年化
+const TRADING_DAYS: f64 = 252.0;
+
+pub struct DailyReturn {
+    pub date: NaiveDate,
+    pub strategy: f64,
+    pub benchmark: f64,
+}
+
+pub struct Comparison {
+    pub strategy_return: f64,
+    pub benchmark_return: f64,
+    pub alpha: f64, // 年化
+    pub beta: f64,
+    pub information_ratio: f64, // 年化
+    pub daily: Vec<DailyReturn>,
+}
+
+impl Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "strategy return:{:.6} benchmark return:{:.6} excess return:{:.6} alpha:{:.6} beta:{:.6} information ratio:{:.6}",
+            self.strategy_return,
+            self.benchmark_return,
+            self.strategy_return - self.benchmark_return,
+            self.alpha,
+            self.beta,
+            self.information_ratio
+        )?;
+        for d in &self.daily {
+            write!(
+                f,
+                "\n  {} strategy:{:.6} benchmark:{:.6} excess:{:.6}",
+                d.date,
+                d.strategy,
+                d.benchmark,
+                d.strategy - d.benchmark
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// 按时间排序的(时间, 价格)
+pub type PriceSeries = Vec<(DateTime<FixedOffset>, u64)>;
+
+// 读取指数的价格序列，和tick数据一样按schema的列名映射读取，date为数据中没有日期时使用的交易日
+pub fn read_index(
+    path: &str,
+    read_ahead: usize,
+    schema: &SchemaConfig,
+    date: NaiveDate,
+) -> Result<PriceSeries, Box<dyn Error>> {
+    let mut res = Vec::new();
+    for tick in open_tick_feed(path, read_ahead, schema, date)? {
+        let tick = tick.map_err(|e| e.to_string())?;
+        if tick.nPrice > 0 {
+            res.push((tick.dt, tick.nPrice));
+        }
+    }
+    Ok(res)
+}
+
+// 不晚于dt的最后一个价格
+pub fn price_at(series: &[(DateTime<FixedOffset>, u64)], dt: DateTime<FixedOffset>) -> Option<u64> {
+    let idx = series.partition_point(|(t, _)| *t <= dt);
+    if idx == 0 {
+        return None;
+    }
+    Some(series[idx - 1].1)
+}
+
+fn mean(v: &[f64]) -> f64 {
+    v.iter().sum::<f64>() / v.len() as f64
+}
+
+// equity为(时间, 累计盈亏)，prices为同一时间的基准价格，没有价格或价格为0的采样跳过
+// base为基准的起始价格
+pub fn compare(
+    equity: &[(DateTime<FixedOffset>, i128)],
+    prices: &[Option<u64>],
+    base: u64,
+    capital: u64,
+) -> Option<Comparison> {
+    if equity.len() != prices.len() || base == 0 || capital == 0 {
+        return None;
+    }
+    let capital = capital as f64;
+    let mut rs = Vec::new();
+    let mut rb = Vec::new();
+    let mut daily: Vec<DailyReturn> = Vec::new();
+    let (mut last_pnl, mut last_price) = (0.0, base as f64);
+    for ((dt, pnl), price) in equity.iter().zip(prices) {
+        let price = match price {
+            Some(p) if *p > 0 => *p as f64,
+            _ => continue,
+        };
+        let pnl = *pnl as f64;
+        let s = (pnl - last_pnl) / (capital + last_pnl);
+        let b = (price - last_price) / last_price;
+        rs.push(s);
+        rb.push(b);
+        let date = dt.date_naive();
+        match daily.last_mut() {
+            Some(d) if d.date == date => {
+                d.strategy = (1.0 + d.strategy) * (1.0 + s) - 1.0;
+                d.benchmark = (1.0 + d.benchmark) * (1.0 + b) - 1.0;
+            }
+            _ => daily.push(DailyReturn {
+                date,
+                strategy: s,
+                benchmark: b,
+            }),
+        }
+        last_pnl = pnl;
+        last_price = price;
+    }
+    if rs.len() < 2 {
+        return None;
+    }
+    let (ms, mb) = (mean(&rs), mean(&rb));
+    let mut cov = 0.0;
+    let mut var = 0.0;
+    for (s, b) in rs.iter().zip(&rb) {
+        cov += (s - ms) * (b - mb);
+        var += (b - mb) * (b - mb);
+    }
+    let beta = if var > 0.0 { cov / var } else { 0.0 };
+    let excess: Vec<f64> = rs.iter().zip(&rb).map(|(s, b)| s - b).collect();
+    let me = mean(&excess);
+    let te = (excess.iter().map(|e| (e - me) * (e - me)).sum::<f64>() / excess.len() as f64).sqrt();
+    // 按每天的采样次数年化
+    let periods = rs.len() as f64 / daily.len() as f64 * TRADING_DAYS;
+    Some(Comparison {
+        strategy_return: last_pnl / capital,
+        benchmark_return: last_price / base as f64 - 1.0,
+        alpha: (ms - beta * mb) * periods,
+        beta,
+        information_ratio: if te > 0.0 { me / te * periods.sqrt() } else { 0.0 },
+        daily,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick::TimeOfDay;
+
+    fn at(date: NaiveDate, ntime: u64) -> DateTime<FixedOffset> {
+        TimeOfDay::from_ntime(ntime).unwrap().on(date)
+    }
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2021, 11, d).unwrap()
+    }
+
+    #[test]
+    fn read_index_with_schema() {
+        let path = std::env::temp_dir().join(format!("benchmark_test_index_{}.csv", std::process::id()));
+        std::fs::write(&path, "time,last\n93000000,50000\n93003000,0\n93006000,50100\n").unwrap();
+        let schema = SchemaConfig {
+            symbol: "000300.SH".to_string(),
+            columns: vec![
+                ("nTime".to_string(), "time".to_string()),
+                ("nPrice".to_string(), "last".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            ..SchemaConfig::default()
+        };
+        let series = read_index(path.to_str().unwrap(), 16, &schema, day(1)).unwrap();
+        // 价格为0的跳过
+        assert_eq!(series, vec![(at(day(1), 93000000), 50000), (at(day(1), 93006000), 50100)]);
+        // 按默认的列名读取时找不到代码列
+        assert!(read_index(path.to_str().unwrap(), 16, &SchemaConfig::default(), day(1)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn holding_the_benchmark_has_no_excess_return() {
+        // 按100元买入1000股一直持有，盈亏和基准的价格同步变化
+        let prices = [100_0000u64, 101_0000, 99_0000, 103_0000];
+        let equity: Vec<_> = prices
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (at(day(1), 93000000 + i as u64 * 100000), (*p as i128 - 100_0000) * 1000))
+            .collect();
+        let prices: Vec<_> = prices.iter().map(|p| Some(*p)).collect();
+        let c = compare(&equity, &prices, 100_0000, 100_0000 * 1000).unwrap();
+        assert!((c.strategy_return - 0.03).abs() < 1e-9);
+        assert!((c.benchmark_return - 0.03).abs() < 1e-9);
+        assert!((c.beta - 1.0).abs() < 1e-9);
+        assert!(c.alpha.abs() < 1e-9);
+        assert_eq!(c.daily.len(), 1);
+    }
+
+    #[test]
+    fn samples_without_price_are_skipped() {
+        let equity = vec![
+            (at(day(1), 93000000), 0),
+            (at(day(1), 93100000), 500),
+            (at(day(1), 93200000), 1000),
+            (at(day(1), 93300000), 2000),
+        ];
+        let prices = vec![None, Some(0), Some(110), Some(121)];
+        let c = compare(&equity, &prices, 100, 10000).unwrap();
+        assert!(c.strategy_return.is_finite() && c.alpha.is_finite() && c.beta.is_finite());
+        assert!((c.strategy_return - 0.2).abs() < 1e-9);
+        assert!((c.benchmark_return - 0.21).abs() < 1e-9);
+        // 跳过的采样的盈亏计入下一个采样
+        assert!((c.daily[0].strategy - 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn returns_are_split_by_trading_day() {
+        let equity = vec![
+            (at(day(1), 100000000), 0),
+            (at(day(1), 140000000), 100),
+            (at(day(2), 100000000), 100),
+            (at(day(2), 140000000), 300),
+        ];
+        let prices = vec![Some(100), Some(110), Some(110), Some(99)];
+        let c = compare(&equity, &prices, 100, 1000).unwrap();
+        assert_eq!(c.daily.len(), 2);
+        assert_eq!(c.daily[0].date, day(1));
+        assert_eq!(c.daily[1].date, day(2));
+        assert!((c.daily[0].strategy - 0.1).abs() < 1e-9);
+        assert!((c.daily[0].benchmark - 0.1).abs() < 1e-9);
+        assert!((c.daily[1].strategy - 200.0 / 1100.0).abs() < 1e-9);
+        assert!((c.daily[1].benchmark + 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn not_enough_data() {
+        let equity = vec![(at(day(1), 93000000), 0), (at(day(1), 93100000), 10)];
+        assert!(compare(&equity, &[Some(100), Some(101)], 0, 1000).is_none());
+        assert!(compare(&equity, &[None, Some(101)], 100, 1000).is_none());
+        assert!(compare(&equity, &[Some(100)], 100, 1000).is_none());
+    }
+}
@@ -0,0 +1,410 @@
+use chrono::NaiveDate;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use super::entrust::entrust;
+use super::feed::FeedError;
+use super::tick::{self, Tick, TimeOfDay};
+use super::transaction::transaction;
+
+// 用逐笔委托和逐笔成交重建完整的委托簿
+// 逐笔委托加入挂单，撤单和成交减少挂单（成交通过AskOrder/BidOrder找到对应的委托）
+// 按固定的时间间隔输出10档快照，和tick数据一样交给策略处理
+
+
+struct Resting {
+    buy: bool,
+    price: u64,
+    volume: u64,
+}
+
+pub struct OrderBook {
+    orders: HashMap<u64, Resting>,
+    bids: BTreeMap<u64, u64>, // 价格 -> 挂单总量
+    asks: BTreeMap<u64, u64>,
+    template: Tick, // 快照中逐笔数据没有的字段（代码、昨收、涨跌停价等）取自这里
+    date: NaiveDate, // 快照的交易日
+    last_price: u64,
+    open: u64,
+    high: u64,
+    low: u64,
+    total_volume: u64,
+    total_turnover: u64,
+    match_items: u64,
+}
+
+// 没有tick数据作为模板时使用的空快照
+pub fn empty_tick(code: &str) -> Tick {
+    macro_rules! empty {
+        ($($f:ident),*) => {
+            Tick {
+                chWindCode: code.to_string(),
+                $($f: 0,)*
+                dt: tick::default_dt(),
+            }
+        };
+    }
+    tick_fields!(empty)
+}
+
+pub fn new_order_book(template: Tick, date: NaiveDate) -> OrderBook {
+    OrderBook {
+        orders: HashMap::new(),
+        bids: BTreeMap::new(),
+        asks: BTreeMap::new(),
+        template,
+        date,
+        last_price: 0,
+        open: 0,
+        high: 0,
+        low: 0,
+        total_volume: 0,
+        total_turnover: 0,
+        match_items: 0,
+    }
+}
+
+impl OrderBook {
+    // 减少委托的剩余数量，volume为0时全部撤销
+    fn reduce(&mut self, id: u64, volume: u64) {
+        let (buy, price, left) = match self.orders.get_mut(&id) {
+            Some(o) => {
+                let v = if volume == 0 { o.volume } else { volume.min(o.volume) };
+                o.volume -= v;
+                (o.buy, o.price, v)
+            }
+            None => return,
+        };
+        let levels = if buy { &mut self.bids } else { &mut self.asks };
+        if let Some(total) = levels.get_mut(&price) {
+            *total = total.saturating_sub(left);
+            if *total == 0 {
+                levels.remove(&price);
+            }
+        }
+        if self.orders[&id].volume == 0 {
+            self.orders.remove(&id);
+        }
+    }
+
+    pub fn on_entrust(&mut self, e: &entrust) {
+        if e.OrderKind == 'D' {
+            self.reduce(e.Order, e.Volume);
+            return;
+        }
+        // 市价委托没有价格，不挂单，成交会在逐笔成交中体现
+        if e.Price == 0 || e.Volume == 0 {
+            return;
+        }
+        let buy = e.BSFlag == 'B';
+        let levels = if buy { &mut self.bids } else { &mut self.asks };
+        *levels.entry(e.Price).or_insert(0) += e.Volume;
+        self.orders.insert(
+            e.Order,
+            Resting {
+                buy,
+                price: e.Price,
+                volume: e.Volume,
+            },
+        );
+    }
+
+    pub fn on_transaction(&mut self, t: &transaction) {
+        // 深交所的撤单在逐笔成交中
+        if t.is_cancel() {
+            let id = if t.BidOrder > 0 { t.BidOrder } else { t.AskOrder };
+            self.reduce(id, t.Volume);
+            return;
+        }
+        self.reduce(t.AskOrder, t.Volume);
+        self.reduce(t.BidOrder, t.Volume);
+        if self.open == 0 {
+            self.open = t.Price;
+            self.low = t.Price;
+        }
+        self.last_price = t.Price;
+        self.high = self.high.max(t.Price);
+        self.low = self.low.min(t.Price);
+        self.total_volume += t.Volume;
+        self.total_turnover += t.Turnover;
+        self.match_items += 1;
+    }
+
+    // 当前委托簿的10档快照
+    pub fn snapshot(&self, ntime: u64) -> Result<Tick, FeedError> {
+        let mut t = self.template.clone();
+        t.nTime = ntime;
+        t.dt = tick::get_time(self.date, ntime)?;
+        t.nPrice = self.last_price;
+        if self.open > 0 {
+            t.Open = self.open;
+            t.High = self.high;
+            t.Low = self.low;
+        }
+        t.nMatchItems = self.match_items;
+        t.TotalVolume = self.total_volume;
+        t.TotalTurnover = self.total_turnover;
+        t.TotalAskVolume = self.asks.values().sum();
+        t.TotalBidVolume = self.bids.values().sum();
+        for level in 1..=10 {
+            t.set_ask(level, 0, 0);
+            t.set_bid(level, 0, 0);
+        }
+        for (idx, (p, v)) in self.asks.iter().take(10).enumerate() {
+            t.set_ask(idx + 1, *p, *v);
+        }
+        for (idx, (p, v)) in self.bids.iter().rev().take(10).enumerate() {
+            t.set_bid(idx + 1, *p, *v);
+        }
+        Ok(t)
+    }
+}
+
+// 按时间顺序回放逐笔委托和逐笔成交，每个代码一个委托簿，每interval毫秒输出一次快照
+// 同一时间的委托先于成交处理，保证成交能找到对应的委托
+// 一个时间段内有变化的代码在时间段结束时各输出一个快照，按最后变化的时间和代码排序
+pub struct BookReplay {
+    books: HashMap<String, OrderBook>,
+    templates: HashMap<String, Tick>, // 每个代码的快照模板，没有时用空快照
+    date: NaiveDate,
+    entrusts: Vec<entrust>,
+    trans: Vec<transaction>,
+    ei: usize,
+    ti: usize,
+    interval: u64,
+    period: Option<u64>,
+    changed: HashMap<String, u64>, // 当前时间段内有变化的代码 -> 最后变化的时间
+    ready: VecDeque<Result<Tick, FeedError>>,
+}
+
+// 代码的委托簿，第一次用到时按模板创建
+fn book_of<'a>(
+    books: &'a mut HashMap<String, OrderBook>,
+    templates: &HashMap<String, Tick>,
+    date: NaiveDate,
+    code: &str,
+) -> &'a mut OrderBook {
+    books.entry(code.to_string()).or_insert_with(|| {
+        let template = templates.get(code).cloned().unwrap_or_else(|| empty_tick(code));
+        new_order_book(template, date)
+    })
+}
+
+pub fn new_book_replay(
+    templates: HashMap<String, Tick>,
+    mut entrusts: Vec<entrust>,
+    mut trans: Vec<transaction>,
+    interval: u64,
+    date: NaiveDate,
+) -> BookReplay {
+    entrusts.sort_by_key(|e| (e.Time, e.Order));
+    trans.sort_by_key(|t| (t.Time, t.Index));
+    BookReplay {
+        books: HashMap::new(),
+        templates,
+        date,
+        entrusts,
+        trans,
+        ei: 0,
+        ti: 0,
+        interval: interval.max(1),
+        period: None,
+        changed: HashMap::new(),
+        ready: VecDeque::new(),
+    }
+}
+
+impl BookReplay {
+    // 输出当前时间段内有变化的代码的快照
+    fn flush(&mut self) {
+        let mut changed: Vec<(u64, String)> = self.changed.drain().map(|(c, t)| (t, c)).collect();
+        changed.sort();
+        for (time, code) in changed {
+            self.ready.push_back(self.books[&code].snapshot(time));
+        }
+    }
+}
+
+impl Iterator for BookReplay {
+    type Item = Result<Tick, FeedError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(tick) = self.ready.pop_front() {
+                return Some(tick);
+            }
+            let e_time = self.entrusts.get(self.ei).map(|e| e.Time);
+            let t_time = self.trans.get(self.ti).map(|t| t.Time);
+            let time = match (e_time, t_time) {
+                (Some(e), Some(t)) => e.min(t),
+                (Some(e), None) => e,
+                (None, Some(t)) => t,
+                (None, None) => {
+                    // 数据结束时输出最后一个时间段的快照
+                    self.flush();
+                    return self.ready.pop_front();
+                }
+            };
+            // 进入新的时间段前，先输出上一段结束时的快照
+            let period = match TimeOfDay::from_ntime(time) {
+                Ok(t) => t.millis() as u64 / self.interval,
+                Err(e) => return Some(Err(e.into())),
+            };
+            if self.period.is_some_and(|p| p != period) {
+                self.flush();
+            }
+            self.period = Some(period);
+            if !self.ready.is_empty() {
+                continue;
+            }
+            let (books, templates, date) = (&mut self.books, &self.templates, self.date);
+            if e_time == Some(time) {
+                let e = &self.entrusts[self.ei];
+                book_of(books, templates, date, &e.Tkr).on_entrust(e);
+                self.changed.insert(e.Tkr.clone(), time);
+                self.ei += 1;
+            } else {
+                let t = &self.trans[self.ti];
+                book_of(books, templates, date, &t.Tkr).on_transaction(t);
+                self.changed.insert(t.Tkr.clone(), time);
+                self.ti += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick::{default_dt, trade_date};
+
+    fn entrust(code: &str, time: u64, order: u64, price: u64, volume: u64, flag: char, kind: char) -> entrust {
+        entrust {
+            Tkr: code.to_string(),
+            Time: time,
+            dt: default_dt(),
+            Order: order,
+            Price: price,
+            Volume: volume,
+            BSFlag: flag,
+            OrderKind: kind,
+        }
+    }
+
+    fn trade(code: &str, time: u64, index: u64, price: u64, volume: u64, ask: u64, bid: u64) -> transaction {
+        transaction {
+            Tkr: code.to_string(),
+            Time: time,
+            dt: default_dt(),
+            Index: index,
+            Price: price,
+            Volume: volume,
+            Turnover: price * volume / 10000,
+            BSFlag: 'B',
+            OrderKind: 0,
+            FunctionCode: '0',
+            AskOrder: ask,
+            BidOrder: bid,
+        }
+    }
+
+    fn book() -> OrderBook {
+        new_order_book(empty_tick("000001.SZ"), trade_date(0).unwrap())
+    }
+
+    #[test]
+    fn add_orders() {
+        let mut b = book();
+        b.on_entrust(&entrust("000001.SZ", 93000000, 1, 180000, 100, 'B', '2'));
+        b.on_entrust(&entrust("000001.SZ", 93000000, 2, 180000, 200, 'B', '2'));
+        b.on_entrust(&entrust("000001.SZ", 93000000, 3, 180100, 300, 'S', '2'));
+        // 市价委托不挂单
+        b.on_entrust(&entrust("000001.SZ", 93000000, 4, 0, 500, 'S', '1'));
+        let t = b.snapshot(93000000).unwrap();
+        assert_eq!((t.nBidPrice1, t.nBidVolume1), (180000, 300));
+        assert_eq!((t.nAskPrice1, t.nAskVolume1), (180100, 300));
+        assert_eq!((t.TotalBidVolume, t.TotalAskVolume), (300, 300));
+        assert_eq!(t.dt, tick::get_time(trade_date(0).unwrap(), 93000000).unwrap());
+    }
+
+    #[test]
+    fn cancel_orders() {
+        let mut b = book();
+        b.on_entrust(&entrust("000001.SZ", 93000000, 1, 180000, 100, 'B', '2'));
+        b.on_entrust(&entrust("000001.SZ", 93000000, 2, 180000, 200, 'B', '2'));
+        b.on_entrust(&entrust("000001.SZ", 93000000, 3, 180100, 300, 'S', '2'));
+        // 上交所的撤单在逐笔委托中
+        b.on_entrust(&entrust("000001.SZ", 93001000, 2, 0, 200, 'B', 'D'));
+        // 深交所的撤单在逐笔成交中，部分撤单
+        let mut cancel = trade("000001.SZ", 93002000, 1, 0, 100, 3, 0);
+        cancel.FunctionCode = 'C';
+        b.on_transaction(&cancel);
+        let t = b.snapshot(93002000).unwrap();
+        assert_eq!((t.nBidPrice1, t.nBidVolume1), (180000, 100));
+        assert_eq!((t.nAskPrice1, t.nAskVolume1), (180100, 200));
+        // 撤单不是成交
+        assert_eq!((t.nPrice, t.TotalVolume, t.nMatchItems), (0, 0, 0));
+        // 全部撤单后档位消失
+        b.on_entrust(&entrust("000001.SZ", 93003000, 1, 0, 0, 'B', 'D'));
+        assert_eq!(b.snapshot(93003000).unwrap().nBidPrice1, 0);
+    }
+
+    #[test]
+    fn trade_reduces_level() {
+        let mut b = book();
+        b.on_entrust(&entrust("000001.SZ", 93000000, 1, 180100, 300, 'S', '2'));
+        b.on_entrust(&entrust("000001.SZ", 93000000, 2, 180200, 300, 'S', '2'));
+        b.on_entrust(&entrust("000001.SZ", 93001000, 3, 180200, 500, 'B', '2'));
+        b.on_transaction(&trade("000001.SZ", 93001000, 1, 180100, 300, 1, 3));
+        b.on_transaction(&trade("000001.SZ", 93001000, 2, 180200, 200, 2, 3));
+        let t = b.snapshot(93001000).unwrap();
+        assert_eq!((t.nAskPrice1, t.nAskVolume1, t.nAskPrice2), (180200, 100, 0));
+        assert_eq!(t.nBidPrice1, 0);
+        assert_eq!((t.nPrice, t.Open, t.High, t.Low), (180200, 180100, 180200, 180100));
+        assert_eq!((t.TotalVolume, t.nMatchItems), (500, 2));
+    }
+
+    #[test]
+    fn top_ten_levels() {
+        let mut b = book();
+        for i in 0..12u64 {
+            b.on_entrust(&entrust("000001.SZ", 93000000, i + 1, 180000 - i * 100, 100 + i, 'B', '2'));
+            b.on_entrust(&entrust("000001.SZ", 93000000, i + 101, 180100 + i * 100, 100 + i, 'S', '2'));
+        }
+        let t = b.snapshot(93000000).unwrap();
+        let bids = t.bids();
+        let asks = t.asks();
+        for i in 0..10 {
+            assert_eq!(bids[i], (180000 - i as u64 * 100, 100 + i as u64));
+            assert_eq!(asks[i], (180100 + i as u64 * 100, 100 + i as u64));
+        }
+        // 总量包括10档以外的挂单
+        assert_eq!(t.TotalBidVolume, (100..112).sum::<u64>());
+    }
+
+    #[test]
+    fn one_book_per_code() {
+        let mut template = empty_tick("000001.SZ");
+        template.PreClose = 180000;
+        let templates = vec![("000001.SZ".to_string(), template)].into_iter().collect();
+        let entrusts = vec![
+            entrust("000001.SZ", 93000000, 1, 180000, 100, 'B', '2'),
+            entrust("000002.SZ", 93000100, 1, 200000, 300, 'S', '2'),
+            entrust("000001.SZ", 93001000, 2, 179900, 200, 'S', '2'),
+        ];
+        let replay = new_book_replay(templates, entrusts, Vec::new(), 1000, trade_date(0).unwrap());
+        let ticks: Vec<Tick> = replay.map(|t| t.unwrap()).collect();
+        let view: Vec<(&str, u64, u64, u64, u64)> = ticks
+            .iter()
+            .map(|t| (t.chWindCode.as_str(), t.nTime, t.PreClose, t.nBidPrice1, t.nAskPrice1))
+            .collect();
+        // 同一个编号的委托属于不同代码，不会互相覆盖，也不会交叉成一个委托簿
+        assert_eq!(
+            view,
+            vec![
+                ("000001.SZ", 93000000, 180000, 180000, 0),
+                ("000002.SZ", 93000100, 0, 0, 200000),
+                ("000001.SZ", 93001000, 180000, 180000, 179900),
+            ]
+        );
+    }
+}
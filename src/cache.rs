@@ -0,0 +1,469 @@
+use chrono::NaiveDate;
+use memmap2::Mmap;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+
+use super::feed::{read_tick_stream, FeedError};
+use super::schema::SchemaConfig;
+use super::tick::{self, default_dt, get_time, trade_date, Tick, TimeOfDay};
+use super::transaction::transaction;
+
+// tick/逐笔成交数据的二进制缓存，避免每次回测都重新解析CSV
+// 文件格式（小端）：
+// 0..8    magic，tick为FEITUTK\0，逐笔成交为FEITUTR\0
+// 8..12   schema版本
+// 12..16  每条记录的字节数
+// 16..24  记录条数
+// 24..28  日期，如20211030，未知为0，读取时使用配置的交易日
+// 28..44  股票代码，不足16字节补0
+// 64..    定长记录，每个字段为u64
+// 一个缓存文件只能包含一个股票的数据
+
+pub const TICK_MAGIC: &[u8; 8] = b"FEITUTK\0";
+pub const TRANS_MAGIC: &[u8; 8] = b"FEITUTR\0";
+const SCHEMA_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 16;
+
+// tick中除代码和时间外的所有字段，按写入顺序排列
+macro_rules! tick_fields {
+    ($m:ident) => {
+        $m!(
+            nTime, Status, PreClose, Open, High, Low, nPrice, nAskPrice1, nAskPrice2, nAskPrice3,
+            nAskPrice4, nAskPrice5, nAskPrice6, nAskPrice7, nAskPrice8, nAskPrice9, nAskPrice10,
+            nAskVolume1, nAskVolume2, nAskVolume3, nAskVolume4, nAskVolume5, nAskVolume6,
+            nAskVolume7, nAskVolume8, nAskVolume9, nAskVolume10, nBidPrice1, nBidPrice2,
+            nBidPrice3, nBidPrice4, nBidPrice5, nBidPrice6, nBidPrice7, nBidPrice8, nBidPrice9,
+            nBidPrice10, nBidVolume1, nBidVolume2, nBidVolume3, nBidVolume4, nBidVolume5,
+            nBidVolume6, nBidVolume7, nBidVolume8, nBidVolume9, nBidVolume10, nMatchItems,
+            TotalVolume, TotalTurnover, TotalBidVolume, TotalAskVolume, WeightedAvgBidPrice,
+            WeightedAvgAskPrice, IOPV, YieldToMaturity, HighLimited, LowLimited
+        )
+    };
+}
+
+macro_rules! field_names {
+    ($($f:ident),*) => {
+        [$(stringify!($f)),*]
+    };
+}
+
+const TICK_FIELDS: usize = tick_fields!(field_names).len();
+const TICK_RECORD_SIZE: usize = TICK_FIELDS * 8;
+const TRANS_FIELDS: usize = 10;
+const TRANS_RECORD_SIZE: usize = TRANS_FIELDS * 8;
+
+pub struct Header {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub record_size: u32,
+    pub rows: u64,
+    pub date: u32,
+    pub symbol: String,
+}
+
+impl Header {
+    fn write<W: Write>(&self, w: &mut W) -> Result<(), Box<dyn Error>> {
+        let mut buf = [0u8; HEADER_SIZE];
+        buf[0..8].copy_from_slice(&self.magic);
+        buf[8..12].copy_from_slice(&self.version.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.record_size.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.rows.to_le_bytes());
+        buf[24..28].copy_from_slice(&self.date.to_le_bytes());
+        let symbol = self.symbol.as_bytes();
+        if symbol.len() > SYMBOL_SIZE {
+            return Err(format!("symbol {} is too long for cache", self.symbol).into());
+        }
+        buf[28..28 + symbol.len()].copy_from_slice(symbol);
+        w.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn read(buf: &[u8]) -> Result<Header, Box<dyn Error>> {
+        if buf.len() < HEADER_SIZE {
+            return Err("cache file is too short".into());
+        }
+        let symbol = &buf[28..28 + SYMBOL_SIZE];
+        let end = symbol.iter().position(|b| *b == 0).unwrap_or(SYMBOL_SIZE);
+        Ok(Header {
+            magic: buf[0..8].try_into()?,
+            version: u32::from_le_bytes(buf[8..12].try_into()?),
+            record_size: u32::from_le_bytes(buf[12..16].try_into()?),
+            rows: u64::from_le_bytes(buf[16..24].try_into()?),
+            date: u32::from_le_bytes(buf[24..28].try_into()?),
+            symbol: String::from_utf8(symbol[..end].to_vec())?,
+        })
+    }
+
+    // 检查magic、版本和记录长度，避免读取格式不一致的缓存
+    fn check(&self, magic: &[u8; 8], record_size: usize, len: usize) -> Result<(), Box<dyn Error>> {
+        if &self.magic != magic {
+            return Err("not a cache file of this type".into());
+        }
+        if self.version != SCHEMA_VERSION {
+            return Err(format!(
+                "cache schema version {} is not supported, expect {}",
+                self.version, SCHEMA_VERSION
+            )
+            .into());
+        }
+        // 记录条数来自文件，计算长度时不能溢出
+        let size = self
+            .rows
+            .checked_mul(record_size as u64)
+            .and_then(|n| n.checked_add(HEADER_SIZE as u64));
+        match size {
+            Some(size) if self.record_size as usize == record_size && len as u64 >= size => {}
+            _ => return Err("cache file is corrupted".into()),
+        }
+        Ok(())
+    }
+}
+
+// 判断文件是否为指定类型的缓存
+pub fn is_cache(path: &str, magic: &[u8; 8]) -> bool {
+    let mut buf = [0u8; 8];
+    match File::open(path) {
+        Ok(mut f) => f.read_exact(&mut buf).is_ok() && &buf == magic,
+        Err(_) => false,
+    }
+}
+
+// 逐条写入记录，先写占位的表头，结束时回到文件开头写入记录条数和股票代码
+pub struct CacheWriter<W: Write + Seek> {
+    w: W,
+    header: Header,
+}
+
+pub fn new_cache_writer<W: Write + Seek>(
+    mut w: W,
+    magic: &[u8; 8],
+    record_size: usize,
+    date: u32,
+) -> Result<CacheWriter<W>, Box<dyn Error>> {
+    let header = Header {
+        magic: *magic,
+        version: SCHEMA_VERSION,
+        record_size: record_size as u32,
+        rows: 0,
+        date,
+        symbol: String::new(),
+    };
+    header.write(&mut w)?;
+    Ok(CacheWriter { w, header })
+}
+
+impl<W: Write + Seek> CacheWriter<W> {
+    pub fn push(&mut self, symbol: &str, record: &[u64]) -> Result<(), Box<dyn Error>> {
+        if record.len() * 8 != self.header.record_size as usize {
+            return Err(format!("record of {} fields does not match cache", record.len()).into());
+        }
+        if self.header.symbol.is_empty() {
+            if symbol.len() > SYMBOL_SIZE {
+                return Err(format!("symbol {} is too long for cache", symbol).into());
+            }
+            self.header.symbol = symbol.to_string();
+        } else if self.header.symbol != symbol {
+            return Err(format!(
+                "more than one symbol: {} and {}",
+                self.header.symbol, symbol
+            )
+            .into());
+        }
+        for v in record {
+            self.w.write_all(&v.to_le_bytes())?;
+        }
+        self.header.rows += 1;
+        Ok(())
+    }
+
+    // 写回表头，返回记录条数
+    pub fn finish(mut self) -> Result<usize, Box<dyn Error>> {
+        self.w.seek(SeekFrom::Start(0))?;
+        self.header.write(&mut self.w)?;
+        self.w.flush()?;
+        Ok(self.header.rows as usize)
+    }
+}
+
+// 把tick的CSV转换为二进制缓存，返回记录条数
+pub fn convert_tick_csv(
+    csv_path: &str,
+    out: &str,
+    date: u32,
+    schema: &SchemaConfig,
+) -> Result<usize, Box<dyn Error>> {
+    let ticks = read_tick_stream(csv_path, 1024, schema, trade_date(date)?)?;
+    let f = BufWriter::new(File::create(out)?);
+    let mut w = new_cache_writer(f, TICK_MAGIC, TICK_RECORD_SIZE, date)?;
+    for result in ticks {
+        let t: Tick = result.map_err(|e| e.to_string())?;
+        macro_rules! encode {
+            ($($f:ident),*) => {
+                [$(t.$f),*]
+            };
+        }
+        w.push(&t.chWindCode, &tick_fields!(encode))
+            .map_err(|e| format!("{}: {}", csv_path, e))?;
+    }
+    w.finish()
+}
+
+// 把逐笔成交的CSV转换为二进制缓存，返回记录条数
+pub fn convert_trans_csv(csv_path: &str, out: &str, date: u32) -> Result<usize, Box<dyn Error>> {
+    trade_date(date)?;
+    let mut rdr = csv::Reader::from_path(csv_path)?;
+    let f = BufWriter::new(File::create(out)?);
+    let mut w = new_cache_writer(f, TRANS_MAGIC, TRANS_RECORD_SIZE, date)?;
+    for result in rdr.deserialize() {
+        let t: transaction = result?;
+        TimeOfDay::from_ntime(t.Time)?;
+        let record = [
+            t.Time,
+            t.Index,
+            t.Price,
+            t.Volume,
+            t.Turnover,
+            t.BSFlag as u64,
+            t.OrderKind,
+            t.FunctionCode as u64,
+            t.AskOrder,
+            t.BidOrder,
+        ];
+        w.push(&t.Tkr, &record)
+            .map_err(|e| format!("{}: {}", csv_path, e))?;
+    }
+    w.finish()
+}
+
+fn field(record: &[u8], idx: usize) -> u64 {
+    u64::from_le_bytes(record[idx * 8..idx * 8 + 8].try_into().unwrap())
+}
+
+// 内存映射的缓存文件，按需解码记录
+pub struct Cache {
+    pub header: Header,
+    date: NaiveDate, // 表头中的日期，没有时为配置的交易日
+    mmap: Mmap,
+}
+
+fn open_cache(
+    path: &str,
+    magic: &[u8; 8],
+    record_size: usize,
+    date: NaiveDate,
+) -> Result<Cache, Box<dyn Error>> {
+    let f = File::open(path)?;
+    // 缓存文件生成后不会再修改
+    let mmap = unsafe { Mmap::map(&f)? };
+    let header = Header::read(&mmap)?;
+    header.check(magic, record_size, mmap.len())?;
+    let date = if header.date == 0 {
+        date
+    } else {
+        trade_date(header.date)?
+    };
+    Ok(Cache { header, date, mmap })
+}
+
+pub fn open_tick_cache(path: &str, date: NaiveDate) -> Result<Cache, Box<dyn Error>> {
+    open_cache(path, TICK_MAGIC, TICK_RECORD_SIZE, date)
+}
+
+pub fn open_trans_cache(path: &str, date: NaiveDate) -> Result<Cache, Box<dyn Error>> {
+    open_cache(path, TRANS_MAGIC, TRANS_RECORD_SIZE, date)
+}
+
+impl Cache {
+    pub fn len(&self) -> usize {
+        self.header.rows as usize
+    }
+
+    fn record(&self, idx: usize) -> &[u8] {
+        let size = self.header.record_size as usize;
+        let start = HEADER_SIZE + idx * size;
+        &self.mmap[start..start + size]
+    }
+
+    pub fn tick(&self, idx: usize) -> Tick {
+        let record = self.record(idx);
+        let mut i = 0;
+        let mut next = || {
+            i += 1;
+            field(record, i - 1)
+        };
+        macro_rules! decode {
+            ($($f:ident),*) => {
+                Tick {
+                    chWindCode: self.header.symbol.clone(),
+                    $($f: next(),)*
+                    dt: default_dt(),
+                }
+            };
+        }
+        let mut t = tick_fields!(decode);
+        // 写入缓存时已经检查过时间
+        t.dt = get_time(self.date, t.nTime).unwrap_or_else(|_| default_dt());
+        t
+    }
+
+    pub fn transaction(&self, idx: usize) -> transaction {
+        let record = self.record(idx);
+        let time = field(record, 0);
+        transaction {
+            Tkr: self.header.symbol.clone(),
+            Time: time,
+            dt: get_time(self.date, time).unwrap_or_else(|_| default_dt()),
+            Index: field(record, 1),
+            Price: field(record, 2),
+            Volume: field(record, 3),
+            Turnover: field(record, 4),
+            BSFlag: std::char::from_u32(field(record, 5) as u32).unwrap_or(' '),
+            OrderKind: field(record, 6),
+            FunctionCode: std::char::from_u32(field(record, 7) as u32).unwrap_or('0'),
+            AskOrder: field(record, 8),
+            BidOrder: field(record, 9),
+        }
+    }
+}
+
+// 按顺序读取缓存中的tick，和CSV数据源一样作为Iterator使用
+pub struct TickCacheIter {
+    cache: Cache,
+    idx: usize,
+}
+
+pub fn read_tick_cache(path: &str, date: NaiveDate) -> Result<TickCacheIter, Box<dyn Error>> {
+    Ok(TickCacheIter {
+        cache: open_tick_cache(path, date)?,
+        idx: 0,
+    })
+}
+
+impl Iterator for TickCacheIter {
+    type Item = Result<tick::Tick, FeedError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.idx >= self.cache.len() {
+            return None;
+        }
+        self.idx += 1;
+        Some(Ok(self.cache.tick(self.idx - 1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Datelike;
+
+    fn temp_path(name: &str) -> String {
+        let name = format!("cache-test-{}-{}", std::process::id(), name);
+        std::env::temp_dir().join(name).to_str().unwrap().to_string()
+    }
+
+    fn remove(paths: &[&str]) {
+        for p in paths {
+            let _ = std::fs::remove_file(p);
+        }
+    }
+
+    #[test]
+    fn tick_round_trip() {
+        let names = tick_fields!(field_names);
+        let csv_path = temp_path("tick.csv");
+        let mut csv = format!("chWindCode,{}\n", names.join(","));
+        for (row, time) in [93000000u64, 93003000].iter().enumerate() {
+            let values: Vec<String> = (0..names.len())
+                .map(|i| match i {
+                    0 => time.to_string(),
+                    _ => (row * 1000 + i).to_string(),
+                })
+                .collect();
+            csv += &format!("601012.SH,{}\n", values.join(","));
+        }
+        std::fs::write(&csv_path, csv).unwrap();
+        let out = temp_path("tick.cache");
+        let rows = convert_tick_csv(&csv_path, &out, 20211101, &SchemaConfig::default()).unwrap();
+        assert_eq!(rows, 2);
+        assert!(is_cache(&out, TICK_MAGIC));
+
+        // 表头中有日期时不使用传入的日期
+        let ticks: Vec<Tick> = read_tick_cache(&out, trade_date(0).unwrap())
+            .unwrap()
+            .map(|t| t.unwrap())
+            .collect();
+        assert_eq!(ticks.len(), 2);
+        assert_eq!(ticks[0].chWindCode, "601012.SH");
+        assert_eq!(ticks[1].nTime, 93003000);
+        assert_eq!(ticks[1].nPrice, 1006);
+        assert_eq!(ticks[1].LowLimited, 1000 + names.len() as u64 - 1);
+        assert_eq!(ticks[0].dt.date_naive().day(), 1);
+        assert_eq!(ticks[1].dt - ticks[0].dt, chrono::Duration::seconds(3));
+        remove(&[&csv_path, &out]);
+    }
+
+    #[test]
+    fn trans_round_trip() {
+        let csv_path = temp_path("trans.csv");
+        std::fs::write(
+            &csv_path,
+            "Tkr,Time,Index,Price,Volume,Turnover,BSFlag,OrderKind,FunctionCode,AskOrder,BidOrder\n\
+             601012.SH,93000010,1,400000,100,40000000,B,0,0,11,12\n\
+             601012.SH,93000020,2,0,200,0,S,0,C,13,14\n",
+        )
+        .unwrap();
+        let out = temp_path("trans.cache");
+        assert_eq!(convert_trans_csv(&csv_path, &out, 0).unwrap(), 2);
+
+        // 表头中没有日期时使用传入的日期
+        let c = open_trans_cache(&out, trade_date(0).unwrap()).unwrap();
+        assert_eq!(c.len(), 2);
+        let t = c.transaction(1);
+        assert_eq!(t.Tkr, "601012.SH");
+        assert_eq!(t.Time, 93000020);
+        assert_eq!(t.BSFlag, 'S');
+        assert!(t.is_cancel());
+        assert!(!c.transaction(0).is_cancel());
+        assert_eq!((t.AskOrder, t.BidOrder), (13, 14));
+        assert_eq!(t.dt.date_naive(), trade_date(0).unwrap());
+        drop(c);
+        remove(&[&csv_path, &out]);
+    }
+
+    #[test]
+    fn more_than_one_symbol() {
+        let csv_path = temp_path("mixed.csv");
+        std::fs::write(
+            &csv_path,
+            "Tkr,Time,Index,Price,Volume,Turnover,BSFlag,OrderKind,FunctionCode,AskOrder,BidOrder\n\
+             601012.SH,93000010,1,400000,100,40000000,B,0,0,11,12\n\
+             600000.SH,93000020,2,400000,200,80000000,S,0,0,13,14\n",
+        )
+        .unwrap();
+        let out = temp_path("mixed.cache");
+        assert!(convert_trans_csv(&csv_path, &out, 0).is_err());
+        remove(&[&csv_path, &out]);
+    }
+
+    #[test]
+    fn rows_overflow_is_corrupted() {
+        let path = temp_path("overflow.cache");
+        let mut buf = Vec::new();
+        Header {
+            magic: *TRANS_MAGIC,
+            version: SCHEMA_VERSION,
+            record_size: TRANS_RECORD_SIZE as u32,
+            rows: u64::MAX,
+            date: 0,
+            symbol: "601012.SH".to_string(),
+        }
+        .write(&mut buf)
+        .unwrap();
+        std::fs::write(&path, buf).unwrap();
+        assert!(open_trans_cache(&path, trade_date(0).unwrap()).is_err());
+        remove(&[&path]);
+    }
+}
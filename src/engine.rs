@@ -0,0 +1,269 @@
+use chrono::{DateTime, Duration, FixedOffset};
+use serde::Deserialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use super::feed::FeedError;
+use super::strategy::StockSys;
+use super::timeline;
+use super::validate::Validator;
+
+// 事件驱动的回测主循环
+// 行情事件（tick快照、逐笔成交）来自timeline，本身已经按时间排序
+// 策略产生的事件（订单确认、成交回报、定时器）放在优先队列中
+// 每次取时间最早的事件分发给策略，同一时间的策略事件先于行情处理
+
+// 模拟的延迟（毫秒），都为0时在产生信号的tick上直接撮合
+// 委托在 信号时间 + decision + routing 到达交易所，用之后的第一个tick快照或逐笔成交撮合
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct LatencyConfig {
+    pub decision: i64, // 策略计算
+    pub routing: i64,  // 报单到交易所
+    pub ack: i64,      // 交易所确认和成交回报回到策略
+    pub cancel: i64,   // 撤单和改单从发出到交易所处理，期间原来的委托仍然可能成交
+}
+
+impl LatencyConfig {
+    // 只看报单的延迟，ack和cancel不影响是否在产生信号的tick上直接撮合
+    pub fn submit_is_zero(&self) -> bool {
+        self.decision == 0 && self.routing == 0
+    }
+
+    // 从产生信号到委托到达交易所
+    pub fn submit(&self) -> Duration {
+        Duration::milliseconds(self.decision + self.routing)
+    }
+
+    pub fn ack(&self) -> Duration {
+        Duration::milliseconds(self.ack)
+    }
+
+    pub fn cancel(&self) -> Duration {
+        Duration::milliseconds(self.cancel)
+    }
+}
+
+// 策略产生的事件，order为订单在StockSys.orders中的下标
+#[derive(Debug, Clone)]
+pub enum Action {
+    // 订单被交易所接受
+    Ack(usize),
+    // 成交回报
+    Fill {
+        order: usize,
+        buy: bool,
+        price: u64,
+        volume: u64,
+    },
+    // 改单到达交易所，volume为0表示撤单
+    Amend {
+        order: usize,
+        price: u64,
+        volume: usize,
+        market: bool,
+    },
+    Timer(Timer),
+}
+
+#[derive(Debug, Clone)]
+pub enum Timer {
+    SellCheck(usize), // 订单到了卖出时间，检查是否需要卖出
+}
+
+struct Scheduled {
+    time: DateTime<FixedOffset>,
+    seq: u64, // 同一时间的事件按加入的顺序处理
+    action: Action,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.time == other.time && self.seq == other.seq
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.time, self.seq).cmp(&(other.time, other.seq))
+    }
+}
+
+pub struct EventQueue {
+    heap: BinaryHeap<Reverse<Scheduled>>,
+    seq: u64,
+}
+
+pub fn new_event_queue() -> EventQueue {
+    EventQueue {
+        heap: BinaryHeap::new(),
+        seq: 0,
+    }
+}
+
+impl EventQueue {
+    pub fn schedule(&mut self, time: DateTime<FixedOffset>, action: Action) {
+        self.seq += 1;
+        self.heap.push(Reverse(Scheduled {
+            time,
+            seq: self.seq,
+            action,
+        }));
+    }
+
+    // 取出不晚于until的最早事件，until为None时取出任意时间的事件
+    pub fn pop_until(
+        &mut self,
+        until: Option<DateTime<FixedOffset>>,
+    ) -> Option<(DateTime<FixedOffset>, Action)> {
+        match (self.heap.peek(), until) {
+            (Some(Reverse(s)), Some(until)) if s.time > until => None,
+            (Some(_), _) => self.heap.pop().map(|Reverse(s)| (s.time, s.action)),
+            (None, _) => None,
+        }
+    }
+}
+
+fn event_time(event: &Result<timeline::Event, FeedError>) -> DateTime<FixedOffset> {
+    match event {
+        Ok(timeline::Event::Tick(tick)) => tick.dt,
+        Ok(timeline::Event::Trade(t)) => t.dt,
+        // 读取出错时立即处理
+        Err(_) => DateTime::<chrono::Utc>::MIN_UTC.into(),
+    }
+}
+
+// 返回读取的tick条数，记录在运行清单中，读取行情出错时返回错误
+pub fn run<'a, I>(
+    sys: &mut StockSys,
+    market: I,
+    mut validator: Option<&mut Validator>,
+) -> Result<usize, FeedError>
+where
+    I: Iterator<Item = Result<timeline::Event<'a>, FeedError>>,
+{
+    let mut market = market.peekable();
+    let mut ticks = 0;
+    loop {
+        // 先处理下一个行情事件之前到期的策略事件，行情结束后处理剩下的所有事件
+        let until = market.peek().map(event_time);
+        if let Some((time, action)) = sys.events.pop_until(until) {
+            sys.on_action(time, action);
+            continue;
+        }
+        let mut tick = match market.next() {
+            Some(event) => match event? {
+                timeline::Event::Tick(tick) => *tick,
+                timeline::Event::Trade(t) => {
+                    sys.on_transaction(t);
+                    continue;
+                }
+            },
+            None => break,
+        };
+        ticks += 1;
+        if let Some(v) = &mut validator {
+            tick = match v.check(tick) {
+                Some(tick) => tick,
+                None => continue,
+            };
+        }
+        sys.do_strategy(&tick);
+    }
+    Ok(ticks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::tests::{new_sys, tick};
+    use crate::tick::{get_time, trade_date};
+
+    fn at(ntime: u64) -> DateTime<FixedOffset> {
+        get_time(trade_date(0).unwrap(), ntime).unwrap()
+    }
+
+    fn timer(event: Option<(DateTime<FixedOffset>, Action)>) -> Option<(u64, usize)> {
+        event.map(|(time, action)| match action {
+            Action::Timer(Timer::SellCheck(idx)) => (crate::tick::ntime_of(time), idx),
+            other => panic!("unexpected action {:?}", other),
+        })
+    }
+
+    #[test]
+    fn earliest_first() {
+        let mut q = new_event_queue();
+        q.schedule(at(93002000), Action::Timer(Timer::SellCheck(1)));
+        q.schedule(at(93000000), Action::Timer(Timer::SellCheck(2)));
+        q.schedule(at(93001000), Action::Timer(Timer::SellCheck(3)));
+        assert_eq!(timer(q.pop_until(None)), Some((93000000, 2)));
+        assert_eq!(timer(q.pop_until(None)), Some((93001000, 3)));
+        assert_eq!(timer(q.pop_until(None)), Some((93002000, 1)));
+        assert_eq!(timer(q.pop_until(None)), None);
+    }
+
+    #[test]
+    fn same_time_in_schedule_order() {
+        // Reverse把最大堆变成最小堆，同一时间按seq从小到大，即加入的顺序
+        let mut q = new_event_queue();
+        for idx in [5, 3, 8, 1].iter() {
+            q.schedule(at(93000000), Action::Timer(Timer::SellCheck(*idx)));
+        }
+        q.schedule(at(92959999), Action::Timer(Timer::SellCheck(0)));
+        let order: Vec<usize> = std::iter::from_fn(|| timer(q.pop_until(None)).map(|(_, idx)| idx)).collect();
+        assert_eq!(order, vec![0, 5, 3, 8, 1]);
+    }
+
+    #[test]
+    fn pop_until_is_inclusive() {
+        let mut q = new_event_queue();
+        q.schedule(at(93000000), Action::Timer(Timer::SellCheck(1)));
+        q.schedule(at(93001000), Action::Timer(Timer::SellCheck(2)));
+        assert_eq!(timer(q.pop_until(Some(at(92959999)))), None);
+        assert_eq!(timer(q.pop_until(Some(at(93000000)))), Some((93000000, 1)));
+        assert_eq!(timer(q.pop_until(Some(at(93000999)))), None);
+        // 没有取出的事件留在队列中
+        assert_eq!(timer(q.pop_until(Some(at(93100000)))), Some((93001000, 2)));
+        assert_eq!(timer(q.pop_until(Some(at(93100000)))), None);
+    }
+
+    #[test]
+    fn submit_is_zero() {
+        let mut latency = LatencyConfig::default();
+        assert!(latency.submit_is_zero());
+        latency.ack = 5;
+        latency.cancel = 10;
+        assert!(latency.submit_is_zero());
+        assert_eq!(latency.submit(), Duration::zero());
+        latency.routing = 2;
+        assert!(!latency.submit_is_zero());
+        latency.routing = 0;
+        latency.decision = 1;
+        assert!(!latency.submit_is_zero());
+        assert_eq!(latency.submit(), Duration::milliseconds(1));
+    }
+
+    #[test]
+    fn run_returns_feed_error() {
+        let mut sys = new_sys("run_returns_feed_error", "");
+        let market: Vec<Result<timeline::Event, FeedError>> = vec![
+            Ok(timeline::Event::Tick(Box::new(tick("601012.SH", 93000000, 400000, 1000)))),
+            Err("bad row".into()),
+            Ok(timeline::Event::Tick(Box::new(tick("601012.SH", 93003000, 400000, 1000)))),
+        ];
+        let err = run(&mut sys, market.into_iter(), None).unwrap_err();
+        assert_eq!(err.to_string(), "bad row");
+
+        let market: Vec<Result<timeline::Event, FeedError>> =
+            vec![Ok(timeline::Event::Tick(Box::new(tick("601012.SH", 93000000, 400000, 1000))))];
+        assert_eq!(run(&mut sys, market.into_iter(), None).unwrap(), 1);
+    }
+}
@@ -0,0 +1,39 @@
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use serde::Deserialize;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+
+use super::tick::{default_dt, get_time};
+
+// 逐笔委托
+// Order为委托编号，与逐笔成交中的AskOrder/BidOrder对应
+// BSFlag: B买 S卖
+// OrderKind: 1市价 2限价 U本方最优 D撤单（上交所的撤单在逐笔委托中）
+// 深交所的撤单在逐笔成交中，FunctionCode为'C'
+#[derive(Debug, Deserialize, Clone)]
+pub struct entrust {
+    pub Tkr: String,
+    pub Time: u64,
+    #[serde(skip_deserializing)]
+    #[serde(default = "default_dt")]
+    pub dt: DateTime<FixedOffset>,
+    pub Order: u64,
+    pub Price: u64,
+    pub Volume: u64,
+    pub BSFlag: char,
+    pub OrderKind: char,
+}
+
+pub fn read_entrust_from_file(path: &str, date: NaiveDate) -> Result<Vec<entrust>, Box<dyn Error>> {
+    let mut res: Vec<entrust> = Vec::new();
+    let f = File::open(path)?;
+    let reader = BufReader::new(f);
+    let mut rdr = csv::Reader::from_reader(reader);
+    for result in rdr.deserialize() {
+        let mut record: entrust = result?;
+        record.dt = get_time(date, record.Time)?;
+        res.push(record);
+    }
+    Ok(res)
+}
@@ -0,0 +1,79 @@
+use chrono::NaiveDate;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+
+use super::cache;
+use super::schema;
+#[cfg(feature = "parquet")]
+use super::parquet_io;
+use super::tick;
+
+pub type FeedError = Box<dyn Error + Send + Sync>;
+
+// 流式读取tick数据，不需要把整个文件读入内存
+// 由后台线程逐行解析，最多预读read_ahead条，内存占用与文件大小无关
+// 实现Iterator<Item = Result<Tick, FeedError>>的都可以作为数据源，后续可以接入实时行情或回放
+// date为数据中没有日期时使用的交易日
+pub struct TickStream {
+    rx: Receiver<Result<tick::Tick, FeedError>>,
+}
+
+pub fn read_tick_stream(
+    path: &str,
+    read_ahead: usize,
+    schema: &schema::SchemaConfig,
+    date: NaiveDate,
+) -> Result<TickStream, Box<dyn Error>> {
+    let f = File::open(path)?;
+    let mut rdr = csv::Reader::from_reader(BufReader::new(f));
+    // 根据表头生成列名映射，映射有问题时直接返回错误
+    let schema = schema::new_schema(schema, rdr.headers()?, date)?;
+    let (tx, rx) = sync_channel(read_ahead.max(1));
+    thread::spawn(move || {
+        for result in rdr.records() {
+            let record = match result {
+                Ok(record) => schema.tick(&record),
+                Err(e) => Err(e.into()),
+            };
+            let failed = record.is_err();
+            // 接收端已经退出时停止读取
+            if tx.send(record).is_err() || failed {
+                break;
+            }
+        }
+    });
+    Ok(TickStream { rx })
+}
+
+impl Iterator for TickStream {
+    type Item = Result<tick::Tick, FeedError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
+
+// 任意来源的tick数据，按时间顺序输出
+pub type TickFeed = Box<dyn Iterator<Item = Result<tick::Tick, FeedError>>>;
+
+// 打开tick数据源，二进制缓存直接内存映射，parquet按batch读取，否则按CSV流式读取
+pub fn open_tick_feed(
+    path: &str,
+    read_ahead: usize,
+    schema: &schema::SchemaConfig,
+    date: NaiveDate,
+) -> Result<TickFeed, Box<dyn Error>> {
+    if cache::is_cache(path, cache::TICK_MAGIC) {
+        return Ok(Box::new(cache::read_tick_cache(path, date)?));
+    }
+    #[cfg(feature = "parquet")]
+    {
+        if parquet_io::is_parquet(path) {
+            return Ok(Box::new(parquet_io::read_tick_parquet(path, read_ahead, schema, date)?));
+        }
+    }
+    Ok(Box::new(read_tick_stream(path, read_ahead, schema, date)?))
+}
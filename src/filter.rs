@@ -0,0 +1,241 @@
+use chrono::{DateTime, Duration, FixedOffset};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+
+use super::tick::Tick;
+
+// 开仓前对盘口的检查，过滤成交清淡、价差过大的假突破
+// 不配置的条件不检查；做空时买卖量不平衡度和距昨收/开盘价的方向相反
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct FilterConfig {
+    pub min_window_volume: Option<u64>, // gap_window时间窗内的最小成交量（TotalVolume的增量）
+    pub max_spread: Option<f64>,        // 卖1和买1的最大价差，相对买1价的比例
+    pub min_imbalance: Option<f64>,     // 10档买卖量不平衡度的最小值，(买量-卖量)/(买量+卖量)，-1~1
+    pub min_match_items: Option<u64>,   // 当天最少成交笔数（nMatchItems）
+    pub max_pre_close_dist: Option<f64>, // 当前价相对昨收的最大涨幅
+    pub max_open_dist: Option<f64>,     // 当前价相对开盘价的最大涨幅
+}
+
+pub struct SignalFilter {
+    pub conf: FilterConfig,
+    span: Duration,
+    volumes: HashMap<String, VecDeque<(DateTime<FixedOffset>, u64)>>, // 每个代码时间窗内的(时间, TotalVolume)
+}
+
+pub fn new_signal_filter(conf: FilterConfig, seconds: i64) -> SignalFilter {
+    SignalFilter {
+        conf,
+        span: Duration::seconds(seconds),
+        volumes: HashMap::new(),
+    }
+}
+
+// 价格相对基准的涨幅，基准为0时无法计算
+fn distance(price: u64, base: u64) -> Option<f64> {
+    if base == 0 {
+        return None;
+    }
+    Some((price as f64 - base as f64) / base as f64)
+}
+
+impl SignalFilter {
+    pub fn update(&mut self, tick: &Tick) {
+        if self.conf.min_window_volume.is_none() {
+            return;
+        }
+        let volumes = self.volumes.entry(tick.chWindCode.clone()).or_default();
+        while let Some((t, _)) = volumes.front() {
+            if tick.dt - *t < self.span {
+                break;
+            }
+            volumes.pop_front();
+        }
+        volumes.push_back((tick.dt, tick.TotalVolume));
+    }
+
+    // 一个代码时间窗内的成交量
+    pub fn window_volume(&self, code: &str) -> u64 {
+        let volumes = match self.volumes.get(code) {
+            Some(volumes) => volumes,
+            None => return 0,
+        };
+        match (volumes.front(), volumes.back()) {
+            (Some((_, first)), Some((_, last))) => last.saturating_sub(*first),
+            _ => 0,
+        }
+    }
+
+    // 所有条件都满足才返回Ok，否则返回没有满足的条件
+    // long为false时按做空检查
+    pub fn check(&self, tick: &Tick, long: bool) -> Result<(), String> {
+        let sign = if long { 1.0 } else { -1.0 };
+        if let Some(min) = self.conf.min_window_volume {
+            let volume = self.window_volume(&tick.chWindCode);
+            if volume < min {
+                return Err(format!("window volume {} < {}", volume, min));
+            }
+        }
+        if let Some(max) = self.conf.max_spread {
+            // 一侧为空时价差无法计算，视为不满足
+            if tick.nAskPrice1 == 0 || tick.nBidPrice1 == 0 {
+                return Err("one side of book is empty".to_string());
+            }
+            let spread = distance(tick.nAskPrice1, tick.nBidPrice1).unwrap_or(0.0);
+            if spread > max {
+                return Err(format!("spread {:.5} > {}", spread, max));
+            }
+        }
+        if let Some(min) = self.conf.min_imbalance {
+            let bid: u64 = tick.bids().iter().map(|(_, v)| v).sum();
+            let ask: u64 = tick.asks().iter().map(|(_, v)| v).sum();
+            if bid + ask == 0 {
+                return Err("book is empty".to_string());
+            }
+            let imbalance = (bid as f64 - ask as f64) / (bid + ask) as f64 * sign;
+            if imbalance < min {
+                return Err(format!("imbalance {:.3} < {}", imbalance, min));
+            }
+        }
+        if let Some(min) = self.conf.min_match_items {
+            if tick.nMatchItems < min {
+                return Err(format!("match items {} < {}", tick.nMatchItems, min));
+            }
+        }
+        if let Some(max) = self.conf.max_pre_close_dist {
+            if let Some(d) = distance(tick.nPrice, tick.PreClose) {
+                if d * sign > max {
+                    return Err(format!("distance from pre close {:.5} > {}", d * sign, max));
+                }
+            }
+        }
+        if let Some(max) = self.conf.max_open_dist {
+            if let Some(d) = distance(tick.nPrice, tick.Open) {
+                if d * sign > max {
+                    return Err(format!("distance from open {:.5} > {}", d * sign, max));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::empty_tick;
+    use crate::tick::{get_time, trade_date};
+
+    // 最新价400000，昨收392000，开盘396000，买卖各10档，每档1000股
+    fn tick(code: &str, ntime: u64, volume: u64) -> Tick {
+        let mut t = empty_tick(code);
+        t.nTime = ntime;
+        t.dt = get_time(trade_date(0).unwrap(), ntime).unwrap();
+        t.nPrice = 400000;
+        t.PreClose = 392000;
+        t.Open = 396000;
+        t.TotalVolume = volume;
+        t.nMatchItems = 100;
+        for l in 1..=10 {
+            t.set_ask(l, 400000 + 100 * l as u64, 1000);
+            t.set_bid(l, 400000 - 100 * l as u64, 1000);
+        }
+        t
+    }
+
+    fn filter(conf: FilterConfig) -> SignalFilter {
+        new_signal_filter(conf, 60)
+    }
+
+    #[test]
+    fn window_volume_per_code() {
+        let mut f = filter(FilterConfig {
+            min_window_volume: Some(1000),
+            ..FilterConfig::default()
+        });
+        f.update(&tick("601012.SH", 93000000, 10000));
+        f.update(&tick("600000.SH", 93001000, 500000));
+        f.update(&tick("601012.SH", 93030000, 10800));
+        assert_eq!(f.window_volume("601012.SH"), 800);
+        assert_eq!(f.check(&tick("601012.SH", 93030000, 10800), true), Err("window volume 800 < 1000".to_string()));
+        f.update(&tick("600000.SH", 93031000, 502000));
+        assert_eq!(f.window_volume("600000.SH"), 2000);
+        assert_eq!(f.check(&tick("600000.SH", 93031000, 502000), true), Ok(()));
+        f.update(&tick("601012.SH", 93050000, 11000));
+        assert_eq!(f.check(&tick("601012.SH", 93050000, 11000), true), Ok(()));
+        // 9:30:00的成交量移出时间窗
+        f.update(&tick("601012.SH", 93100000, 11100));
+        assert_eq!(f.window_volume("601012.SH"), 300);
+        assert_eq!(f.window_volume("000001.SZ"), 0);
+    }
+
+    #[test]
+    fn spread() {
+        let f = filter(FilterConfig {
+            max_spread: Some(0.001),
+            ..FilterConfig::default()
+        });
+        let mut t = tick("601012.SH", 93000000, 0);
+        assert_eq!(f.check(&t, true), Ok(()));
+        t.set_ask(1, 400500, 1000);
+        assert_eq!(f.check(&t, true), Err("spread 0.00150 > 0.001".to_string()));
+        t.set_bid(1, 0, 0);
+        assert_eq!(f.check(&t, true), Err("one side of book is empty".to_string()));
+    }
+
+    #[test]
+    fn imbalance() {
+        let f = filter(FilterConfig {
+            min_imbalance: Some(0.1),
+            ..FilterConfig::default()
+        });
+        let mut t = tick("601012.SH", 93000000, 0);
+        assert_eq!(f.check(&t, true), Err("imbalance 0.000 < 0.1".to_string()));
+        // 买盘多时可以做多，不能做空
+        t.set_bid(1, 399900, 5000);
+        assert_eq!(f.check(&t, true), Ok(()));
+        assert_eq!(f.check(&t, false), Err("imbalance -0.167 < 0.1".to_string()));
+        t.set_bid(1, 399900, 1000);
+        t.set_ask(1, 400100, 5000);
+        assert_eq!(f.check(&t, false), Ok(()));
+    }
+
+    #[test]
+    fn match_items() {
+        let f = filter(FilterConfig {
+            min_match_items: Some(100),
+            ..FilterConfig::default()
+        });
+        let mut t = tick("601012.SH", 93000000, 0);
+        assert_eq!(f.check(&t, true), Ok(()));
+        t.nMatchItems = 99;
+        assert_eq!(f.check(&t, true), Err("match items 99 < 100".to_string()));
+    }
+
+    #[test]
+    fn pre_close_and_open_distance() {
+        // 当前价相对昨收涨2.04%，相对开盘价涨1.01%
+        let t = tick("601012.SH", 93000000, 0);
+        let f = filter(FilterConfig {
+            max_pre_close_dist: Some(0.02),
+            ..FilterConfig::default()
+        });
+        assert_eq!(f.check(&t, true), Err("distance from pre close 0.02041 > 0.02".to_string()));
+        assert_eq!(f.check(&t, false), Ok(()));
+        let f = filter(FilterConfig {
+            max_open_dist: Some(0.02),
+            ..FilterConfig::default()
+        });
+        assert_eq!(f.check(&t, true), Ok(()));
+        let f = filter(FilterConfig {
+            max_open_dist: Some(0.01),
+            ..FilterConfig::default()
+        });
+        assert_eq!(f.check(&t, true), Err("distance from open 0.01010 > 0.01".to_string()));
+        assert_eq!(f.check(&t, false), Ok(()));
+        // 没有开盘价时不检查
+        let mut t = t;
+        t.Open = 0;
+        assert_eq!(f.check(&t, true), Ok(()));
+    }
+}
@@ -0,0 +1,686 @@
+use chrono::NaiveDate;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::error::Error;
+
+use super::bar::Bar;
+use super::tick::Tick;
+
+// 技术指标，每个tick或K线增量更新，复杂度O(1)
+// 指标在配置中以字符串表示，参数用冒号分隔：
+// sma:20 ema:20 vwap rsi:14 macd:12:26:9 boll:20:2 boll_upper:20:2 boll_lower:20:2
+// atr:14 vol:20 obi
+// 价格单位与tick一致
+
+// 指标按tick还是按K线更新
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    #[default]
+    Tick,
+    Bar,
+}
+
+// 指标的输入，由tick或K线转换而来
+pub struct Sample {
+    pub date: NaiveDate, // 交易日
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+    pub bid_volume: f64, // 买1~买10总量，K线没有盘口数据时为0
+    pub ask_volume: f64, // 卖1~卖10总量
+}
+
+pub trait Indicator {
+    fn update(&mut self, s: &Sample);
+    // 数据不足时返回None
+    fn value(&self) -> Option<f64>;
+}
+
+// 简单移动平均
+pub struct Sma {
+    n: usize,
+    window: VecDeque<f64>,
+    sum: f64,
+}
+
+impl Indicator for Sma {
+    fn update(&mut self, s: &Sample) {
+        self.window.push_back(s.close);
+        self.sum += s.close;
+        if self.window.len() > self.n {
+            self.sum -= self.window.pop_front().unwrap();
+        }
+    }
+    fn value(&self) -> Option<f64> {
+        if self.window.len() < self.n {
+            return None;
+        }
+        Some(self.sum / self.n as f64)
+    }
+}
+
+// 指数移动平均
+pub struct Ema {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+fn new_ema(n: usize) -> Ema {
+    Ema {
+        alpha: 2.0 / (n as f64 + 1.0),
+        value: None,
+    }
+}
+
+impl Ema {
+    fn push(&mut self, x: f64) -> f64 {
+        let v = match self.value {
+            Some(v) => v + self.alpha * (x - v),
+            None => x,
+        };
+        self.value = Some(v);
+        v
+    }
+}
+
+impl Indicator for Ema {
+    fn update(&mut self, s: &Sample) {
+        self.push(s.close);
+    }
+    fn value(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+// 成交量加权平均价，每个交易日重新累计
+pub struct Vwap {
+    date: Option<NaiveDate>,
+    value: f64,
+    volume: f64,
+}
+
+impl Indicator for Vwap {
+    fn update(&mut self, s: &Sample) {
+        if self.date != Some(s.date) {
+            self.date = Some(s.date);
+            self.value = 0.0;
+            self.volume = 0.0;
+        }
+        self.value += s.close * s.volume;
+        self.volume += s.volume;
+    }
+    fn value(&self) -> Option<f64> {
+        if self.volume == 0.0 {
+            return None;
+        }
+        Some(self.value / self.volume)
+    }
+}
+
+// 相对强弱指标，Wilder平滑
+pub struct Rsi {
+    n: usize,
+    count: usize,
+    last: Option<f64>,
+    gain: f64,
+    loss: f64,
+}
+
+impl Indicator for Rsi {
+    fn update(&mut self, s: &Sample) {
+        if let Some(last) = self.last {
+            let diff = s.close - last;
+            let (gain, loss) = if diff > 0.0 { (diff, 0.0) } else { (0.0, -diff) };
+            self.count += 1;
+            // 前n个值取平均，之后按Wilder方式平滑
+            let n = self.count.min(self.n) as f64;
+            self.gain += (gain - self.gain) / n;
+            self.loss += (loss - self.loss) / n;
+        }
+        self.last = Some(s.close);
+    }
+    fn value(&self) -> Option<f64> {
+        if self.count < self.n {
+            return None;
+        }
+        if self.loss == 0.0 {
+            return Some(100.0);
+        }
+        Some(100.0 - 100.0 / (1.0 + self.gain / self.loss))
+    }
+}
+
+// MACD，值为柱状线（DIF - DEA）
+pub struct Macd {
+    fast: Ema,
+    slow: Ema,
+    signal: Ema,
+    hist: Option<f64>,
+}
+
+impl Indicator for Macd {
+    fn update(&mut self, s: &Sample) {
+        let dif = self.fast.push(s.close) - self.slow.push(s.close);
+        let dea = self.signal.push(dif);
+        self.hist = Some(dif - dea);
+    }
+    fn value(&self) -> Option<f64> {
+        self.hist
+    }
+}
+
+// 布林带，band为0取中轨，1取上轨，-1取下轨
+pub struct Bollinger {
+    sma: Sma,
+    sum_sq: f64,
+    k: f64,
+    band: f64,
+}
+
+impl Indicator for Bollinger {
+    fn update(&mut self, s: &Sample) {
+        self.sum_sq += s.close * s.close;
+        if self.sma.window.len() == self.sma.n {
+            let first = self.sma.window[0];
+            self.sum_sq -= first * first;
+        }
+        self.sma.update(s);
+    }
+    fn value(&self) -> Option<f64> {
+        let mid = self.sma.value()?;
+        let n = self.sma.n as f64;
+        let std = (self.sum_sq / n - mid * mid).max(0.0).sqrt();
+        Some(mid + self.band * self.k * std)
+    }
+}
+
+// 平均真实波幅，Wilder平滑
+pub struct Atr {
+    n: usize,
+    count: usize,
+    last_close: Option<f64>,
+    value: f64,
+}
+
+impl Indicator for Atr {
+    fn update(&mut self, s: &Sample) {
+        let tr = match self.last_close {
+            Some(c) => (s.high - s.low)
+                .max((s.high - c).abs())
+                .max((s.low - c).abs()),
+            None => s.high - s.low,
+        };
+        self.count += 1;
+        let n = self.count.min(self.n) as f64;
+        self.value += (tr - self.value) / n;
+        self.last_close = Some(s.close);
+    }
+    fn value(&self) -> Option<f64> {
+        if self.count < self.n {
+            return None;
+        }
+        Some(self.value)
+    }
+}
+
+// 滚动波动率，n个对数收益率的标准差
+pub struct Volatility {
+    n: usize,
+    last: Option<f64>,
+    window: VecDeque<f64>,
+    sum: f64,
+    sum_sq: f64,
+}
+
+impl Indicator for Volatility {
+    fn update(&mut self, s: &Sample) {
+        if let Some(last) = self.last {
+            if last > 0.0 && s.close > 0.0 {
+                let r = (s.close / last).ln();
+                self.window.push_back(r);
+                self.sum += r;
+                self.sum_sq += r * r;
+                if self.window.len() > self.n {
+                    let first = self.window.pop_front().unwrap();
+                    self.sum -= first;
+                    self.sum_sq -= first * first;
+                }
+            }
+        }
+        self.last = Some(s.close);
+    }
+    fn value(&self) -> Option<f64> {
+        if self.window.len() < self.n || self.n < 2 {
+            return None;
+        }
+        let n = self.n as f64;
+        let var = (self.sum_sq - self.sum * self.sum / n) / (n - 1.0);
+        Some(var.max(0.0).sqrt())
+    }
+}
+
+// 盘口不平衡度，(买量-卖量)/(买量+卖量)，取值-1~1
+pub struct Imbalance {
+    value: Option<f64>,
+}
+
+impl Indicator for Imbalance {
+    fn update(&mut self, s: &Sample) {
+        let total = s.bid_volume + s.ask_volume;
+        if total > 0.0 {
+            self.value = Some((s.bid_volume - s.ask_volume) / total);
+        }
+    }
+    fn value(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+fn param(parts: &[&str], idx: usize, spec: &str) -> Result<usize, Box<dyn Error>> {
+    let v: usize = parts
+        .get(idx)
+        .ok_or_else(|| format!("missing parameter {} of indicator {}", idx, spec))?
+        .parse()?;
+    if v == 0 {
+        return Err(format!("parameter of indicator {} must be greater than 0", spec).into());
+    }
+    Ok(v)
+}
+
+pub fn new_indicator(spec: &str) -> Result<Box<dyn Indicator>, Box<dyn Error>> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let bollinger = |band: f64| -> Result<Box<dyn Indicator>, Box<dyn Error>> {
+        let n = param(&parts, 1, spec)?;
+        let k: f64 = parts.get(2).unwrap_or(&"2").parse()?;
+        Ok(Box::new(Bollinger {
+            sma: Sma {
+                n,
+                window: VecDeque::new(),
+                sum: 0.0,
+            },
+            sum_sq: 0.0,
+            k,
+            band,
+        }))
+    };
+    let ind: Box<dyn Indicator> = match parts[0] {
+        "sma" => Box::new(Sma {
+            n: param(&parts, 1, spec)?,
+            window: VecDeque::new(),
+            sum: 0.0,
+        }),
+        "ema" => Box::new(new_ema(param(&parts, 1, spec)?)),
+        "vwap" => Box::new(Vwap {
+            date: None,
+            value: 0.0,
+            volume: 0.0,
+        }),
+        "rsi" => Box::new(Rsi {
+            n: param(&parts, 1, spec)?,
+            count: 0,
+            last: None,
+            gain: 0.0,
+            loss: 0.0,
+        }),
+        "macd" => Box::new(Macd {
+            fast: new_ema(param(&parts, 1, spec)?),
+            slow: new_ema(param(&parts, 2, spec)?),
+            signal: new_ema(param(&parts, 3, spec)?),
+            hist: None,
+        }),
+        "boll" => bollinger(0.0)?,
+        "boll_upper" => bollinger(1.0)?,
+        "boll_lower" => bollinger(-1.0)?,
+        "atr" => Box::new(Atr {
+            n: param(&parts, 1, spec)?,
+            count: 0,
+            last_close: None,
+            value: 0.0,
+        }),
+        "vol" => Box::new(Volatility {
+            n: param(&parts, 1, spec)?,
+            last: None,
+            window: VecDeque::new(),
+            sum: 0.0,
+            sum_sq: 0.0,
+        }),
+        "obi" => Box::new(Imbalance { value: None }),
+        _ => return Err(format!("unknown indicator: {}", spec).into()),
+    };
+    Ok(ind)
+}
+
+// 过滤条件，格式为 "左值 比较符 右值"，如 "price > vwap"、"rsi:14 < 70"
+// 值可以是price（当前价）、数字或指标
+#[derive(Debug)]
+enum Operand {
+    Price,
+    Const(f64),
+    Indicator(usize),
+}
+
+pub struct Filter {
+    expr: String,
+    left: Operand,
+    op: String,
+    right: Operand,
+}
+
+// 策略使用的所有指标和过滤条件
+pub struct IndicatorSet {
+    source: Source,
+    names: Vec<String>,
+    indicators: Vec<Box<dyn Indicator>>,
+    filters: Vec<Filter>,
+    last_volume: Option<u64>,
+}
+
+pub fn new_indicator_set(filters: &[String], source: Source) -> Result<IndicatorSet, Box<dyn Error>> {
+    let mut set = IndicatorSet {
+        source,
+        names: Vec::new(),
+        indicators: Vec::new(),
+        filters: Vec::new(),
+        last_volume: None,
+    };
+    for expr in filters {
+        let parts: Vec<&str> = expr.split_whitespace().collect();
+        if parts.len() != 3 || !["<", "<=", ">", ">="].contains(&parts[1]) {
+            return Err(format!("invalid filter: {}", expr).into());
+        }
+        let left = set.operand(parts[0])?;
+        let right = set.operand(parts[2])?;
+        set.filters.push(Filter {
+            expr: expr.to_string(),
+            left,
+            op: parts[1].to_string(),
+            right,
+        });
+    }
+    Ok(set)
+}
+
+impl IndicatorSet {
+    fn operand(&mut self, s: &str) -> Result<Operand, Box<dyn Error>> {
+        if s == "price" {
+            return Ok(Operand::Price);
+        }
+        if let Ok(v) = s.parse::<f64>() {
+            return Ok(Operand::Const(v));
+        }
+        // 相同的指标只计算一次
+        if let Some(idx) = self.names.iter().position(|n| n == s) {
+            return Ok(Operand::Indicator(idx));
+        }
+        // K线没有盘口数据
+        if self.source == Source::Bar && s == "obi" {
+            return Err(format!("indicator {} needs order book, can not update by bar", s).into());
+        }
+        self.indicators.push(new_indicator(s)?);
+        self.names.push(s.to_string());
+        Ok(Operand::Indicator(self.indicators.len() - 1))
+    }
+
+    fn update(&mut self, s: &Sample) {
+        for ind in &mut self.indicators {
+            ind.update(s);
+        }
+    }
+
+    pub fn update_tick(&mut self, tick: &Tick) {
+        // 集合竞价期间没有成交价
+        if tick.nPrice == 0 {
+            return;
+        }
+        let volume = tick
+            .TotalVolume
+            .saturating_sub(self.last_volume.unwrap_or(tick.TotalVolume));
+        self.last_volume = Some(tick.TotalVolume);
+        let price = tick.nPrice as f64;
+        self.update(&Sample {
+            date: tick.dt.date_naive(),
+            high: price,
+            low: price,
+            close: price,
+            volume: volume as f64,
+            bid_volume: tick.bids().iter().map(|(_, v)| *v as f64).sum(),
+            ask_volume: tick.asks().iter().map(|(_, v)| *v as f64).sum(),
+        });
+    }
+
+    pub fn update_bar(&mut self, bar: &Bar) {
+        self.update(&Sample {
+            date: bar.start.date_naive(),
+            high: bar.high as f64,
+            low: bar.low as f64,
+            close: bar.close as f64,
+            volume: bar.volume as f64,
+            bid_volume: 0.0,
+            ask_volume: 0.0,
+        });
+    }
+
+    fn eval(&self, o: &Operand, price: u64) -> Option<f64> {
+        match o {
+            Operand::Price => Some(price as f64),
+            Operand::Const(v) => Some(*v),
+            Operand::Indicator(idx) => self.indicators[*idx].value(),
+        }
+    }
+
+    // 所有过滤条件都满足才返回true，指标数据不足时视为不满足
+    pub fn check(&self, price: u64) -> Result<(), String> {
+        for f in &self.filters {
+            let pass = match (self.eval(&f.left, price), self.eval(&f.right, price)) {
+                (Some(l), Some(r)) => match f.op.as_str() {
+                    "<" => l < r,
+                    "<=" => l <= r,
+                    ">" => l > r,
+                    _ => l >= r,
+                },
+                _ => false,
+            };
+            if !pass {
+                return Err(f.expr.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::empty_tick;
+    use crate::tick::{get_time, trade_date};
+
+    fn close(date: NaiveDate, close: f64, volume: f64) -> Sample {
+        Sample {
+            date,
+            high: close,
+            low: close,
+            close,
+            volume,
+            bid_volume: 0.0,
+            ask_volume: 0.0,
+        }
+    }
+
+    // 依次输入收盘价，返回每次更新后的值
+    fn series(spec: &str, closes: &[f64]) -> Vec<Option<f64>> {
+        let date = trade_date(0).unwrap();
+        let mut ind = new_indicator(spec).unwrap();
+        closes
+            .iter()
+            .map(|c| {
+                ind.update(&close(date, *c, 1.0));
+                ind.value()
+            })
+            .collect()
+    }
+
+    fn assert_near(got: Option<f64>, want: f64) {
+        let got = got.unwrap();
+        assert!((got - want).abs() < 1e-9, "{} != {}", got, want);
+    }
+
+    #[test]
+    fn sma() {
+        let v = series("sma:3", &[1.0, 2.0, 3.0, 4.0, 8.0]);
+        assert_eq!(&v[..2], &[None, None]);
+        assert_near(v[2], 2.0);
+        assert_near(v[3], 3.0);
+        assert_near(v[4], 5.0);
+    }
+
+    #[test]
+    fn ema() {
+        // alpha = 2 / (3 + 1) = 0.5
+        let v = series("ema:3", &[1.0, 2.0, 3.0, 1.0]);
+        assert_near(v[0], 1.0);
+        assert_near(v[1], 1.5);
+        assert_near(v[2], 2.25);
+        assert_near(v[3], 1.625);
+    }
+
+    #[test]
+    fn vwap_resets_daily() {
+        let day1 = trade_date(0).unwrap();
+        let day2 = trade_date(20211101).unwrap();
+        let mut ind = new_indicator("vwap").unwrap();
+        ind.update(&close(day1, 10.0, 0.0));
+        assert_eq!(ind.value(), None);
+        ind.update(&close(day1, 10.0, 100.0));
+        ind.update(&close(day1, 20.0, 300.0));
+        assert_near(ind.value(), 17.5);
+        ind.update(&close(day2, 30.0, 100.0));
+        assert_near(ind.value(), 30.0);
+    }
+
+    #[test]
+    fn rsi() {
+        // 涨1、涨2、跌1：前2个取平均 gain=1.5 loss=0，之后 gain=0.75 loss=0.5
+        let v = series("rsi:2", &[1.0, 2.0, 4.0, 3.0]);
+        assert_eq!(&v[..2], &[None, None]);
+        assert_near(v[2], 100.0);
+        assert_near(v[3], 60.0);
+    }
+
+    #[test]
+    fn macd() {
+        // fast为ema:1即收盘价，slow和signal的alpha都是0.5
+        // dif: 0 0.5 0.75，dea: 0 0.25 0.5
+        let v = series("macd:1:3:3", &[1.0, 2.0, 3.0]);
+        assert_near(v[0], 0.0);
+        assert_near(v[1], 0.25);
+        assert_near(v[2], 0.25);
+    }
+
+    #[test]
+    fn bollinger() {
+        let v = series("boll:3:2", &[1.0, 2.0, 3.0]);
+        assert_eq!(v[1], None);
+        assert_near(v[2], 2.0);
+        // 总体标准差 sqrt(2/3)
+        let std = (2.0f64 / 3.0).sqrt();
+        assert_near(series("boll_upper:3:2", &[1.0, 2.0, 3.0])[2], 2.0 + 2.0 * std);
+        assert_near(series("boll_lower:3:1", &[1.0, 2.0, 3.0, 4.0])[3], 3.0 - std);
+    }
+
+    #[test]
+    fn bollinger_running_sum_sq() {
+        // 窗口滑动很多次之后，累计的平方和与直接计算的一致
+        let closes: Vec<f64> = (0..500).map(|i| 400000.0 + ((i * 7919) % 23) as f64 * 100.0).collect();
+        let v = series("boll_upper:20:2", &closes);
+        for (i, got) in v.iter().enumerate().skip(19) {
+            let window = &closes[i - 19..=i];
+            let mean = window.iter().sum::<f64>() / 20.0;
+            let var = window.iter().map(|c| (c - mean) * (c - mean)).sum::<f64>() / 20.0;
+            let want = mean + 2.0 * var.sqrt();
+            assert!((got.unwrap() - want).abs() < 1e-3, "{}: {:?} != {}", i, got, want);
+        }
+    }
+
+    #[test]
+    fn atr() {
+        let date = trade_date(0).unwrap();
+        let mut ind = new_indicator("atr:2").unwrap();
+        let bars = [(10.0, 8.0, 9.0), (12.0, 9.0, 11.0), (11.0, 10.0, 10.0)];
+        let mut v = Vec::new();
+        for (high, low, close) in bars {
+            ind.update(&Sample {
+                date,
+                high,
+                low,
+                close,
+                volume: 0.0,
+                bid_volume: 0.0,
+                ask_volume: 0.0,
+            });
+            v.push(ind.value());
+        }
+        // 真实波幅 2 3 1
+        assert_eq!(v[0], None);
+        assert_near(v[1], 2.5);
+        assert_near(v[2], 1.75);
+    }
+
+    #[test]
+    fn volatility() {
+        let v = series("vol:2", &[100.0, 110.0, 99.0]);
+        assert_eq!(&v[..2], &[None, None]);
+        // 两个值的样本标准差为差的绝对值除以根号2
+        let (r1, r2) = ((1.1f64).ln(), (0.9f64).ln());
+        assert_near(v[2], (r1 - r2).abs() / 2.0f64.sqrt());
+        assert_eq!(series("vol:1", &[100.0, 110.0]), vec![None, None]);
+    }
+
+    #[test]
+    fn imbalance() {
+        let mut ind = new_indicator("obi").unwrap();
+        ind.update(&Sample {
+            bid_volume: 300.0,
+            ask_volume: 100.0,
+            ..close(trade_date(0).unwrap(), 1.0, 0.0)
+        });
+        assert_near(ind.value(), 0.5);
+    }
+
+    #[test]
+    fn bad_specs() {
+        for spec in ["sma", "sma:0", "sma:x", "macd:12:26", "foo:3"] {
+            assert!(new_indicator(spec).is_err(), "{}", spec);
+        }
+        assert!(new_indicator_set(&["price >".to_string()], Source::Tick).is_err());
+        assert!(new_indicator_set(&["price == 1".to_string()], Source::Tick).is_err());
+        assert!(new_indicator_set(&["obi > 0".to_string()], Source::Bar).is_err());
+    }
+
+    fn tick(ntime: u64, price: u64, volume: u64) -> Tick {
+        let mut t = empty_tick("601012.SH");
+        t.nTime = ntime;
+        t.dt = get_time(trade_date(0).unwrap(), ntime).unwrap();
+        t.nPrice = price;
+        t.TotalVolume = volume;
+        t
+    }
+
+    #[test]
+    fn filters_on_ticks() {
+        let filters = ["price > sma:2".to_string(), "vwap >= 400000".to_string()];
+        let mut set = new_indicator_set(&filters, Source::Tick).unwrap();
+        set.update_tick(&tick(93000000, 400000, 1000));
+        // sma数据不足
+        assert_eq!(set.check(400000), Err("price > sma:2".to_string()));
+        // 集合竞价没有成交价的tick不更新指标
+        set.update_tick(&tick(93001000, 0, 1000));
+        set.update_tick(&tick(93003000, 400200, 1500));
+        assert_eq!(set.check(400200), Ok(()));
+        assert_eq!(set.check(400100), Err("price > sma:2".to_string()));
+        // 第一个tick的成交量不计入vwap，只有第二个tick的500股
+        set.update_tick(&tick(93006000, 399000, 1500));
+        assert_eq!(set.check(400300), Ok(()));
+    }
+}
@@ -0,0 +1,300 @@
+// 证券品种相关的交易规则
+// 不同板块/品种的交易单位（手）和涨跌幅限制不一样，下单前需要按交易所规则校验，否则真实交易所会拒单
+
+use super::tick::Tick;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Board {
+    Main,    // 沪深主板
+    Star,    // 科创板 688/689
+    ChiNext, // 创业板 300/301
+    Beijing, // 北交所
+    Etf,     // ETF/LOF
+    Bond,    // 可转债/债券
+}
+
+// 交易单位规则：买入数量需不小于min，且超过min的部分为step的整数倍
+// 沪深主板为100股的整数倍(min=100,step=100)，科创板为200股起、1股递增(min=200,step=1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LotRule {
+    pub min: u64,
+    pub step: u64,
+}
+
+// 根据代码判断品种，如 601012.SH / 300750.SZ / 688981.SH
+pub fn board_of(code: &str) -> Board {
+    let mut parts = code.split('.');
+    let num = parts.next().unwrap_or("");
+    let market = parts.next().unwrap_or("").to_uppercase();
+    match market.as_str() {
+        "SH" => {
+            if num.starts_with("688") || num.starts_with("689") {
+                Board::Star
+            } else if num.starts_with("51") || num.starts_with("56") || num.starts_with("58") {
+                Board::Etf
+            } else if num.starts_with("01") || num.starts_with("11") || num.starts_with("12") {
+                // 国债010/019，可转债110/113/118，公司债12x，000开头的是指数不是债券
+                Board::Bond
+            } else {
+                Board::Main
+            }
+        }
+        "SZ" => {
+            if num.starts_with("300") || num.starts_with("301") {
+                Board::ChiNext
+            } else if num.starts_with("15") || num.starts_with("16") {
+                Board::Etf
+            } else if num.starts_with("12") || num.starts_with("10") || num.starts_with("11") {
+                Board::Bond
+            } else {
+                Board::Main
+            }
+        }
+        "BJ" => Board::Beijing,
+        _ => Board::Main,
+    }
+}
+
+impl Board {
+    pub fn lot_rule(&self) -> LotRule {
+        match self {
+            Board::Main | Board::ChiNext | Board::Etf => LotRule { min: 100, step: 100 },
+            Board::Star => LotRule { min: 200, step: 1 },
+            Board::Beijing => LotRule { min: 100, step: 1 },
+            Board::Bond => LotRule { min: 10, step: 10 }, // 1手=10张
+        }
+    }
+}
+
+impl LotRule {
+    // 买入数量向下取整到合法的交易单位，不足最小单位时返回0
+    pub fn round_buy(&self, volume: u64) -> u64 {
+        if volume < self.min {
+            return 0;
+        }
+        volume - (volume - self.min) % self.step
+    }
+
+    // 卖出数量向下取整到合法的交易单位
+    // 零股只允许在一次性卖出全部剩余持仓时卖出
+    pub fn round_sell(&self, volume: u64, left: u64) -> u64 {
+        if volume >= left {
+            return left;
+        }
+        self.round_buy(volume)
+    }
+
+    pub fn is_valid_buy(&self, volume: u64) -> bool {
+        volume > 0 && self.round_buy(volume) == volume
+    }
+}
+
+// 涨跌停价格区间，单位与tick中的价格一致
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PriceLimit {
+    pub low: u64,
+    pub high: u64,
+}
+
+impl Board {
+    // 涨跌幅限制比例，主板ST股票为5%，科创板和创业板的ST股票仍然是20%
+    pub fn limit_band(&self, st: bool) -> f64 {
+        match self {
+            Board::Main if st => 0.05,
+            Board::Main | Board::Etf => 0.10,
+            Board::Star | Board::ChiNext | Board::Bond => 0.20,
+            Board::Beijing => 0.30,
+        }
+    }
+}
+
+// tick数据中的涨跌停价比普通值少了一位，需要乘以10
+// 数据中缺少涨跌停价时，按昨收价和板块的涨跌幅比例计算，价格四舍五入到分
+pub fn price_limit(tick: &Tick, st: bool) -> PriceLimit {
+    let band = board_of(&tick.chWindCode).limit_band(st);
+    let by_band = |rate: f64| ((tick.PreClose as f64 * rate / 100.0).round() as u64) * 100;
+    PriceLimit {
+        low: if tick.LowLimited > 0 {
+            tick.LowLimited * 10
+        } else {
+            by_band(1.0 - band)
+        },
+        high: if tick.HighLimited > 0 {
+            tick.HighLimited * 10
+        } else if tick.PreClose > 0 {
+            by_band(1.0 + band)
+        } else {
+            u64::MAX
+        },
+    }
+}
+
+impl PriceLimit {
+    pub fn contains(&self, price: u64) -> bool {
+        price >= self.low && price <= self.high
+    }
+
+    // 超出涨跌停范围的委托价调整到涨跌停价
+    pub fn clamp(&self, price: u64) -> u64 {
+        price.max(self.low).min(self.high)
+    }
+
+    // 涨停封板：价格在涨停价且卖盘为空，买不到
+    pub fn is_sealed_up(&self, tick: &Tick) -> bool {
+        tick.nAskVolume1 == 0 && (tick.nPrice >= self.high || tick.nBidPrice1 >= self.high)
+    }
+
+    // 跌停封板：价格在跌停价且买盘为空，卖不出
+    // 卖盘为空或者还没有成交价时价格为0，不能当作跌停
+    pub fn is_sealed_down(&self, tick: &Tick) -> bool {
+        tick.nBidVolume1 == 0
+            && ((tick.nPrice > 0 && tick.nPrice <= self.low)
+                || (tick.nAskPrice1 > 0 && tick.nAskPrice1 <= self.low))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::empty_tick;
+
+    fn tick(code: &str, pre_close: u64) -> Tick {
+        let mut t = empty_tick(code);
+        t.PreClose = pre_close;
+        t
+    }
+
+    #[test]
+    fn boards() {
+        assert_eq!(board_of("601012.SH"), Board::Main);
+        assert_eq!(board_of("000001.SH"), Board::Main);
+        assert_eq!(board_of("000001.SZ"), Board::Main);
+        assert_eq!(board_of("688981.SH"), Board::Star);
+        assert_eq!(board_of("300750.SZ"), Board::ChiNext);
+        assert_eq!(board_of("510300.SH"), Board::Etf);
+        assert_eq!(board_of("019547.SH"), Board::Bond);
+        assert_eq!(board_of("113050.SH"), Board::Bond);
+        assert_eq!(board_of("128136.SZ"), Board::Bond);
+        assert_eq!(board_of("430047.BJ"), Board::Beijing);
+    }
+
+    #[test]
+    fn lot_rules() {
+        assert_eq!(board_of("601012.SH").lot_rule(), LotRule { min: 100, step: 100 });
+        assert_eq!(board_of("688981.SH").lot_rule(), LotRule { min: 200, step: 1 });
+        assert_eq!(board_of("113050.SH").lot_rule(), LotRule { min: 10, step: 10 });
+    }
+
+    #[test]
+    fn round_buy() {
+        let main = Board::Main.lot_rule();
+        assert_eq!(main.round_buy(99), 0);
+        assert_eq!(main.round_buy(100), 100);
+        assert_eq!(main.round_buy(1050), 1000);
+        // 科创板200股起，超过部分按1股递增
+        let star = Board::Star.lot_rule();
+        assert_eq!(star.round_buy(199), 0);
+        assert_eq!(star.round_buy(201), 201);
+        let bj = Board::Beijing.lot_rule();
+        assert_eq!(bj.round_buy(150), 150);
+    }
+
+    #[test]
+    fn round_sell() {
+        let main = Board::Main.lot_rule();
+        // 卖出全部剩余时允许零股
+        assert_eq!(main.round_sell(150, 150), 150);
+        assert_eq!(main.round_sell(500, 150), 150);
+        // 部分卖出要按整手，剩下的零股留到最后
+        assert_eq!(main.round_sell(150, 1050), 100);
+        assert_eq!(main.round_sell(50, 1050), 0);
+        let star = Board::Star.lot_rule();
+        assert_eq!(star.round_sell(150, 1000), 0);
+        assert_eq!(star.round_sell(250, 1000), 250);
+    }
+
+    #[test]
+    fn valid_buy() {
+        let main = Board::Main.lot_rule();
+        assert!(main.is_valid_buy(100));
+        assert!(main.is_valid_buy(1000));
+        assert!(!main.is_valid_buy(0));
+        assert!(!main.is_valid_buy(150));
+        let star = Board::Star.lot_rule();
+        assert!(star.is_valid_buy(201));
+        assert!(!star.is_valid_buy(100));
+    }
+
+    #[test]
+    fn st_band_only_on_main_board() {
+        assert_eq!(Board::Main.limit_band(true), 0.05);
+        assert_eq!(Board::Main.limit_band(false), 0.10);
+        assert_eq!(Board::Star.limit_band(true), 0.20);
+        assert_eq!(Board::ChiNext.limit_band(true), 0.20);
+        assert_eq!(Board::Beijing.limit_band(true), 0.30);
+    }
+
+    #[test]
+    fn limit_from_pre_close() {
+        // 昨收40.00元，主板涨跌停44.00/36.00
+        let limit = price_limit(&tick("601012.SH", 400000), false);
+        assert_eq!(limit, PriceLimit { low: 360000, high: 440000 });
+        // ST股票按5%计算，四舍五入到分：12.34 * 1.05 = 12.957 -> 12.96
+        let limit = price_limit(&tick("600000.SH", 123400), true);
+        assert_eq!(limit, PriceLimit { low: 117200, high: 129600 });
+        let limit = price_limit(&tick("300750.SZ", 123400), true);
+        assert_eq!(limit, PriceLimit { low: 98700, high: 148100 });
+    }
+
+    #[test]
+    fn limit_from_tick() {
+        // tick中的涨跌停价少一位
+        let mut t = tick("601012.SH", 400000);
+        t.HighLimited = 44000;
+        t.LowLimited = 36000;
+        let limit = price_limit(&t, true);
+        assert_eq!(limit, PriceLimit { low: 360000, high: 440000 });
+        // 没有昨收时不限制涨停
+        assert_eq!(price_limit(&tick("601012.SH", 0), false).high, u64::MAX);
+    }
+
+    #[test]
+    fn clamp_and_sealed() {
+        let limit = PriceLimit { low: 360000, high: 440000 };
+        assert!(limit.contains(440000));
+        assert!(!limit.contains(440100));
+        assert_eq!(limit.clamp(450000), 440000);
+        assert_eq!(limit.clamp(350000), 360000);
+
+        let mut t = tick("601012.SH", 400000);
+        t.nPrice = 440000;
+        t.nBidPrice1 = 440000;
+        t.nBidVolume1 = 1000;
+        assert!(limit.is_sealed_up(&t));
+        t.nAskVolume1 = 100;
+        assert!(!limit.is_sealed_up(&t));
+        assert!(!limit.is_sealed_down(&t));
+    }
+
+    #[test]
+    fn sealed_down() {
+        let limit = PriceLimit { low: 360000, high: 440000 };
+        let mut t = tick("601012.SH", 400000);
+        t.nPrice = 360000;
+        t.nAskPrice1 = 360000;
+        t.nAskVolume1 = 5000;
+        assert!(limit.is_sealed_down(&t));
+        t.nBidPrice1 = 359900;
+        t.nBidVolume1 = 100;
+        assert!(!limit.is_sealed_down(&t));
+    }
+
+    #[test]
+    fn empty_book_is_not_sealed_down() {
+        let limit = PriceLimit { low: 360000, high: 440000 };
+        let mut t = tick("601012.SH", 400000);
+        assert!(!limit.is_sealed_down(&t));
+        t.nPrice = 400000;
+        assert!(!limit.is_sealed_down(&t));
+    }
+}
@@ -1,26 +1,255 @@
 #[macro_use]
 extern crate log;
 
+#[macro_use]
+mod cache;
+mod attribution;
+mod bar;
+mod benchmark;
+mod book;
+mod engine;
+mod entrust;
+mod feed;
+mod filter;
+mod indicator;
+mod instrument;
+mod manifest;
+mod margin;
+#[cfg(feature = "parquet")]
+mod parquet_io;
+mod position;
+mod report;
+mod risk;
+mod schema;
 mod strategy;
 mod tick;
+mod timeline;
 mod transaction;
+mod validate;
+mod window;
 
-use strategy::{new_stock_sys, read_tick_from_data};
-use transaction::read_trans_data_from_file;
+use feed::{open_tick_feed, TickFeed};
+use std::collections::{HashMap, HashSet};
+use strategy::{new_stock_sys, StockSys};
 
-fn back_testing() {
-    let mut sys = new_stock_sys("src/strategy.toml").expect("fail to create new sotck instance");
+// 返回保存运行清单的目录，run_dir为空时不保存
+fn back_testing(config: &str) -> Option<String> {
+    let start = chrono::Local::now();
+    let mut sys = new_stock_sys(config).expect("fail to create new sotck instance");
     sys.init_logger();
-    let ticks = read_tick_from_data(&sys.conf.tick_data).expect("read ticks data failed!");
-   // sys.trans = read_trans_data_from_file(&sys.conf.trans_data).expect("read transaction data failed!");
+    let ticks = if sys.conf.order_data.is_empty() {
+        open_tick_feed(
+            &sys.conf.tick_data,
+            sys.conf.read_ahead,
+            &sys.conf.schema,
+            sys.conf.date(),
+        )
+        .expect("read ticks data failed!")
+    } else {
+        replay_order_book(&sys)
+    };
+    let trans = if sys.conf.trans_data.is_empty() {
+        transaction::new_trans_store(Vec::new())
+    } else {
+        transaction::read_trans_store(&sys.conf.trans_data, sys.conf.date())
+            .expect("read transaction data failed!")
+    };
+    info!("load {} transactions from {}", trans.len(), sys.conf.trans_data);
+    let mut validator = if sys.conf.validate.enabled {
+        Some(validate::new_validator(sys.conf.validate.clone()))
+    } else {
+        None
+    };
 
-    for tick in ticks {
-        sys.do_strategy(&tick);
+    let ticks = engine::run(
+        &mut sys,
+        timeline::new_timeline(ticks, &trans),
+        validator.as_mut(),
+    )
+    .expect("read ticks data failed!");
+    sys.finish();
+    if let Some(v) = &validator {
+        info!("validate {}: {}", sys.conf.tick_data, v.report);
     }
 
     sys.statistics();
+    if !sys.conf.parquet_output.is_empty() {
+        write_parquet(&sys);
+    }
+    if !sys.conf.html_report.is_empty() {
+        let text = std::fs::read_to_string(config).unwrap_or_default();
+        report::write_html(&sys.conf.html_report, &sys, &text).expect("write html report failed!");
+    }
+    if sys.conf.run_dir.is_empty() {
+        return None;
+    }
+    let dir = manifest::write_run(&sys, config, start, ticks, trans.len())
+        .expect("write run manifest failed!");
+    info!("write run manifest to {}", dir);
+    Some(dir)
+}
+
+// 用逐笔委托和逐笔成交重建委托簿，每个代码一个委托簿，输出快照
+// tick数据存在时取每个代码的第一条作为快照的模板，提供昨收、涨跌停价等逐笔数据中没有的字段
+fn replay_order_book(sys: &StockSys) -> TickFeed {
+    let date = sys.conf.date();
+    let entrusts = entrust::read_entrust_from_file(&sys.conf.order_data, date)
+        .expect("read order data failed!");
+    let trans = transaction::read_trans_records(&sys.conf.trans_data, date)
+        .expect("read transaction data failed!");
+    let codes: HashSet<&str> = entrusts
+        .iter()
+        .map(|e| e.Tkr.as_str())
+        .chain(trans.iter().map(|t| t.Tkr.as_str()))
+        .collect();
+    let mut templates = HashMap::new();
+    if let Ok(ticks) = open_tick_feed(&sys.conf.tick_data, sys.conf.read_ahead, &sys.conf.schema, date) {
+        for tick in ticks.map_while(|t| t.ok()) {
+            if codes.contains(tick.chWindCode.as_str()) && !templates.contains_key(&tick.chWindCode) {
+                templates.insert(tick.chWindCode.clone(), tick);
+                if templates.len() == codes.len() {
+                    break;
+                }
+            }
+        }
+    }
+    info!("replay order book of {} codes, {} with tick template", codes.len(), templates.len());
+    Box::new(book::new_book_replay(
+        templates,
+        entrusts,
+        trans,
+        sys.conf.snapshot_interval,
+        date,
+    ))
+}
+
+// 输出parquet格式的交易记录和资金曲线
+#[cfg(feature = "parquet")]
+fn write_parquet(sys: &StockSys) {
+    let prefix = &sys.conf.parquet_output;
+    parquet_io::write_trades(&format!("{}_trades.parquet", prefix), &sys.orders, &sys.shorts)
+        .expect("write trades to parquet failed!");
+    parquet_io::write_equity(&format!("{}_equity.parquet", prefix), &sys.equity)
+        .expect("write equity curve to parquet failed!");
+}
+
+#[cfg(not(feature = "parquet"))]
+fn write_parquet(_: &StockSys) {
+    warn!("parquet_output is set but the parquet feature is not enabled");
+}
+
+// 把CSV转换为二进制缓存，tick数据按strategy.toml中[schema]的列名映射读取
+// 不指定日期时使用strategy.toml中的trade_date
+// cache tick|trans <csv> <out> [yyyymmdd]
+fn convert_cache(args: &[String]) {
+    if args.len() < 3 {
+        eprintln!("usage: cache tick|trans <csv> <out> [yyyymmdd]");
+        std::process::exit(1);
+    }
+    let conf = strategy::new_config("src/strategy.toml").expect("fail to read config");
+    let date: u32 = match args.get(3) {
+        Some(d) => d.parse().expect("invalid date, expect yyyymmdd"),
+        None => conf.trade_date,
+    };
+    let rows = match args[0].as_str() {
+        "tick" => cache::convert_tick_csv(&args[1], &args[2], date, &conf.schema),
+        "trans" => cache::convert_trans_csv(&args[1], &args[2], date),
+        _ => {
+            eprintln!("unknown cache type {}, expect tick or trans", args[0]);
+            std::process::exit(1);
+        }
+    }
+    .expect("convert cache failed!");
+    println!("write {} rows to {}", rows, args[2]);
+}
+
+// 按strategy.toml中[validate]的规则检查tick数据，输出异常报告
+// 指定输出文件时把清洗后的数据写为CSV
+// validate-data <file>... [-o <out.csv>]
+fn validate_data(args: &[String]) {
+    let mut files = Vec::new();
+    let mut out = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "-o" {
+            out = iter.next();
+        } else {
+            files.push(arg);
+        }
+    }
+    if files.is_empty() || (out.is_some() && files.len() > 1) {
+        eprintln!("usage: validate-data <file>... [-o <out.csv>]");
+        std::process::exit(1);
+    }
+    let conf = strategy::new_config("src/strategy.toml").expect("fail to read config");
+    for file in files {
+        let mut validator = validate::new_validator(conf.validate.clone());
+        let mut writer = out.map(|o| csv::Writer::from_path(o).expect("create output failed!"));
+        let ticks =
+            open_tick_feed(file, conf.read_ahead, &conf.schema, conf.date())
+                .expect("read ticks data failed!");
+        for tick in ticks {
+            let tick = tick.expect("read ticks data failed!");
+            if let Some(tick) = validator.check(tick) {
+                if let Some(w) = &mut writer {
+                    w.serialize(&tick).expect("write output failed!");
+                }
+            }
+        }
+        if let Some(w) = &mut writer {
+            w.flush().expect("write output failed!");
+        }
+        println!("{}: {}", file, validator.report);
+    }
+}
+
+// 用运行目录中的配置快照重新运行，并和原来的结果比较
+fn rerun(args: &[String]) {
+    if args.is_empty() {
+        eprintln!("usage: rerun <run dir>");
+        std::process::exit(1);
+    }
+    let old = manifest::read_manifest(&args[0]).expect("read run manifest failed!");
+    for changed in manifest::check_data(&old) {
+        eprintln!("data changed since the run: {}", changed);
+    }
+    let config = std::path::Path::new(&args[0]).join(manifest::CONFIG_FILE);
+    match back_testing(&config.to_string_lossy()) {
+        Some(dir) => {
+            println!("rerun saved to {}", dir);
+            print_diff(&args[0], &dir);
+        }
+        None => eprintln!("run_dir of the config is empty, nothing to compare"),
+    }
+}
+
+fn diff_runs(args: &[String]) {
+    if args.len() < 2 {
+        eprintln!("usage: diff-runs <run dir> <run dir>");
+        std::process::exit(1);
+    }
+    print_diff(&args[0], &args[1]);
+}
+
+fn print_diff(a: &str, b: &str) {
+    let diffs = manifest::diff_runs(a, b).expect("diff runs failed!");
+    if diffs.is_empty() {
+        println!("{} and {} are identical", a, b);
+    }
+    for d in diffs {
+        println!("{}", d);
+    }
 }
 
 fn main() {
-    back_testing();
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("cache") => convert_cache(&args[2..]),
+        Some("validate-data") => validate_data(&args[2..]),
+        Some("rerun") => rerun(&args[2..]),
+        Some("diff-runs") => diff_runs(&args[2..]),
+        _ => {
+            back_testing("src/strategy.toml");
+        }
+    }
 }
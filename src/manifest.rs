@@ -0,0 +1,439 @@
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use super::margin::short;
+use super::strategy::{order, StockSys};
+
+// 运行清单，记录一次回测的输入和结果，用于复现和比较
+// 每次运行在run_dir下建一个子目录，包括manifest.toml、配置快照strategy.toml和交易记录orders.csv
+pub const MANIFEST_FILE: &str = "manifest.toml";
+pub const CONFIG_FILE: &str = "strategy.toml";
+pub const ORDERS_FILE: &str = "orders.csv";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub engine_version: String,
+    pub seed: u64,
+    pub start: String,
+    pub end: String,
+    pub config_file: String, // 运行时使用的配置文件，快照保存在同一目录的strategy.toml
+    pub config_hash: String,
+    pub results: RunResults,
+    pub data: Vec<DataFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct DataFile {
+    pub kind: String,
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunResults {
+    pub orders: usize,
+    pub closed_orders: usize,
+    pub profit: i64,
+    pub profit_with_tax_commission: i64,
+    pub shorts: usize,
+    pub short_profit_with_fee: i64,
+}
+
+// 引擎版本，加上编译时的git提交，工作区有未提交的修改时带-dirty，见build.rs
+pub fn engine_version() -> String {
+    format!(
+        "{}+{}",
+        env!("CARGO_PKG_VERSION"),
+        option_env!("ENGINE_GIT_COMMIT").unwrap_or("unknown")
+    )
+}
+
+// 交易记录，每个订单一行，side为long时先买后卖，为short时先融券卖出后买入平仓
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct TradeRow {
+    side: String,
+    code: String,
+    open_time: String,
+    close_time: String,
+    volume: usize,
+    left: usize,
+    open_price: u64,
+    close_price_avg: u64,
+    profit: i64,
+    tax: u64,
+    commission: u64,
+    fee: u64,
+}
+
+// 盈亏在内部是i128，超出i64时报错而不是截断
+pub fn to_i64(v: i128, what: &str) -> Result<i64, Box<dyn Error>> {
+    i64::try_from(v).map_err(|_| format!("{} {} is out of range of i64", what, v).into())
+}
+
+fn trade_row(o: &order) -> Result<TradeRow, Box<dyn Error>> {
+    Ok(TradeRow {
+        side: "long".to_string(),
+        code: o.code.clone(),
+        open_time: o.time.to_rfc3339(),
+        close_time: o.selt_time.to_rfc3339(),
+        volume: o.volume,
+        left: o.left,
+        open_price: o.open_price,
+        close_price_avg: o.sell_price_avg,
+        profit: to_i64(o.profit, "order profit")?,
+        tax: o.tax,
+        commission: o.commission,
+        fee: 0,
+    })
+}
+
+fn short_row(s: &short) -> Result<TradeRow, Box<dyn Error>> {
+    Ok(TradeRow {
+        side: "short".to_string(),
+        code: s.code.clone(),
+        open_time: s.time.to_rfc3339(),
+        close_time: s.cover_time.to_rfc3339(),
+        volume: s.volume,
+        left: s.left,
+        open_price: s.open_price,
+        close_price_avg: s.cover_price_avg,
+        profit: to_i64(s.profit, "short profit")?,
+        tax: s.tax,
+        commission: s.commission,
+        fee: s.fee,
+    })
+}
+
+// FNV-1a 64位哈希，不依赖编译器版本，用来判断数据文件是否变化
+fn hash_bytes(mut h: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+pub fn hash_file(path: &str) -> Result<(String, u64), Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; 1 << 20];
+    let (mut h, mut size) = (FNV_OFFSET, 0u64);
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        h = hash_bytes(h, &buf[..n]);
+        size += n as u64;
+    }
+    Ok((format!("{:016x}", h), size))
+}
+
+fn data_file(kind: &str, path: &str, rows: Option<usize>) -> Result<DataFile, Box<dyn Error>> {
+    let (hash, size) = hash_file(path).map_err(|e| format!("hash {} failed: {}", path, e))?;
+    Ok(DataFile {
+        kind: kind.to_string(),
+        path: path.to_string(),
+        hash,
+        size,
+        rows,
+    })
+}
+
+fn run_results(sys: &StockSys) -> Result<RunResults, Box<dyn Error>> {
+    let mut profit: i128 = 0;
+    let mut cost: u64 = 0;
+    for o in &sys.orders {
+        profit += o.profit;
+        cost += o.tax + o.commission;
+    }
+    let mut short_profit: i128 = 0;
+    for s in &sys.shorts {
+        short_profit += s.profit - (s.tax + s.commission + s.fee) as i128;
+    }
+    Ok(RunResults {
+        orders: sys.orders.len(),
+        closed_orders: sys.orders.iter().filter(|o| o.left == 0).count(),
+        profit: to_i64(profit, "profit")?,
+        profit_with_tax_commission: to_i64(profit - cost as i128, "profit with tax commission")?,
+        shorts: sys.shorts.len(),
+        short_profit_with_fee: to_i64(short_profit, "short profit with fee")?,
+    })
+}
+
+// 子目录以开始时间命名，同一秒内多次运行时加序号
+fn new_run_dir(root: &str, start: &DateTime<Local>) -> Result<String, Box<dyn Error>> {
+    let name = start.format("%Y%m%d-%H%M%S").to_string();
+    let mut dir = Path::new(root).join(&name);
+    let mut n = 1;
+    while dir.exists() {
+        dir = Path::new(root).join(format!("{}-{}", name, n));
+        n += 1;
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(dir.to_string_lossy().to_string())
+}
+
+// ticks为实际读取的tick条数，trans为逐笔成交条数
+pub fn write_run(
+    sys: &StockSys,
+    config: &str,
+    start: DateTime<Local>,
+    ticks: usize,
+    trans: usize,
+) -> Result<String, Box<dyn Error>> {
+    let conf = &sys.conf;
+    let text = fs::read_to_string(config)?;
+    // 重建委托簿时tick数据只用作模板，读取的tick是委托簿快照，没有tick数据时不记录
+    let replay = !conf.order_data.is_empty();
+    let mut data = Vec::new();
+    if !replay {
+        data.push(data_file("tick", &conf.tick_data, Some(ticks))?);
+    } else if Path::new(&conf.tick_data).is_file() {
+        data.push(data_file("tick", &conf.tick_data, None)?);
+    }
+    if !conf.trans_data.is_empty() {
+        data.push(data_file("trans", &conf.trans_data, Some(trans))?);
+    }
+    if replay {
+        data.push(data_file("order", &conf.order_data, None)?);
+    }
+    if !conf.benchmark.index_data.is_empty() {
+        data.push(data_file("index", &conf.benchmark.index_data, None)?);
+    }
+    if !conf.margin.borrow_file.is_empty() {
+        data.push(data_file("borrow", &conf.margin.borrow_file, None)?);
+    }
+    let manifest = Manifest {
+        engine_version: engine_version(),
+        seed: conf.seed,
+        start: start.to_rfc3339(),
+        end: Local::now().to_rfc3339(),
+        config_file: config.to_string(),
+        config_hash: format!("{:016x}", hash_bytes(FNV_OFFSET, text.as_bytes())),
+        results: run_results(sys)?,
+        data,
+    };
+
+    let dir = new_run_dir(&conf.run_dir, &start)?;
+    let dir_path = Path::new(&dir);
+    fs::write(dir_path.join(CONFIG_FILE), &text)?;
+    fs::write(dir_path.join(MANIFEST_FILE), toml::to_string(&manifest)?)?;
+    let mut writer = csv::Writer::from_path(dir_path.join(ORDERS_FILE))?;
+    for o in &sys.orders {
+        writer.serialize(trade_row(o)?)?;
+    }
+    for s in &sys.shorts {
+        writer.serialize(short_row(s)?)?;
+    }
+    writer.flush()?;
+    Ok(dir)
+}
+
+pub fn read_manifest(dir: &str) -> Result<Manifest, Box<dyn Error>> {
+    let text = fs::read_to_string(Path::new(dir).join(MANIFEST_FILE))?;
+    Ok(toml::from_str(&text)?)
+}
+
+// 检查清单中的数据文件是否还和当时一样，返回不一样的文件
+pub fn check_data(manifest: &Manifest) -> Vec<String> {
+    let mut res = Vec::new();
+    for d in &manifest.data {
+        match hash_file(&d.path) {
+            Ok((hash, _)) if hash == d.hash => {}
+            Ok((hash, _)) => res.push(format!("{} {} hash {} -> {}", d.kind, d.path, d.hash, hash)),
+            Err(e) => res.push(format!("{} {}: {}", d.kind, d.path, e)),
+        }
+    }
+    res
+}
+
+fn read_trades(dir: &str) -> Result<Vec<TradeRow>, Box<dyn Error>> {
+    let mut reader = csv::Reader::from_path(Path::new(dir).join(ORDERS_FILE))?;
+    let mut res = Vec::new();
+    for row in reader.deserialize() {
+        res.push(row?);
+    }
+    Ok(res)
+}
+
+fn trade_text(row: Option<&TradeRow>) -> String {
+    match row {
+        Some(r) => format!(
+            "{} {} open {}@{} close {}@{} volume:{} left:{} profit:{} fee:{}",
+            r.side,
+            r.code,
+            r.open_time,
+            r.open_price,
+            r.close_time,
+            r.close_price_avg,
+            r.volume,
+            r.left,
+            r.profit,
+            r.fee
+        ),
+        None => "none".to_string(),
+    }
+}
+
+// 只显示前几条不同的交易
+const MAX_TRADE_DIFFS: usize = 10;
+
+// 比较两次运行的配置、数据、结果和交易记录，返回不同之处，没有不同时为空
+pub fn diff_runs(a: &str, b: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let (ma, mb) = (read_manifest(a)?, read_manifest(b)?);
+    let mut res = Vec::new();
+    if ma.engine_version != mb.engine_version {
+        res.push(format!("engine version: {} -> {}", ma.engine_version, mb.engine_version));
+    }
+    if ma.seed != mb.seed {
+        res.push(format!("seed: {} -> {}", ma.seed, mb.seed));
+    }
+
+    // 配置按行比较，忽略空行和注释
+    if ma.config_hash != mb.config_hash {
+        let lines = |dir: &str| -> Result<Vec<String>, Box<dyn Error>> {
+            Ok(fs::read_to_string(Path::new(dir).join(CONFIG_FILE))?
+                .lines()
+                .map(|l| l.trim().to_string())
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .collect())
+        };
+        let (la, lb) = (lines(a)?, lines(b)?);
+        for l in la.iter().filter(|l| !lb.contains(l)) {
+            res.push(format!("config - {}", l));
+        }
+        for l in lb.iter().filter(|l| !la.contains(l)) {
+            res.push(format!("config + {}", l));
+        }
+    }
+
+    for da in &ma.data {
+        match mb.data.iter().find(|d| d.kind == da.kind) {
+            Some(db) if db == da => {}
+            Some(db) => res.push(format!(
+                "data {}: {} {} rows:{:?} -> {} {} rows:{:?}",
+                da.kind, da.path, da.hash, da.rows, db.path, db.hash, db.rows
+            )),
+            None => res.push(format!("data {}: {} -> none", da.kind, da.path)),
+        }
+    }
+    for db in mb.data.iter().filter(|d| !ma.data.iter().any(|da| da.kind == d.kind)) {
+        res.push(format!("data {}: none -> {}", db.kind, db.path));
+    }
+
+    let (ra, rb) = (toml::Value::try_from(&ma.results)?, toml::Value::try_from(&mb.results)?);
+    if let (Some(ra), Some(rb)) = (ra.as_table(), rb.as_table()) {
+        for (k, va) in ra {
+            match rb.get(k) {
+                Some(vb) if vb == va => {}
+                Some(vb) => res.push(format!("{}: {} -> {}", k, va, vb)),
+                None => res.push(format!("{}: {} -> none", k, va)),
+            }
+        }
+    }
+
+    let (ta, tb) = (read_trades(a)?, read_trades(b)?);
+    let mut diffs = 0;
+    for i in 0..ta.len().max(tb.len()) {
+        let (x, y) = (ta.get(i), tb.get(i));
+        if x == y {
+            continue;
+        }
+        diffs += 1;
+        if diffs <= MAX_TRADE_DIFFS {
+            res.push(format!("trade {}: {} -> {}", i, trade_text(x), trade_text(y)));
+        }
+    }
+    if diffs > MAX_TRADE_DIFFS {
+        res.push(format!("... {} trades differ", diffs));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::tests::{new_sys, tick};
+
+    // 测试用的临时目录，order_data为重建委托簿的逐笔委托文件，没有tick数据
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("manifest_test_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
+    fn replay_sys(dir: &str, name: &str, extra: &str) -> (StockSys, String) {
+        let order = format!("{}/order.csv", dir);
+        if !Path::new(&order).exists() {
+            fs::write(&order, "Tkr,Time,Order,Price,Volume,BSFlag,OrderKind\n").unwrap();
+        }
+        let text = format!("order_data = \"{}\"\nrun_dir = \"{}/runs\"\n{}", order, dir, extra);
+        let config = format!("{}/{}.toml", dir, name);
+        fs::write(&config, &text).unwrap();
+        (new_sys(name, &text), config)
+    }
+
+    #[test]
+    fn replay_without_tick_data() {
+        let dir = temp_dir("replay");
+        let (sys, config) = replay_sys(&dir, "manifest_replay", "");
+        let run = write_run(&sys, &config, Local::now(), 0, 0).unwrap();
+        let m = read_manifest(&run).unwrap();
+        let kinds: Vec<&str> = m.data.iter().map(|d| d.kind.as_str()).collect();
+        assert_eq!(kinds, vec!["order"]);
+        assert_eq!(fs::read_to_string(Path::new(&run).join(CONFIG_FILE)).unwrap(), fs::read_to_string(&config).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_data_detects_changes() {
+        let dir = temp_dir("check");
+        let (sys, config) = replay_sys(&dir, "manifest_check", "");
+        let run = write_run(&sys, &config, Local::now(), 0, 0).unwrap();
+        let m = read_manifest(&run).unwrap();
+        assert!(check_data(&m).is_empty());
+        let order = format!("{}/order.csv", dir);
+        fs::write(&order, "Tkr,Time,Order,Price,Volume,BSFlag,OrderKind\n601012.SH,93000000,1,400000,100,B,2\n").unwrap();
+        let (hash, _) = hash_file(&order).unwrap();
+        assert_eq!(check_data(&m), vec![format!("order {} hash {} -> {}", order, m.data[0].hash, hash)]);
+        fs::remove_file(&order).unwrap();
+        let changed = check_data(&m);
+        assert_eq!(changed.len(), 1);
+        assert!(changed[0].starts_with(&format!("order {}: ", order)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn diff_two_runs() {
+        let dir = temp_dir("diff");
+        let (a, config) = replay_sys(&dir, "manifest_diff_a", "");
+        let run_a = write_run(&a, &config, Local::now(), 0, 0).unwrap();
+        assert!(diff_runs(&run_a, &run_a).unwrap().is_empty());
+        // 第二次运行换了随机数种子，并且有一笔买入
+        let (mut b, config) = replay_sys(&dir, "manifest_diff_b", "seed = 7\n");
+        b.do_strategy(&tick("601012.SH", 93000000, 400000, 1000));
+        b.do_strategy(&tick("601012.SH", 93003000, 402100, 2000));
+        let run_b = write_run(&b, &config, Local::now(), 0, 0).unwrap();
+        let diffs = diff_runs(&run_a, &run_b).unwrap();
+        assert_eq!(
+            diffs,
+            vec![
+                "seed: 0 -> 7".to_string(),
+                "config + seed = 7".to_string(),
+                "orders: 0 -> 1".to_string(),
+                format!("trade 0: none -> {}", trade_text(Some(&trade_row(&b.orders[0]).unwrap()))),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
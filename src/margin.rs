@@ -0,0 +1,218 @@
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::BufReader;
+
+// 融券卖空
+// 只有券源文件中列出的股票可以融券，融券数量不能超过可借数量
+// 开仓时按成交金额 * margin_ratio 占用保证金，平仓后释放
+// 融券费用按自然日计算：成交金额 * 年费率 / 360 * 天数，当天开平仓也按1天计算
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MarginConfig {
+    pub enabled: bool,       // 是否做空
+    pub borrow_file: String, // 券源文件，CSV，列为 code,volume[,rate]
+    pub margin_ratio: f64,   // 融券保证金比例
+    pub borrow_rate: f64,    // 默认融券年费率，券源文件中没有rate时使用
+    pub max_margin: u64,     // 最多占用的保证金，0表示不限制
+    pub short_point: f64,    // 从时间窗最高价下跌超过这个比例时融券卖出
+    pub short_volume: usize, // 每次融券卖出的数量
+}
+
+impl Default for MarginConfig {
+    fn default() -> Self {
+        MarginConfig {
+            enabled: false,
+            borrow_file: String::new(),
+            margin_ratio: 0.5,
+            borrow_rate: 0.106,
+            max_margin: 0,
+            short_point: 0.005,
+            short_volume: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BorrowRecord {
+    code: String,
+    volume: u64,
+    rate: Option<f64>,
+}
+
+// 融券空头，字段含义和order对应，开仓为卖出，平仓为买入
+#[derive(Debug)]
+pub struct short {
+    pub code: String,
+    pub open_price: u64,
+    pub time: DateTime<FixedOffset>,
+    pub cover_time: DateTime<FixedOffset>,
+    pub volume: usize,
+    pub cover_price: u64,
+    pub want_cover_all: bool,
+    pub cover_price_avg: u64,
+    pub left: usize,
+    pub profit: i128,
+    pub tax: u64,
+    pub commission: u64,
+    pub fee: u64,    // 融券费用
+    pub margin: u64, // 占用的保证金
+    pub rate: f64,   // 融券年费率
+}
+
+impl Display for short {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "short price:{} cover price:{} short time:{} cover time:{} volume:{} left:{} profit:{} tax:{} commission:{} fee:{}", self.open_price, self.cover_price_avg, self.time, self.cover_time, self.volume, self.left, self.profit, self.tax, self.commission, self.fee)?;
+        Ok(())
+    }
+}
+
+impl short {
+    // 到某个时间为止的融券费用，按自然日计算，当天归还也算一天
+    pub fn fee_until(&self, dt: DateTime<FixedOffset>) -> u64 {
+        let days = (dt.date_naive() - self.time.date_naive()).num_days().max(1);
+        (self.open_price as f64 * self.volume as f64 * self.rate / 360.0 * days as f64) as u64
+    }
+}
+
+// 券源和保证金占用
+pub struct MarginAccount {
+    pub conf: MarginConfig,
+    available: HashMap<String, u64>, // 剩余可借数量
+    rates: HashMap<String, f64>,
+    pub margin: u64, // 当前占用的保证金
+}
+
+pub fn new_margin_account(conf: MarginConfig) -> Result<MarginAccount, Box<dyn Error>> {
+    let mut available = HashMap::new();
+    let mut rates = HashMap::new();
+    if conf.enabled {
+        let f = File::open(&conf.borrow_file)?;
+        let mut rdr = csv::Reader::from_reader(BufReader::new(f));
+        for result in rdr.deserialize() {
+            let record: BorrowRecord = result?;
+            if let Some(rate) = record.rate {
+                rates.insert(record.code.clone(), rate);
+            }
+            *available.entry(record.code).or_insert(0) += record.volume;
+        }
+    }
+    Ok(MarginAccount {
+        conf,
+        available,
+        rates,
+        margin: 0,
+    })
+}
+
+impl MarginAccount {
+    // 是否融券标的
+    pub fn is_marginable(&self, code: &str) -> bool {
+        self.available.contains_key(code)
+    }
+
+    pub fn available(&self, code: &str) -> u64 {
+        *self.available.get(code).unwrap_or(&0)
+    }
+
+    pub fn rate(&self, code: &str) -> f64 {
+        *self.rates.get(code).unwrap_or(&self.conf.borrow_rate)
+    }
+
+    // 开仓需要的保证金
+    pub fn required(&self, value: u64) -> u64 {
+        (value as f64 * self.conf.margin_ratio) as u64
+    }
+
+    // 保证金是否足够
+    pub fn can_lock(&self, margin: u64) -> bool {
+        self.conf.max_margin == 0 || self.margin + margin <= self.conf.max_margin
+    }
+
+    // 借券并占用保证金
+    pub fn borrow(&mut self, code: &str, volume: u64, margin: u64) {
+        if let Some(v) = self.available.get_mut(code) {
+            *v -= volume.min(*v);
+        }
+        self.margin += margin;
+    }
+
+    // 还券并释放保证金
+    pub fn give_back(&mut self, code: &str, volume: u64, margin: u64) {
+        if let Some(v) = self.available.get_mut(code) {
+            *v += volume;
+        }
+        self.margin -= margin.min(self.margin);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick::{get_time, trade_date};
+
+    fn account(borrow: &str, max_margin: u64) -> MarginAccount {
+        let path = std::env::temp_dir().join(format!("margin_test_{}_{}.csv", max_margin, std::process::id()));
+        std::fs::write(&path, borrow).unwrap();
+        let conf = MarginConfig {
+            enabled: true,
+            borrow_file: path.to_str().unwrap().to_string(),
+            max_margin,
+            ..MarginConfig::default()
+        };
+        let account = new_margin_account(conf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        account
+    }
+
+    #[test]
+    fn borrow_and_give_back() {
+        let mut a = account("code,volume,rate\n601012.SH,1000,0.18\n601012.SH,500,\n600000.SH,300,\n", 0);
+        // 同一个代码的券源合计，没有rate时使用默认费率
+        assert_eq!(a.available("601012.SH"), 1500);
+        assert_eq!((a.rate("601012.SH"), a.rate("600000.SH")), (0.18, 0.106));
+        assert!(a.is_marginable("600000.SH") && !a.is_marginable("000001.SZ"));
+        assert_eq!(a.required(1000000), 500000);
+        a.borrow("601012.SH", 1000, 500000);
+        assert_eq!((a.available("601012.SH"), a.margin), (500, 500000));
+        a.give_back("601012.SH", 1000, 500000);
+        assert_eq!((a.available("601012.SH"), a.margin), (1500, 0));
+    }
+
+    #[test]
+    fn max_margin() {
+        let mut a = account("code,volume,rate\n601012.SH,1000,\n", 1000000);
+        assert!(a.can_lock(1000000));
+        a.borrow("601012.SH", 500, 600000);
+        assert!(a.can_lock(400000));
+        assert!(!a.can_lock(400001));
+    }
+
+    #[test]
+    fn fee_by_calendar_days() {
+        let open = get_time(trade_date(20211029).unwrap(), 93000000).unwrap();
+        let s = short {
+            code: "601012.SH".to_string(),
+            open_price: 360000,
+            time: open,
+            cover_time: open,
+            volume: 1000,
+            cover_price: 0,
+            want_cover_all: false,
+            cover_price_avg: 0,
+            left: 1000,
+            profit: 0,
+            tax: 0,
+            commission: 0,
+            fee: 0,
+            margin: 0,
+            rate: 0.1,
+        };
+        // 当天平仓按1天，周五到周一按3天
+        assert_eq!(s.fee_until(get_time(trade_date(20211029).unwrap(), 145000000).unwrap()), 100000);
+        assert_eq!(s.fee_until(get_time(trade_date(20211101).unwrap(), 93000000).unwrap()), 300000);
+    }
+}
@@ -0,0 +1,557 @@
+use arrow_array::{
+    Array, ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray,
+    TimestampMillisecondArray, UInt64Array,
+};
+use arrow_cast::cast;
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::ArrowWriter;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+
+use super::feed::FeedError;
+use super::schema::{self, SchemaConfig};
+use super::manifest::to_i64;
+use super::margin::short;
+use super::strategy::order;
+use super::tick::{default_dt, get_time, Tick};
+use super::transaction::transaction;
+
+// parquet格式的输入输出，需要开启parquet特性
+// tick的列名和单位按[schema]映射，与CSV一致，逐笔成交的列名与CSV一致
+// 逐笔成交的BSFlag和FunctionCode可以是字符串，也可以是字符编码的整数
+// 数值列可以是任意整数或浮点类型，会转换为u64，空值、负数和小数返回错误
+
+const PARQUET_MAGIC: &[u8; 4] = b"PAR1";
+
+// 判断文件是否为parquet格式
+pub fn is_parquet(path: &str) -> bool {
+    let mut buf = [0u8; 4];
+    match File::open(path) {
+        Ok(mut f) => f.read_exact(&mut buf).is_ok() && &buf == PARQUET_MAGIC,
+        Err(_) => false,
+    }
+}
+
+fn column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a ArrayRef, Box<dyn Error>> {
+    Ok(batch
+        .column_by_name(name)
+        .ok_or_else(|| format!("column {} not found in parquet", name))?)
+}
+
+// 第一个空值的行号
+fn first_null(col: &dyn Array) -> Option<usize> {
+    if col.null_count() == 0 {
+        return None;
+    }
+    (0..col.len()).find(|i| col.is_null(*i))
+}
+
+// 读取数值列并转换为u64，first_row为batch的第一行在文件中的行号，用于错误信息
+// 整数列原样读取，scale不为1时乘以scale后四舍五入
+// 空值和schema::scale_value不能转换的值都返回错误，不会被当作0或截断
+fn u64_column(
+    batch: &RecordBatch,
+    name: &str,
+    scale: f64,
+    first_row: usize,
+) -> Result<Vec<u64>, Box<dyn Error>> {
+    let col = column(batch, name)?;
+    let invalid = |row: usize, reason: String| -> Box<dyn Error> {
+        format!("column {} row {}: {}", name, first_row + row, reason).into()
+    };
+    let not_integer = |row: usize, v: &dyn std::fmt::Display| {
+        invalid(row, format!("{} is not a non-negative integer", v))
+    };
+    if let Some(row) = first_null(col.as_ref()) {
+        return Err(invalid(row, "null value".to_string()));
+    }
+    let not_number = || format!("column {} is not a number", name);
+    match col.data_type() {
+        DataType::UInt8 | DataType::UInt16 | DataType::UInt32 | DataType::UInt64
+            if scale == 1.0 =>
+        {
+            let col = cast(col, &DataType::UInt64)?;
+            let values = col
+                .as_any()
+                .downcast_ref::<UInt64Array>()
+                .ok_or_else(not_number)?;
+            Ok(values.values().to_vec())
+        }
+        DataType::Int8 | DataType::Int16 | DataType::Int32 | DataType::Int64 if scale == 1.0 => {
+            let col = cast(col, &DataType::Int64)?;
+            let values = col
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .ok_or_else(not_number)?;
+            values
+                .values()
+                .iter()
+                .enumerate()
+                .map(|(row, v)| u64::try_from(*v).map_err(|_| not_integer(row, v)))
+                .collect()
+        }
+        _ => {
+            // 字符串等无法转换为数字的值在转换后为空值
+            let col = cast(col, &DataType::Float64)?;
+            if let Some(row) = first_null(col.as_ref()) {
+                return Err(invalid(row, "not a number".to_string()));
+            }
+            let values = col
+                .as_any()
+                .downcast_ref::<Float64Array>()
+                .ok_or_else(not_number)?;
+            values
+                .values()
+                .iter()
+                .enumerate()
+                .map(|(row, v)| schema::scale_value(*v, scale).ok_or_else(|| not_integer(row, v)))
+                .collect()
+        }
+    }
+}
+
+fn string_column(
+    batch: &RecordBatch,
+    name: &str,
+    first_row: usize,
+) -> Result<StringArray, Box<dyn Error>> {
+    let col = cast(column(batch, name)?, &DataType::Utf8)?;
+    if let Some(row) = first_null(col.as_ref()) {
+        return Err(format!("column {} row {} is null", name, first_row + row).into());
+    }
+    Ok(col
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| format!("column {} is not a string", name))?
+        .clone())
+}
+
+// 读取字符列，如BSFlag和FunctionCode，字符串取第一个字符，整数为字符的编码
+fn char_column(
+    batch: &RecordBatch,
+    name: &str,
+    first_row: usize,
+) -> Result<Vec<char>, Box<dyn Error>> {
+    let col = column(batch, name)?;
+    if !col.data_type().is_integer() {
+        let col = string_column(batch, name, first_row)?;
+        return Ok(col
+            .iter()
+            .map(|v| v.and_then(|v| v.chars().next()).unwrap_or(' '))
+            .collect());
+    }
+    let invalid = |row: usize, v: u64| -> Box<dyn Error> {
+        format!("column {} row {}: {} is not a char", name, first_row + row, v).into()
+    };
+    u64_column(batch, name, 1.0, first_row)?
+        .iter()
+        .enumerate()
+        .map(|(row, v)| {
+            u32::try_from(*v)
+                .ok()
+                .and_then(std::char::from_u32)
+                .ok_or_else(|| invalid(row, *v))
+        })
+        .collect()
+}
+
+// 按[schema]的列名映射和单位换算读取tick，和CSV一样
+struct TickColumns {
+    code: Option<String>,       // 代码列名
+    symbol: String,             // 没有代码列时使用的代码
+    time_format: String,        // 为空时时间列为91003000这样的整数
+    names: Vec<Option<String>>, // tick_fields中每个字段对应的列名，没有的列取0
+    scale: Vec<f64>,
+}
+
+fn new_tick_columns(conf: &SchemaConfig, headers: &[&str]) -> Result<TickColumns, Box<dyn Error>> {
+    let (columns, scale, time_format) = schema::mapping(conf)?;
+    let mut names = schema::resolve_columns(conf, &columns, headers)?;
+    let code = names.remove(0);
+    let fields = tick_fields!(field_names);
+    Ok(TickColumns {
+        code,
+        symbol: conf.symbol.clone(),
+        time_format,
+        names,
+        scale: fields.iter().map(|f| *scale.get(*f).unwrap_or(&1.0)).collect(),
+    })
+}
+
+fn ticks_of_batch(
+    batch: &RecordBatch,
+    columns: &TickColumns,
+    first_row: usize,
+    date: NaiveDate,
+) -> Result<Vec<Tick>, Box<dyn Error>> {
+    let codes = match &columns.code {
+        Some(code) => Some(string_column(batch, code, first_row)?),
+        None => None,
+    };
+    // 第一个字段是nTime，一定存在，按时间格式解析时同时得到日期
+    let time = columns.names[0].as_deref().unwrap_or("nTime");
+    let mut dates = vec![date; batch.num_rows()];
+    let mut cols = Vec::with_capacity(columns.names.len());
+    if columns.time_format.is_empty() {
+        cols.push(u64_column(batch, time, 1.0, first_row)?);
+    } else {
+        let times = string_column(batch, time, first_row)?;
+        let mut ntimes = Vec::with_capacity(batch.num_rows());
+        for (row, date) in dates.iter_mut().enumerate() {
+            let (d, ntime) = schema::parse_time(times.value(row), &columns.time_format)
+                .map_err(|e| e.to_string())?;
+            *date = d.unwrap_or(*date);
+            ntimes.push(ntime);
+        }
+        cols.push(ntimes);
+    }
+    for (name, scale) in columns.names.iter().zip(&columns.scale).skip(1) {
+        match name {
+            Some(name) => cols.push(u64_column(batch, name, *scale, first_row)?),
+            None => cols.push(vec![0; batch.num_rows()]),
+        }
+    }
+    let mut res = Vec::with_capacity(batch.num_rows());
+    for row in 0..batch.num_rows() {
+        let mut i = 0;
+        let mut next = || {
+            i += 1;
+            cols[i - 1][row]
+        };
+        macro_rules! decode {
+            ($($f:ident),*) => {
+                Tick {
+                    chWindCode: match &codes {
+                        Some(codes) => codes.value(row).to_string(),
+                        None => columns.symbol.clone(),
+                    },
+                    $($f: next(),)*
+                    dt: default_dt(),
+                }
+            };
+        }
+        let mut t = tick_fields!(decode);
+        t.dt = get_time(dates[row], t.nTime)?;
+        res.push(t);
+    }
+    Ok(res)
+}
+
+// 按batch读取parquet中的tick，内存中只保留一个batch
+pub struct ParquetTickIter {
+    reader: ParquetRecordBatchReader,
+    buffer: VecDeque<Tick>,
+    columns: TickColumns,
+    rows: usize, // 已经读取的行数
+    date: NaiveDate,
+}
+
+pub fn read_tick_parquet(
+    path: &str,
+    batch_size: usize,
+    schema: &SchemaConfig,
+    date: NaiveDate,
+) -> Result<ParquetTickIter, Box<dyn Error>> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+    let fields = builder.schema().fields().clone();
+    let headers: Vec<&str> = fields.iter().map(|f| f.name().as_str()).collect();
+    let columns = new_tick_columns(schema, &headers)?;
+    let reader = builder.with_batch_size(batch_size.max(1)).build()?;
+    Ok(ParquetTickIter {
+        reader,
+        buffer: VecDeque::new(),
+        columns,
+        rows: 0,
+        date,
+    })
+}
+
+impl Iterator for ParquetTickIter {
+    type Item = Result<Tick, FeedError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.is_empty() {
+            let batch = match self.reader.next()? {
+                Ok(batch) => batch,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match ticks_of_batch(&batch, &self.columns, self.rows, self.date) {
+                Ok(ticks) => self.buffer.extend(ticks),
+                Err(e) => return Some(Err(e.to_string().into())),
+            }
+            self.rows += batch.num_rows();
+        }
+        self.buffer.pop_front().map(Ok)
+    }
+}
+
+pub fn read_trans_parquet(path: &str, date: NaiveDate) -> Result<Vec<transaction>, Box<dyn Error>> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()?;
+    let mut res = Vec::new();
+    for batch in reader {
+        let batch = batch?;
+        let first_row = res.len();
+        let tkr = string_column(&batch, "Tkr", first_row)?;
+        let flags = char_column(&batch, "BSFlag", first_row)?;
+        let functions = char_column(&batch, "FunctionCode", first_row)?;
+        let names = [
+            "Time",
+            "Index",
+            "Price",
+            "Volume",
+            "Turnover",
+            "OrderKind",
+            "AskOrder",
+            "BidOrder",
+        ];
+        let cols = names
+            .iter()
+            .map(|name| u64_column(&batch, name, 1.0, first_row))
+            .collect::<Result<Vec<_>, _>>()?;
+        let value = |col: usize, row: usize| cols[col][row];
+        for row in 0..batch.num_rows() {
+            let time = value(0, row);
+            res.push(transaction {
+                Tkr: tkr.value(row).to_string(),
+                Time: time,
+                dt: get_time(date, time)?,
+                Index: value(1, row),
+                Price: value(2, row),
+                Volume: value(3, row),
+                Turnover: value(4, row),
+                BSFlag: flags[row],
+                OrderKind: value(5, row),
+                FunctionCode: functions[row],
+                AskOrder: value(6, row),
+                BidOrder: value(7, row),
+            });
+        }
+    }
+    Ok(res)
+}
+
+fn timestamp_field(name: &str) -> Field {
+    Field::new(
+        name,
+        DataType::Timestamp(TimeUnit::Millisecond, Some("+08:00".into())),
+        false,
+    )
+}
+
+fn timestamps(times: Vec<DateTime<FixedOffset>>) -> ArrayRef {
+    Arc::new(
+        TimestampMillisecondArray::from(
+            times
+                .iter()
+                .map(|t| t.timestamp_millis())
+                .collect::<Vec<_>>(),
+        )
+        .with_timezone("+08:00"),
+    )
+}
+
+fn write_batch(path: &str, batch: RecordBatch) -> Result<(), Box<dyn Error>> {
+    let mut writer = ArrowWriter::try_new(File::create(path)?, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+// 输出交易记录，列和运行清单中的orders.csv一致
+// 每个订单和融券一行，side为long时先买后卖，为short时先融券卖出后买入平仓
+pub fn write_trades(path: &str, orders: &[order], shorts: &[short]) -> Result<(), Box<dyn Error>> {
+    let schema = Schema::new(vec![
+        Field::new("side", DataType::Utf8, false),
+        Field::new("code", DataType::Utf8, false),
+        timestamp_field("open_time"),
+        timestamp_field("close_time"),
+        Field::new("volume", DataType::UInt64, false),
+        Field::new("left", DataType::UInt64, false),
+        Field::new("open_price", DataType::UInt64, false),
+        Field::new("close_price_avg", DataType::UInt64, false),
+        Field::new("profit", DataType::Int64, false),
+        Field::new("tax", DataType::UInt64, false),
+        Field::new("commission", DataType::UInt64, false),
+        Field::new("fee", DataType::UInt64, false),
+    ]);
+    let u64s = |o: &dyn Fn(&order) -> u64, s: &dyn Fn(&short) -> u64| -> ArrayRef {
+        Arc::new(UInt64Array::from(
+            orders.iter().map(o).chain(shorts.iter().map(s)).collect::<Vec<_>>(),
+        ))
+    };
+    let mut profits = Vec::with_capacity(orders.len() + shorts.len());
+    for o in orders {
+        profits.push(to_i64(o.profit, "order profit")?);
+    }
+    for s in shorts {
+        profits.push(to_i64(s.profit, "short profit")?);
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(
+            orders
+                .iter()
+                .map(|_| "long")
+                .chain(shorts.iter().map(|_| "short"))
+                .collect::<Vec<_>>(),
+        )),
+        Arc::new(StringArray::from(
+            orders
+                .iter()
+                .map(|o| o.code.clone())
+                .chain(shorts.iter().map(|s| s.code.clone()))
+                .collect::<Vec<_>>(),
+        )),
+        timestamps(orders.iter().map(|o| o.time).chain(shorts.iter().map(|s| s.time)).collect()),
+        timestamps(
+            orders
+                .iter()
+                .map(|o| o.selt_time)
+                .chain(shorts.iter().map(|s| s.cover_time))
+                .collect(),
+        ),
+        u64s(&|o| o.volume as u64, &|s| s.volume as u64),
+        u64s(&|o| o.left as u64, &|s| s.left as u64),
+        u64s(&|o| o.open_price, &|s| s.open_price),
+        u64s(&|o| o.sell_price_avg, &|s| s.cover_price_avg),
+        Arc::new(Int64Array::from(profits)),
+        u64s(&|o| o.tax, &|s| s.tax),
+        u64s(&|o| o.commission, &|s| s.commission),
+        u64s(&|_| 0, &|s| s.fee),
+    ];
+    write_batch(path, RecordBatch::try_new(Arc::new(schema), columns)?)
+}
+
+// 输出资金曲线
+pub fn write_equity(path: &str, equity: &[(DateTime<FixedOffset>, i128)]) -> Result<(), Box<dyn Error>> {
+    let schema = Schema::new(vec![
+        timestamp_field("time"),
+        Field::new("pnl", DataType::Int64, false),
+    ]);
+    let columns: Vec<ArrayRef> = vec![
+        timestamps(equity.iter().map(|(t, _)| *t).collect()),
+        Arc::new(Int64Array::from(
+            equity
+                .iter()
+                .map(|(_, p)| to_i64(*p, "pnl"))
+                .collect::<Result<Vec<_>, _>>()?,
+        )),
+    ];
+    write_batch(path, RecordBatch::try_new(Arc::new(schema), columns)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Array, TimestampMillisecondArray};
+    use crate::tick::trade_date;
+
+    fn at(ntime: u64) -> DateTime<FixedOffset> {
+        get_time(trade_date(0).unwrap(), ntime).unwrap()
+    }
+
+    fn sold(profit: i128) -> order {
+        order {
+            code: "601012.SH".to_string(),
+            open_price: 400000,
+            time: at(93000000),
+            selt_time: at(93100000),
+            volume: 1000,
+            sell_price: 401000,
+            sell_arrive: at(93100000),
+            sell_volume: 0,
+            amending: false,
+            cancelled: false,
+            want_sell_all: false,
+            sell_price_avg: 401000,
+            left: 0,
+            profit,
+            tax: 40100,
+            commission: 240600,
+            signal_mid: 399950,
+            exit_mid: 401050,
+        }
+    }
+
+    fn covered() -> short {
+        short {
+            code: "600000.SH".to_string(),
+            open_price: 80000,
+            time: at(93500000),
+            cover_time: at(93600000),
+            volume: 500,
+            cover_price: 79900,
+            want_cover_all: false,
+            cover_price_avg: 79900,
+            left: 0,
+            profit: 50000,
+            tax: 4000,
+            commission: 100000,
+            fee: 1177,
+            margin: 20000000,
+            rate: 0.106,
+        }
+    }
+
+    fn read_back(path: &str) -> RecordBatch {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut batches: Vec<RecordBatch> = reader.map(|b| b.unwrap()).collect();
+        assert_eq!(batches.len(), 1);
+        batches.remove(0)
+    }
+
+    fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> &'a T {
+        batch.column_by_name(name).unwrap().as_any().downcast_ref::<T>().unwrap()
+    }
+
+    #[test]
+    fn trades_round_trip() {
+        let path = std::env::temp_dir().join(format!("parquet_trades_{}.parquet", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_trades(path, &[sold(1000000)], &[covered()]).unwrap();
+        let batch = read_back(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        let side = column::<StringArray>(&batch, "side");
+        let code = column::<StringArray>(&batch, "code");
+        assert_eq!((side.value(0), code.value(0)), ("long", "601012.SH"));
+        assert_eq!((side.value(1), code.value(1)), ("short", "600000.SH"));
+        let close = column::<TimestampMillisecondArray>(&batch, "close_time");
+        assert_eq!(close.value(0), at(93100000).timestamp_millis());
+        assert_eq!(close.value(1), at(93600000).timestamp_millis());
+        let price = column::<UInt64Array>(&batch, "close_price_avg");
+        assert_eq!((price.value(0), price.value(1)), (401000, 79900));
+        let profit = column::<Int64Array>(&batch, "profit");
+        assert_eq!((profit.value(0), profit.value(1)), (1000000, 50000));
+        let fee = column::<UInt64Array>(&batch, "fee");
+        assert_eq!((fee.value(0), fee.value(1)), (0, 1177));
+    }
+
+    #[test]
+    fn equity_round_trip() {
+        let path = std::env::temp_dir().join(format!("parquet_equity_{}.parquet", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_equity(path, &[(at(93000000), 0), (at(93100000), -123456)]).unwrap();
+        let batch = read_back(path);
+        std::fs::remove_file(path).unwrap();
+        let pnl = column::<Int64Array>(&batch, "pnl");
+        assert_eq!((pnl.len(), pnl.value(1)), (2, -123456));
+    }
+
+    #[test]
+    fn profit_out_of_range() {
+        let path = std::env::temp_dir().join(format!("parquet_overflow_{}.parquet", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(write_trades(path, &[sold(i64::MAX as i128 + 1)], &[]).is_err());
+        assert!(write_equity(path, &[(at(93000000), i64::MIN as i128 - 1)]).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,152 @@
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt::Display;
+
+// 加仓和分批止盈
+// 开启后同一个股票的多次买入合并为一个持仓：
+// 已有持仓时，价格比上一次买入再上涨add_step才加仓，最多max_tranches次
+// 当前价达到 持仓均价 * (1 + exit_targets[i]) 时，卖出最大持仓的exit_fractions[i]
+// 没有止盈卖完的部分仍然按sell_delay_time和sell_all_delay卖出
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PyramidConfig {
+    pub enabled: bool,
+    pub max_tranches: usize,
+    pub add_step: f64,
+    pub exit_targets: Vec<f64>,
+    pub exit_fractions: Vec<f64>,
+}
+
+impl Default for PyramidConfig {
+    fn default() -> Self {
+        PyramidConfig {
+            enabled: false,
+            max_tranches: 3,
+            add_step: 0.003,
+            exit_targets: Vec::new(),
+            exit_fractions: Vec::new(),
+        }
+    }
+}
+
+impl PyramidConfig {
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.exit_targets.len() != self.exit_fractions.len() {
+            return Err("exit_targets and exit_fractions of [pyramid] must have the same length".into());
+        }
+        if self.exit_fractions.iter().any(|f| *f <= 0.0 || *f > 1.0) {
+            return Err("exit_fractions of [pyramid] must be in (0, 1]".into());
+        }
+        Ok(())
+    }
+}
+
+// 一个股票的持仓，成本按买入均价计算，卖出不改变均价
+// 持仓由多次买入的订单组成，止盈和下跌离场按持仓判断，再按买入顺序分配到各个订单上挂卖单
+// 卖出只能通过订单成交，成交时同时更新订单和持仓
+#[derive(Debug, Default)]
+pub struct Position {
+    pub orders: Vec<usize>, // 这一轮持仓的买入订单，按买入顺序
+    pub volume: u64,
+    pub cost: u64,     // 持仓成本 = 持仓均价 * 持仓数量
+    pub peak: u64,     // 这一轮持仓的最大数量，分批止盈按它计算
+    pub tranches: usize,
+    pub last_price: u64, // 上一次买入的价格
+    pub last_time: Option<DateTime<FixedOffset>>,
+    pub targets_hit: usize, // 已经触发的止盈目标数
+    pub realized: i128, // 已实现盈亏，不含税费
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "volume:{} avg cost:{} tranches:{} targets hit:{} realized:{}",
+            self.volume,
+            self.avg_cost(),
+            self.tranches,
+            self.targets_hit,
+            self.realized
+        )
+    }
+}
+
+impl Position {
+    pub fn avg_cost(&self) -> u64 {
+        if self.volume == 0 {
+            return 0;
+        }
+        self.cost / self.volume
+    }
+
+    // idx为买入订单的序号
+    pub fn add(&mut self, idx: usize, volume: u64, price: u64, dt: DateTime<FixedOffset>) {
+        // 上一轮已经卖完，重新开始计算
+        if self.volume == 0 {
+            self.orders.clear();
+            self.peak = 0;
+            self.tranches = 0;
+            self.targets_hit = 0;
+        }
+        self.orders.push(idx);
+        self.volume += volume;
+        self.cost += volume * price;
+        self.peak = self.peak.max(self.volume);
+        self.tranches += 1;
+        self.last_price = price;
+        self.last_time = Some(dt);
+    }
+
+    pub fn reduce(&mut self, volume: u64, price: u64) {
+        let volume = volume.min(self.volume);
+        let avg = self.avg_cost();
+        self.realized += volume as i128 * (price as i128 - avg as i128);
+        self.cost -= avg * volume;
+        self.volume -= volume;
+        if self.volume == 0 {
+            self.cost = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick::{get_time, trade_date};
+
+    #[test]
+    fn average_cost() {
+        let dt = get_time(trade_date(0).unwrap(), 93000000).unwrap();
+        let mut pos = Position::default();
+        pos.add(0, 1000, 402200, dt);
+        pos.add(3, 500, 405200, dt);
+        assert_eq!((pos.volume, pos.avg_cost(), pos.peak, pos.tranches), (1500, 403200, 1500, 2));
+        assert_eq!((pos.last_price, pos.orders.clone()), (405200, vec![0, 3]));
+        // 卖出不改变均价
+        pos.reduce(600, 404200);
+        assert_eq!((pos.volume, pos.avg_cost(), pos.peak, pos.realized), (900, 403200, 1500, 600000));
+        pos.add(4, 100, 393200, dt);
+        assert_eq!((pos.volume, pos.avg_cost(), pos.tranches), (1000, 402200, 3));
+        // 超过持仓的部分不计算
+        pos.reduce(2000, 400200);
+        assert_eq!((pos.volume, pos.cost, pos.realized), (0, 0, 600000 - 2000000));
+        // 卖完后重新开始一轮
+        pos.targets_hit = 1;
+        pos.add(5, 300, 400000, dt);
+        assert_eq!((pos.orders.clone(), pos.peak, pos.tranches, pos.targets_hit), (vec![5], 300, 1, 0));
+        assert_eq!(pos.avg_cost(), 400000);
+    }
+
+    #[test]
+    fn check_config() {
+        let mut conf = PyramidConfig::default();
+        assert!(conf.check().is_ok());
+        conf.exit_targets = vec![0.01, 0.02];
+        assert!(conf.check().is_err());
+        conf.exit_fractions = vec![0.5, 1.0];
+        assert!(conf.check().is_ok());
+        conf.exit_fractions = vec![0.5, 0.0];
+        assert!(conf.check().is_err());
+    }
+}
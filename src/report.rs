@@ -0,0 +1,302 @@
+use chrono::{DateTime, FixedOffset};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+
+use super::strategy::StockSys;
+
+// 生成单个HTML文件的回测报告，图表为内嵌的SVG，不依赖任何网络资源
+// 包括资金曲线、回撤、价格和买卖点、每笔盈亏分布、持仓时间分布和使用的配置
+
+const WIDTH: f64 = 900.0;
+const HEIGHT: f64 = 260.0;
+const PAD: f64 = 50.0;
+const BINS: usize = 20;
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// 把数据范围映射到图表坐标，范围为0时向两边扩展
+struct Scale {
+    min: f64,
+    max: f64,
+    from: f64,
+    to: f64,
+}
+
+fn new_scale(values: impl Iterator<Item = f64>, from: f64, to: f64) -> Scale {
+    let (mut min, mut max) = (f64::MAX, f64::MIN);
+    for v in values {
+        min = min.min(v);
+        max = max.max(v);
+    }
+    if min > max {
+        min = 0.0;
+        max = 1.0;
+    }
+    if min == max {
+        min -= 1.0;
+        max += 1.0;
+    }
+    Scale { min, max, from, to }
+}
+
+impl Scale {
+    fn map(&self, v: f64) -> f64 {
+        self.from + (v - self.min) / (self.max - self.min) * (self.to - self.from)
+    }
+}
+
+fn svg_begin(out: &mut String, title: &str) {
+    let _ = writeln!(
+        out,
+        "<h2>{}</h2>\n<svg width=\"{}\" height=\"{}\" xmlns=\"http://www.w3.org/2000/svg\">",
+        escape(title),
+        WIDTH,
+        HEIGHT
+    );
+    let _ = writeln!(
+        out,
+        "<rect x=\"{p}\" y=\"{p}\" width=\"{w}\" height=\"{h}\" fill=\"none\" stroke=\"#ccc\"/>",
+        p = PAD / 2.0,
+        w = WIDTH - PAD,
+        h = HEIGHT - PAD
+    );
+}
+
+fn label(out: &mut String, x: f64, y: f64, anchor: &str, text: &str) {
+    let _ = writeln!(
+        out,
+        "<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"11\" text-anchor=\"{}\">{}</text>",
+        x,
+        y,
+        anchor,
+        escape(text)
+    );
+}
+
+// 折线图，x为时间，markers为(时间, 值, 颜色)
+fn line_chart(
+    title: &str,
+    points: &[(DateTime<FixedOffset>, f64)],
+    markers: &[(DateTime<FixedOffset>, f64, &str)],
+) -> String {
+    let mut out = String::new();
+    svg_begin(&mut out, title);
+    if points.is_empty() {
+        label(&mut out, WIDTH / 2.0, HEIGHT / 2.0, "middle", "no data");
+        out.push_str("</svg>\n");
+        return out;
+    }
+    let t0 = points[0].0;
+    let secs = |t: &DateTime<FixedOffset>| (*t - t0).num_milliseconds() as f64 / 1000.0;
+    let xs = new_scale(points.iter().map(|(t, _)| secs(t)), PAD / 2.0, WIDTH - PAD / 2.0);
+    let ys = new_scale(
+        points.iter().map(|(_, v)| *v).chain(markers.iter().map(|(_, v, _)| *v)),
+        HEIGHT - PAD / 2.0,
+        PAD / 2.0,
+    );
+    out.push_str("<polyline fill=\"none\" stroke=\"#1f77b4\" stroke-width=\"1.2\" points=\"");
+    for (t, v) in points {
+        let _ = write!(out, "{:.1},{:.1} ", xs.map(secs(t)), ys.map(*v));
+    }
+    out.push_str("\"/>\n");
+    for (t, v, color) in markers {
+        let _ = writeln!(
+            out,
+            "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\" fill=\"{}\"/>",
+            xs.map(secs(t)),
+            ys.map(*v),
+            color
+        );
+    }
+    let last = points[points.len() - 1].0;
+    // 跨越多个交易日时时间轴要带上日期
+    let format = if t0.date_naive() == last.date_naive() {
+        "%H:%M:%S"
+    } else {
+        "%Y-%m-%d %H:%M:%S"
+    };
+    label(&mut out, PAD / 2.0, HEIGHT - 8.0, "start", &t0.format(format).to_string());
+    label(&mut out, WIDTH - PAD / 2.0, HEIGHT - 8.0, "end", &last.format(format).to_string());
+    label(&mut out, PAD / 2.0 + 4.0, PAD / 2.0 + 12.0, "start", &format!("{:.0}", ys.max));
+    label(&mut out, PAD / 2.0 + 4.0, HEIGHT - PAD / 2.0 - 4.0, "start", &format!("{:.0}", ys.min));
+    out.push_str("</svg>\n");
+    out
+}
+
+// 直方图
+fn histogram(title: &str, values: &[f64], unit: &str) -> String {
+    let mut out = String::new();
+    svg_begin(&mut out, title);
+    if values.is_empty() {
+        label(&mut out, WIDTH / 2.0, HEIGHT / 2.0, "middle", "no data");
+        out.push_str("</svg>\n");
+        return out;
+    }
+    let range = new_scale(values.iter().copied(), 0.0, BINS as f64);
+    let mut counts = [0usize; BINS];
+    for v in values {
+        let idx = (range.map(*v) as usize).min(BINS - 1);
+        counts[idx] += 1;
+    }
+    let max = *counts.iter().max().unwrap_or(&1) as f64;
+    let bar = (WIDTH - PAD) / BINS as f64;
+    for (i, c) in counts.iter().enumerate() {
+        let h = *c as f64 / max * (HEIGHT - PAD - 15.0);
+        let _ = writeln!(
+            out,
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"#1f77b4\"><title>{}</title></rect>",
+            PAD / 2.0 + i as f64 * bar + 1.0,
+            HEIGHT - PAD / 2.0 - h,
+            bar - 2.0,
+            h,
+            c
+        );
+    }
+    label(&mut out, PAD / 2.0, HEIGHT - 8.0, "start", &format!("{:.0}{}", range.min, unit));
+    label(&mut out, WIDTH - PAD / 2.0, HEIGHT - 8.0, "end", &format!("{:.0}{}", range.max, unit));
+    label(&mut out, PAD / 2.0 + 4.0, PAD / 2.0 + 12.0, "start", &format!("{}", max));
+    out.push_str("</svg>\n");
+    out
+}
+
+pub fn write_html(path: &str, sys: &StockSys, config: &str) -> Result<(), Box<dyn Error>> {
+    let equity: Vec<(DateTime<FixedOffset>, f64)> =
+        sys.equity.iter().map(|(t, v)| (*t, *v as f64)).collect();
+    let mut peak = f64::MIN;
+    let drawdown: Vec<(DateTime<FixedOffset>, f64)> = equity
+        .iter()
+        .map(|(t, v)| {
+            peak = peak.max(*v);
+            (*t, *v - peak)
+        })
+        .collect();
+    // 已经平仓的买入和融券卖空，盈亏扣除税费和融券费用
+    let mut closed = Vec::new();
+    for o in sys.orders.iter().filter(|o| o.left == 0) {
+        closed.push((o.profit - (o.tax + o.commission) as i128, o.selt_time - o.time));
+    }
+    for s in sys.shorts.iter().filter(|s| s.left == 0) {
+        closed.push((s.profit - (s.tax + s.commission + s.fee) as i128, s.cover_time - s.time));
+    }
+    let net: i128 = closed.iter().map(|(p, _)| p).sum();
+    let wins = closed.iter().filter(|(p, _)| *p > 0).count();
+    let profits: Vec<f64> = closed.iter().map(|(p, _)| *p as f64).collect();
+    let holding: Vec<f64> = closed.iter().map(|(_, d)| d.num_seconds() as f64).collect();
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>backtest report</title>\n");
+    html.push_str("<style>body{font-family:sans-serif;margin:20px}table{border-collapse:collapse}td{border:1px solid #ccc;padding:4px 8px}pre{background:#f5f5f5;padding:10px}</style>\n");
+    html.push_str("</head><body>\n<h1>backtest report</h1>\n<table>\n");
+    let rows = [
+        ("tick data", sys.conf.tick_data.clone()),
+        ("orders", sys.orders.len().to_string()),
+        ("shorts", sys.shorts.len().to_string()),
+        ("closed trades", closed.len().to_string()),
+        ("wins", wins.to_string()),
+        ("profit with tax commission fee", net.to_string()),
+        (
+            "max drawdown",
+            format!("{:.0}", drawdown.iter().map(|(_, d)| *d).fold(0.0, f64::min)),
+        ),
+    ];
+    for (k, v) in rows.iter() {
+        let _ = writeln!(html, "<tr><td>{}</td><td>{}</td></tr>", k, escape(v));
+    }
+    html.push_str("</table>\n");
+    html.push_str(&line_chart("equity curve", &equity, &[]));
+    html.push_str(&line_chart("drawdown", &drawdown, &[]));
+    // 每个股票一张价格图，价格为资金曲线采样时的价格
+    for (code, hold) in &sys.hold {
+        let prices: Vec<(DateTime<FixedOffset>, f64)> = sys
+            .equity
+            .iter()
+            .zip(&hold.prices)
+            .filter_map(|((t, _), p)| p.map(|p| (*t, p as f64)))
+            .collect();
+        let mut markers = Vec::new();
+        for o in sys.orders.iter().filter(|o| &o.code == code) {
+            markers.push((o.time, o.open_price as f64, "#2ca02c"));
+            if o.left == 0 {
+                markers.push((o.selt_time, o.sell_price_avg as f64, "#d62728"));
+            }
+        }
+        for s in sys.shorts.iter().filter(|s| &s.code == code) {
+            markers.push((s.time, s.open_price as f64, "#ff7f0e"));
+            if s.left == 0 {
+                markers.push((s.cover_time, s.cover_price_avg as f64, "#9467bd"));
+            }
+        }
+        let title = format!(
+            "price of {} (green: buy, red: sell, orange: short, purple: cover)",
+            code
+        );
+        html.push_str(&line_chart(&title, &prices, &markers));
+    }
+    html.push_str(&histogram("profit per trade", &profits, ""));
+    html.push_str(&histogram("holding time", &holding, "s"));
+    let _ = writeln!(html, "<h2>config</h2>\n<pre>{}</pre>", escape(config));
+    html.push_str("</body></html>\n");
+    fs::write(path, html)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategy::tests::{new_sys, tick};
+    use crate::tick::{get_time, trade_date};
+
+    fn at(yyyymmdd: u32, ntime: u64) -> DateTime<FixedOffset> {
+        get_time(trade_date(yyyymmdd).unwrap(), ntime).unwrap()
+    }
+
+    #[test]
+    fn time_axis_labels() {
+        let one_day = [(at(20211101, 93000000), 1.0), (at(20211101, 145700000), 2.0)];
+        let svg = line_chart("equity curve", &one_day, &[]);
+        assert!(svg.contains(">09:30:00</text>"));
+        assert!(svg.contains(">14:57:00</text>"));
+        let two_days = [(at(20211101, 93000000), 1.0), (at(20211102, 145700000), 2.0)];
+        let svg = line_chart("equity curve", &two_days, &[]);
+        assert!(svg.contains(">2021-11-01 09:30:00</text>"));
+        assert!(svg.contains(">2021-11-02 14:57:00</text>"));
+        assert!(line_chart("drawdown", &[], &[]).contains("no data"));
+    }
+
+    #[test]
+    fn renders_report() {
+        let mut sys = new_sys("report", "");
+        sys.do_strategy(&tick("601012.SH", 93000000, 400000, 1000));
+        sys.do_strategy(&tick("601012.SH", 93003000, 402100, 2000));
+        sys.do_strategy(&tick("601012.SH", 93200000, 402300, 3000));
+        let path = std::env::temp_dir().join(format!("report_test_{}.html", std::process::id()));
+        write_html(path.to_str().unwrap(), &sys, "buy_point = 0.005 # <&>").unwrap();
+        let html = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        for title in [
+            "<h2>equity curve</h2>",
+            "<h2>drawdown</h2>",
+            "<h2>price of 601012.SH",
+            "<h2>profit per trade</h2>",
+            "<h2>holding time</h2>",
+        ]
+        .iter()
+        {
+            assert!(html.contains(title), "missing {}", title);
+        }
+        // 资金曲线、回撤和价格各一条折线，买入点和卖出点
+        assert_eq!(html.matches("<polyline").count(), 3);
+        assert_eq!(html.matches("fill=\"#2ca02c\"").count(), 1);
+        assert_eq!(html.matches("fill=\"#d62728\"").count(), 1);
+        assert!(html.contains("<tr><td>orders</td><td>1</td></tr>"));
+        assert!(html.contains("<tr><td>closed trades</td><td>1</td></tr>"));
+        assert!(html.contains(">09:30:00</text>"));
+        assert!(html.contains("buy_point = 0.005 # &lt;&amp;&gt;"));
+    }
+}
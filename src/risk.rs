@@ -0,0 +1,292 @@
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use super::tick;
+
+// 风控参数，值为0表示不限制
+// 金额单位与tick中的价格一致（元*10000）
+#[derive(Debug, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct RiskConfig {
+    pub max_open_orders: usize,  // 单个股票最多未平仓订单数
+    pub max_position: u64,       // 单个股票最大持仓股数
+    pub max_gross_exposure: u64, // 最大总敞口（按当前价计算的持仓市值）
+    pub max_daily_loss: u64,     // 单日最大亏损，触发后当天不再开仓
+    pub max_book_ratio: f64,     // 单笔委托数量占对手盘10档总量的最大比例
+    pub kill_switch: bool,       // 紧急停止，不再开仓
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Breach {
+    KillSwitch,
+    DailyLoss,
+    OpenOrders,
+    Position,
+    GrossExposure,
+    BookDepth,
+}
+
+impl Display for Breach {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Breach::KillSwitch => "kill switch",
+            Breach::DailyLoss => "max daily loss",
+            Breach::OpenOrders => "max open orders",
+            Breach::Position => "max position",
+            Breach::GrossExposure => "max gross exposure",
+            Breach::BookDepth => "max order size vs book depth",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// 下单时的持仓情况，由策略根据当前订单计算
+pub struct Exposure {
+    pub open_orders: usize,  // 当前股票同方向（买入或融券）未平仓订单数
+    pub position: u64,       // 当前股票同方向持仓股数
+    pub gross: u64,          // 所有持仓按当前价计算的市值
+    pub daily_pnl: i128,     // 当日已实现+未实现盈亏（已扣除税费）
+}
+
+// 每个订单下单前都需要经过风控检查
+pub struct RiskManager {
+    pub conf: RiskConfig,
+    pub breaches: BTreeMap<Breach, u64>,
+    halted: Option<NaiveDate>, // 触发单日最大亏损的日期，当天停止开仓
+}
+
+pub fn new_risk_manager(conf: RiskConfig) -> RiskManager {
+    RiskManager {
+        conf,
+        breaches: BTreeMap::new(),
+        halted: None,
+    }
+}
+
+impl RiskManager {
+    fn breach(&mut self, dt: DateTime<FixedOffset>, b: Breach, detail: String) {
+        warn!("{} risk breach [{}]: {}", dt, b, detail);
+        *self.breaches.entry(b).or_insert(0) += 1;
+    }
+
+    // 当天是否已经停止交易
+    pub fn is_halted(&self, dt: DateTime<FixedOffset>) -> bool {
+        self.halted == Some(dt.date_naive())
+    }
+
+    // 每个tick检查单日亏损，触发后当天停止开仓
+    pub fn update_daily_pnl(&mut self, dt: DateTime<FixedOffset>, daily_pnl: i128) {
+        if self.conf.max_daily_loss == 0 || self.is_halted(dt) {
+            return;
+        }
+        if daily_pnl < -(self.conf.max_daily_loss as i128) {
+            warn!(
+                "{} daily pnl {} exceeds max daily loss {}, halt trading for the day",
+                dt, daily_pnl, self.conf.max_daily_loss
+            );
+            self.halted = Some(dt.date_naive());
+        }
+    }
+
+    // 检查买单，全部通过才能下单
+    pub fn check_buy(&mut self, tick: &tick::Tick, volume: u64, exposure: &Exposure) -> bool {
+        self.check(tick, volume, exposure, false)
+    }
+
+    // 检查融券卖出，和买单一样检查所有限制，exposure为融券的订单数和持仓
+    pub fn check_short(&mut self, tick: &tick::Tick, volume: u64, exposure: &Exposure) -> bool {
+        self.check(tick, volume, exposure, true)
+    }
+
+    // 买单按卖1估算市值、和卖盘比较，融券卖出按买1估算市值、和买盘比较
+    fn check(&mut self, tick: &tick::Tick, volume: u64, exposure: &Exposure, short: bool) -> bool {
+        let dt = tick.dt;
+        let (price, side) = if short {
+            (tick.nBidPrice1, "bid")
+        } else {
+            (tick.nAskPrice1, "ask")
+        };
+        if self.conf.kill_switch {
+            self.breach(dt, Breach::KillSwitch, "kill switch is on".to_string());
+            return false;
+        }
+        self.update_daily_pnl(dt, exposure.daily_pnl);
+        if self.is_halted(dt) {
+            self.breach(dt, Breach::DailyLoss, "trading halted for the day".to_string());
+            return false;
+        }
+        if self.conf.max_open_orders > 0 && exposure.open_orders >= self.conf.max_open_orders {
+            self.breach(
+                dt,
+                Breach::OpenOrders,
+                format!("{} open orders of {}", exposure.open_orders, tick.chWindCode),
+            );
+            return false;
+        }
+        if self.conf.max_position > 0 && exposure.position + volume > self.conf.max_position {
+            self.breach(
+                dt,
+                Breach::Position,
+                format!(
+                    "position {} + {} > {} of {}",
+                    exposure.position, volume, self.conf.max_position, tick.chWindCode
+                ),
+            );
+            return false;
+        }
+        if self.conf.max_gross_exposure > 0
+            && exposure.gross + volume * price > self.conf.max_gross_exposure
+        {
+            self.breach(
+                dt,
+                Breach::GrossExposure,
+                format!(
+                    "exposure {} + {} > {}",
+                    exposure.gross,
+                    volume * price,
+                    self.conf.max_gross_exposure
+                ),
+            );
+            return false;
+        }
+        if self.conf.max_book_ratio > 0.0 {
+            let levels = if short { tick.bids() } else { tick.asks() };
+            let depth: u64 = levels.iter().map(|(_, v)| v).sum();
+            if volume as f64 > depth as f64 * self.conf.max_book_ratio {
+                self.breach(
+                    dt,
+                    Breach::BookDepth,
+                    format!(
+                        "volume {} > {} depth {} * {}",
+                        volume, side, depth, self.conf.max_book_ratio
+                    ),
+                );
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::empty_tick;
+    use crate::tick::{get_time, trade_date};
+
+    fn tick(ntime: u64) -> tick::Tick {
+        let mut t = empty_tick("601012.SH");
+        t.nTime = ntime;
+        t.dt = get_time(trade_date(0).unwrap(), ntime).unwrap();
+        t.nPrice = 400000;
+        t.nBidPrice1 = 399900;
+        t.nBidVolume1 = 1000;
+        t.nAskPrice1 = 400100;
+        t.nAskVolume1 = 500;
+        t.nAskPrice2 = 400200;
+        t.nAskVolume2 = 500;
+        t
+    }
+
+    fn exposure(open_orders: usize, position: u64, gross: u64, daily_pnl: i128) -> Exposure {
+        Exposure {
+            open_orders,
+            position,
+            gross,
+            daily_pnl,
+        }
+    }
+
+    #[test]
+    fn no_limits() {
+        let mut risk = new_risk_manager(RiskConfig::default());
+        assert!(risk.check_buy(&tick(93000000), 1000000, &exposure(100, 1000000, u64::MAX / 2, -1)));
+        assert!(risk.breaches.is_empty());
+    }
+
+    #[test]
+    fn kill_switch() {
+        let mut risk = new_risk_manager(RiskConfig {
+            kill_switch: true,
+            ..RiskConfig::default()
+        });
+        assert!(!risk.check_buy(&tick(93000000), 100, &exposure(0, 0, 0, 0)));
+        assert!(!risk.check_short(&tick(93000000), 100, &exposure(0, 0, 0, 0)));
+        assert_eq!(risk.breaches[&Breach::KillSwitch], 2);
+    }
+
+    #[test]
+    fn max_open_orders_and_position() {
+        let mut risk = new_risk_manager(RiskConfig {
+            max_open_orders: 2,
+            max_position: 1000,
+            ..RiskConfig::default()
+        });
+        let t = tick(93000000);
+        assert!(risk.check_buy(&t, 500, &exposure(1, 500, 0, 0)));
+        assert!(!risk.check_buy(&t, 100, &exposure(2, 500, 0, 0)));
+        assert!(!risk.check_buy(&t, 600, &exposure(1, 500, 0, 0)));
+        assert_eq!(risk.breaches[&Breach::OpenOrders], 1);
+        assert_eq!(risk.breaches[&Breach::Position], 1);
+    }
+
+    #[test]
+    fn max_gross_exposure() {
+        let mut risk = new_risk_manager(RiskConfig {
+            max_gross_exposure: 100000000,
+            ..RiskConfig::default()
+        });
+        let t = tick(93000000);
+        // 买单按卖1，融券按买1估算市值
+        assert!(risk.check_buy(&t, 100, &exposure(0, 0, 100000000 - 400100 * 100, 0)));
+        assert!(!risk.check_buy(&t, 100, &exposure(0, 0, 100000000 - 400100 * 100 + 1, 0)));
+        assert!(risk.check_short(&t, 100, &exposure(0, 0, 100000000 - 399900 * 100, 0)));
+        assert_eq!(risk.breaches[&Breach::GrossExposure], 1);
+    }
+
+    #[test]
+    fn max_book_ratio() {
+        let mut risk = new_risk_manager(RiskConfig {
+            max_book_ratio: 0.5,
+            ..RiskConfig::default()
+        });
+        let t = tick(93000000);
+        assert!(risk.check_buy(&t, 500, &exposure(0, 0, 0, 0)));
+        assert!(!risk.check_buy(&t, 600, &exposure(0, 0, 0, 0)));
+        assert!(risk.check_short(&t, 500, &exposure(0, 0, 0, 0)));
+        assert!(!risk.check_short(&t, 600, &exposure(0, 0, 0, 0)));
+        assert_eq!(risk.breaches[&Breach::BookDepth], 2);
+    }
+
+    #[test]
+    fn daily_loss_halts_for_the_day() {
+        let mut risk = new_risk_manager(RiskConfig {
+            max_daily_loss: 1000000,
+            ..RiskConfig::default()
+        });
+        let t = tick(93000000);
+        risk.update_daily_pnl(t.dt, -1000000);
+        assert!(!risk.is_halted(t.dt));
+        risk.update_daily_pnl(t.dt, -1000001);
+        assert!(risk.is_halted(t.dt));
+        // 亏损收窄后当天仍然停止开仓
+        assert!(!risk.check_buy(&tick(100000000), 100, &exposure(0, 0, 0, 0)));
+        assert_eq!(risk.breaches[&Breach::DailyLoss], 1);
+        // 第二天恢复
+        let mut next = tick(93000000);
+        next.dt = get_time(trade_date(20211101).unwrap(), 93000000).unwrap();
+        assert!(risk.check_buy(&next, 100, &exposure(0, 0, 0, 0)));
+    }
+
+    #[test]
+    fn check_updates_daily_loss() {
+        let mut risk = new_risk_manager(RiskConfig {
+            max_daily_loss: 1000000,
+            ..RiskConfig::default()
+        });
+        assert!(!risk.check_short(&tick(93000000), 100, &exposure(0, 0, 0, -2000000)));
+        assert!(risk.is_halted(tick(93000000).dt));
+    }
+}
@@ -0,0 +1,404 @@
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use csv::StringRecord;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+
+use super::feed::FeedError;
+use super::tick::{self, Tick, TimeOfDay};
+
+// tick数据的列名映射，用于读取不同数据商的CSV
+// 先取vendor预置的映射，再用columns/scale/time_format覆盖
+// columns: Tick字段名 = 数据文件中的列名，没有映射的字段按同名列读取，找不到时取0
+//          映射了的列和时间列必须存在，否则报错
+// scale: 数据文件中的值乘以scale得到Tick中的值，如价格单位为元时为10000
+//        注意Tick中的涨跌停价比普通价格少一位，价格单位为元时为1000
+// time_format: nTime列的时间格式，如 "%Y-%m-%d %H:%M:%S%.f"，为空时按91003000这样的整数读取
+//              格式中包括日期时使用数据中的日期，否则使用配置的交易日
+// symbol: 数据文件中没有代码列时使用的代码
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct SchemaConfig {
+    pub vendor: String,
+    pub symbol: String,
+    pub time_format: String,
+    pub columns: HashMap<String, String>,
+    pub scale: HashMap<String, f64>,
+}
+
+// Tick中需要从数据文件读取的字段，按Tick的字段顺序排列
+fn tick_columns() -> Vec<&'static str> {
+    let mut columns = vec!["chWindCode"];
+    columns.extend_from_slice(&tick_fields!(field_names));
+    columns
+}
+
+// (Tick字段名 -> 列名, Tick字段名 -> 单位换算, 时间格式)
+pub type Mapping = (HashMap<String, String>, HashMap<String, f64>, String);
+
+// 预置的数据商列名映射
+fn vendor_preset(vendor: &str) -> Result<Mapping, Box<dyn Error>> {
+    let mut columns = HashMap::new();
+    let mut scale = HashMap::new();
+    let mut map = |field: &str, column: &str, s: f64| {
+        columns.insert(field.to_string(), column.to_string());
+        if s != 1.0 {
+            scale.insert(field.to_string(), s);
+        }
+    };
+    let time_format = match vendor {
+        // Wind/TDF，即Tick结构体本身的列名
+        "" | "wind" => String::new(),
+        // 聚宽 get_ticks 导出的CSV，价格单位为元，5档行情
+        "joinquant" => {
+            map("nTime", "time", 1.0);
+            map("nPrice", "current", 10000.0);
+            map("High", "high", 10000.0);
+            map("Low", "low", 10000.0);
+            map("TotalVolume", "volume", 1.0);
+            map("TotalTurnover", "money", 1.0);
+            for i in 1..=5 {
+                map(&format!("nAskPrice{}", i), &format!("a{}_p", i), 10000.0);
+                map(&format!("nAskVolume{}", i), &format!("a{}_v", i), 1.0);
+                map(&format!("nBidPrice{}", i), &format!("b{}_p", i), 10000.0);
+                map(&format!("nBidVolume{}", i), &format!("b{}_v", i), 1.0);
+            }
+            "%Y-%m-%d %H:%M:%S%.f".to_string()
+        }
+        // 米筐 get_price(frequency='tick') 导出的CSV，价格单位为元，5档行情
+        "ricequant" => {
+            map("chWindCode", "order_book_id", 1.0);
+            map("nTime", "datetime", 1.0);
+            map("Open", "open", 10000.0);
+            map("nPrice", "last", 10000.0);
+            map("High", "high", 10000.0);
+            map("Low", "low", 10000.0);
+            map("PreClose", "prev_close", 10000.0);
+            map("TotalVolume", "volume", 1.0);
+            map("TotalTurnover", "total_turnover", 1.0);
+            map("HighLimited", "limit_up", 1000.0);
+            map("LowLimited", "limit_down", 1000.0);
+            for i in 1..=5 {
+                map(&format!("nAskPrice{}", i), &format!("a{}", i), 10000.0);
+                map(&format!("nAskVolume{}", i), &format!("a{}_v", i), 1.0);
+                map(&format!("nBidPrice{}", i), &format!("b{}", i), 10000.0);
+                map(&format!("nBidVolume{}", i), &format!("b{}_v", i), 1.0);
+            }
+            "%Y-%m-%d %H:%M:%S%.f".to_string()
+        }
+        _ => return Err(format!("unknown tick vendor: {}", vendor).into()),
+    };
+    Ok((columns, scale, time_format))
+}
+
+// 根据配置和数据文件的表头生成的映射
+pub struct Schema {
+    identity: bool,             // 列名和单位都与Tick一致，可以直接反序列化
+    source: Vec<Option<usize>>, // Tick每个字段对应的数据文件列号
+    scale: Vec<f64>,
+    time_format: String,
+    symbol: String,
+    date: NaiveDate,            // 时间中没有日期时使用的交易日
+    headers: StringRecord,      // Tick的字段名
+    file_headers: StringRecord, // 数据文件的表头
+}
+
+// 合并vendor预置的映射和配置，CSV和parquet都按这个映射读取
+pub fn mapping(conf: &SchemaConfig) -> Result<Mapping, Box<dyn Error>> {
+    let (mut columns, mut scale, mut time_format) = vendor_preset(&conf.vendor)?;
+    columns.extend(conf.columns.clone());
+    scale.extend(conf.scale.clone());
+    if !conf.time_format.is_empty() {
+        time_format = conf.time_format.clone();
+    }
+    let fields = tick_columns();
+    for name in columns.keys().chain(scale.keys()) {
+        if !fields.contains(&name.as_str()) {
+            return Err(format!("unknown Tick field in schema: {}", name).into());
+        }
+    }
+    Ok((columns, scale, time_format))
+}
+
+// 按数据文件的列名找出Tick每个字段对应的列，按Tick的字段顺序排列，没有的列为None
+// 映射了的列和时间列找不到时报错，没有代码列时需要配置symbol，CSV和parquet都按这个规则检查
+pub fn resolve_columns(
+    conf: &SchemaConfig,
+    columns: &HashMap<String, String>,
+    headers: &[&str],
+) -> Result<Vec<Option<String>>, Box<dyn Error>> {
+    let mut missing = Vec::new();
+    let res: Vec<Option<String>> = tick_columns()
+        .into_iter()
+        .map(|field| {
+            let column = columns.get(field).map(|c| c.as_str()).unwrap_or(field);
+            if headers.contains(&column) {
+                return Some(column.to_string());
+            }
+            if field != "chWindCode" && (field == "nTime" || columns.contains_key(field)) {
+                missing.push(format!("{} ({})", column, field));
+            }
+            None
+        })
+        .collect();
+    if res[0].is_none() && conf.symbol.is_empty() {
+        return Err("no symbol column in tick data, set symbol in [schema]".into());
+    }
+    if !missing.is_empty() {
+        return Err(format!("columns not found in tick data: {}", missing.join(", ")).into());
+    }
+    Ok(res)
+}
+
+pub fn new_schema(
+    conf: &SchemaConfig,
+    headers: &StringRecord,
+    date: NaiveDate,
+) -> Result<Schema, Box<dyn Error>> {
+    let (columns, scale, time_format) = mapping(conf)?;
+    let fields = tick_columns();
+    let names: Vec<&str> = headers.iter().collect();
+    let source: Vec<Option<usize>> = resolve_columns(conf, &columns, &names)?
+        .iter()
+        .map(|column| column.as_ref().and_then(|c| names.iter().position(|h| h == c)))
+        .collect();
+    let identity = columns.is_empty()
+        && scale.is_empty()
+        && time_format.is_empty()
+        && source.iter().all(|s| s.is_some());
+    Ok(Schema {
+        identity,
+        scale: fields
+            .iter()
+            .map(|f| *scale.get(*f).unwrap_or(&1.0))
+            .collect(),
+        source,
+        time_format,
+        symbol: conf.symbol.clone(),
+        date,
+        headers: fields.iter().collect(),
+        file_headers: headers.clone(),
+    })
+}
+
+// 数据文件中的值乘以scale后转换为Tick中的整数，CSV和parquet都按这个规则转换
+// 负数、NaN、超出范围的值，以及没有配置scale时的小数都返回None，不会被当作0或截断
+pub fn scale_value(v: f64, scale: f64) -> Option<u64> {
+    let x = v * scale;
+    if !x.is_finite() || x < 0.0 || x >= u64::MAX as f64 || (scale == 1.0 && x.fract() != 0.0) {
+        return None;
+    }
+    Some(x.round() as u64)
+}
+
+// 把时间转为91003000这样的整数，格式中有日期时同时返回日期
+pub fn parse_time(s: &str, format: &str) -> Result<(Option<NaiveDate>, u64), FeedError> {
+    let (date, t) = match NaiveDateTime::parse_from_str(s, format) {
+        Ok(dt) => (Some(dt.date()), dt.time()),
+        Err(_) => (
+            None,
+            NaiveTime::parse_from_str(s, format)
+                .map_err(|e| format!("invalid time {} for format {}: {}", s, format, e))?,
+        ),
+    };
+    // 闰秒的毫秒数超过999，不是合法的时间
+    let t = TimeOfDay::from_hms_milli(t.hour(), t.minute(), t.second(), t.nanosecond() / 1000000)
+        .ok_or_else(|| format!("invalid time {}", s))?;
+    Ok((date, t.ntime()))
+}
+
+impl Schema {
+    // 把数据文件的一行转换为Tick
+    pub fn tick(&self, record: &StringRecord) -> Result<Tick, FeedError> {
+        let mut date = self.date;
+        let mut t: Tick = if self.identity {
+            record.deserialize(Some(&self.file_headers))?
+        } else {
+            let mut row = StringRecord::new();
+            for (idx, source) in self.source.iter().enumerate() {
+                let raw = source.and_then(|s| record.get(s)).unwrap_or("").trim();
+                if idx == 0 {
+                    row.push_field(if raw.is_empty() { &self.symbol } else { raw });
+                } else if raw.is_empty() {
+                    row.push_field("0");
+                } else if idx == 1 && !self.time_format.is_empty() {
+                    let (d, ntime) = parse_time(raw, &self.time_format)?;
+                    date = d.unwrap_or(date);
+                    row.push_field(&ntime.to_string());
+                } else if self.scale[idx] != 1.0 || raw.contains('.') {
+                    let column = source.and_then(|s| self.file_headers.get(s)).unwrap_or("");
+                    let v = raw
+                        .parse()
+                        .ok()
+                        .and_then(|v| scale_value(v, self.scale[idx]))
+                        .ok_or_else(|| {
+                            format!("column {}: {} is not a non-negative integer", column, raw)
+                        })?;
+                    row.push_field(&v.to_string());
+                } else {
+                    row.push_field(raw);
+                }
+            }
+            row.deserialize(Some(&self.headers))?
+        };
+        t.dt = tick::get_time(date, t.nTime)?;
+        Ok(t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick::trade_date;
+
+    fn conf(vendor: &str) -> SchemaConfig {
+        SchemaConfig {
+            vendor: vendor.to_string(),
+            ..SchemaConfig::default()
+        }
+    }
+
+    fn parse(conf: &SchemaConfig, headers: &[&str], row: &[&str]) -> Result<Tick, FeedError> {
+        let schema = new_schema(conf, &StringRecord::from(headers.to_vec()), trade_date(0).unwrap())
+            .map_err(|e| e.to_string())?;
+        schema.tick(&StringRecord::from(row.to_vec()))
+    }
+
+    #[test]
+    fn wind() {
+        let headers = tick_columns();
+        let mut row: Vec<String> = vec!["0".to_string(); headers.len()];
+        row[0] = "601012.SH".to_string();
+        row[1] = "93003000".to_string();
+        row[headers.iter().position(|h| *h == "nPrice").unwrap()] = "400100".to_string();
+        let row: Vec<&str> = row.iter().map(|s| s.as_str()).collect();
+        let t = parse(&conf("wind"), &headers, &row).unwrap();
+        assert_eq!((t.chWindCode.as_str(), t.nTime, t.nPrice), ("601012.SH", 93003000, 400100));
+        assert_eq!(t.dt, tick::get_time(trade_date(0).unwrap(), 93003000).unwrap());
+    }
+
+    #[test]
+    fn joinquant() {
+        let conf = SchemaConfig {
+            symbol: "601012.SH".to_string(),
+            ..conf("joinquant")
+        };
+        let headers = ["time", "current", "high", "low", "volume", "money", "a1_p", "a1_v", "b1_p", "b1_v"];
+        let mut headers = headers.to_vec();
+        let names: Vec<String> = (2..=5)
+            .flat_map(|i| vec![format!("a{}_p", i), format!("a{}_v", i), format!("b{}_p", i), format!("b{}_v", i)])
+            .collect();
+        headers.extend(names.iter().map(|s| s.as_str()));
+        let mut row = vec![
+            "2021-11-01 09:30:03.500", "40.01", "40.5", "39.8", "1200", "4801200.0", "40.02", "300", "40.01", "200",
+        ];
+        row.extend(std::iter::repeat_n("0", 16));
+        let t = parse(&conf, &headers, &row).unwrap();
+        assert_eq!(t.chWindCode, "601012.SH");
+        assert_eq!(t.nTime, 93003500);
+        // 时间中的日期优先于配置的交易日
+        assert_eq!(t.dt.date_naive(), trade_date(20211101).unwrap());
+        assert_eq!((t.nPrice, t.High, t.Low), (400100, 405000, 398000));
+        assert_eq!((t.TotalVolume, t.TotalTurnover), (1200, 4801200));
+        assert_eq!((t.nAskPrice1, t.nAskVolume1, t.nBidPrice1, t.nBidVolume1), (400200, 300, 400100, 200));
+    }
+
+    #[test]
+    fn ricequant() {
+        let mut headers = vec![
+            "order_book_id", "datetime", "open", "last", "high", "low", "prev_close", "volume",
+            "total_turnover", "limit_up", "limit_down",
+        ];
+        let names: Vec<String> = (1..=5)
+            .flat_map(|i| vec![format!("a{}", i), format!("a{}_v", i), format!("b{}", i), format!("b{}_v", i)])
+            .collect();
+        headers.extend(names.iter().map(|s| s.as_str()));
+        let mut row = vec![
+            "601012.XSHG", "2021-10-30 14:59:59", "40", "40.01", "40.5", "39.8", "39.9", "1200",
+            "4801200", "43.89", "35.91", "40.02", "300", "40.01", "200",
+        ];
+        row.extend(std::iter::repeat_n("0", 16));
+        let t = parse(&conf("ricequant"), &headers, &row).unwrap();
+        assert_eq!((t.chWindCode.as_str(), t.nTime), ("601012.XSHG", 145959000));
+        assert_eq!((t.Open, t.nPrice, t.PreClose), (400000, 400100, 399000));
+        // 涨跌停价比普通价格少一位
+        assert_eq!((t.HighLimited, t.LowLimited), (43890, 35910));
+        assert_eq!((t.nAskPrice1, t.nBidVolume1), (400200, 200));
+    }
+
+    #[test]
+    fn bad_values() {
+        let conf = SchemaConfig {
+            symbol: "601012.SH".to_string(),
+            ..conf("")
+        };
+        let headers = ["nTime", "nPrice", "TotalVolume"];
+        assert!(parse(&conf, &headers, &["93000000", "400000", "100"]).is_ok());
+        assert!(parse(&conf, &headers, &["93000000", "-400000", "100"]).is_err());
+        // 没有配置scale时小数不四舍五入
+        assert!(parse(&conf, &headers, &["93000000", "400000.5", "100"]).is_err());
+        assert_eq!(parse(&conf, &headers, &["93000000", "400000.0", "100"]).unwrap().nPrice, 400000);
+        let scaled = SchemaConfig {
+            scale: vec![("nPrice".to_string(), 10000.0)].into_iter().collect(),
+            ..conf.clone()
+        };
+        assert_eq!(parse(&scaled, &headers, &["93000000", "40.0123", "100"]).unwrap().nPrice, 400123);
+        for bad in ["-40", "NaN", "inf", "1e300", "x"] {
+            assert!(parse(&scaled, &headers, &["93000000", bad, "100"]).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn scale_values() {
+        assert_eq!(scale_value(40.01, 10000.0), Some(400100));
+        assert_eq!(scale_value(3.0, 1.0), Some(3));
+        assert_eq!(scale_value(3.5, 1.0), None);
+        assert_eq!(scale_value(-1.0, 1.0), None);
+        assert_eq!(scale_value(f64::NAN, 10000.0), None);
+        assert_eq!(scale_value(f64::INFINITY, 1.0), None);
+        assert_eq!(scale_value(u64::MAX as f64, 1.0), None);
+    }
+
+    #[test]
+    fn missing_columns() {
+        let (columns, _, _) = mapping(&conf("joinquant")).unwrap();
+        let headers = ["time", "current", "volume"];
+        let err = resolve_columns(&conf("joinquant"), &columns, &headers).unwrap_err();
+        assert_eq!(err.to_string(), "no symbol column in tick data, set symbol in [schema]");
+        let with_symbol = SchemaConfig {
+            symbol: "601012.SH".to_string(),
+            ..conf("joinquant")
+        };
+        let err = resolve_columns(&with_symbol, &columns, &headers).unwrap_err().to_string();
+        assert!(err.starts_with("columns not found in tick data: "), "{}", err);
+        assert!(err.contains("money (TotalTurnover)"), "{}", err);
+        assert!(!err.contains("volume (TotalVolume)"), "{}", err);
+        // 没有映射的字段找不到时取0
+        let columns = HashMap::new();
+        let res = resolve_columns(&with_symbol, &columns, &["nTime", "nPrice"]).unwrap();
+        assert_eq!(res[1], Some("nTime".to_string()));
+        assert_eq!(res.iter().filter(|c| c.is_some()).count(), 2);
+        // 时间列必须存在
+        assert!(resolve_columns(&with_symbol, &columns, &["nPrice"]).is_err());
+        assert!(mapping(&SchemaConfig {
+            columns: vec![("Foo".to_string(), "foo".to_string())].into_iter().collect(),
+            ..SchemaConfig::default()
+        })
+        .is_err());
+        assert!(mapping(&conf("tushare")).is_err());
+    }
+
+    #[test]
+    fn parse_times() {
+        let format = "%Y-%m-%d %H:%M:%S%.f";
+        assert_eq!(
+            parse_time("2021-11-01 09:30:03.123", format).unwrap(),
+            (Some(trade_date(20211101).unwrap()), 93003123)
+        );
+        assert_eq!(parse_time("14:59:59.5", "%H:%M:%S%.f").unwrap(), (None, 145959500));
+        assert!(parse_time("09:30", format).is_err());
+        assert!(parse_time("25:00:00", "%H:%M:%S").is_err());
+        // 闰秒
+        assert!(parse_time("23:59:60.5", "%H:%M:%S%.f").is_err());
+    }
+}
@@ -1,34 +1,62 @@
-use chrono::{DateTime, Duration, FixedOffset};
-use csv;
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate};
 use serde::Deserialize;
 use simple_log::LogConfigBuilder;
 use std::cmp::max;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fmt::{Debug, Display};
-use std::io::BufReader;
 use std::io::Read;
 use std::{fs::File, u64::MAX, u64::MIN};
 
 use crate::tick::Tick;
 
+use super::attribution;
+use super::bar;
+use super::benchmark;
+use super::engine::{self, Action, Timer};
+use super::filter;
+use super::indicator;
+use super::instrument;
+use super::margin::{self, short};
+use super::position;
+use super::risk;
+use super::schema;
 use super::tick;
 use super::transaction;
+use super::validate;
+use super::window;
 
 #[derive(Debug)]
 pub struct order {
-    open_price: u64,
-    time: DateTime<FixedOffset>,
-    selt_time: DateTime<FixedOffset>,
-    volume: usize,
-    sell_price: u64,
-    want_sell_all: bool,
-    sell_price_avg: u64,
-    left: usize,
-    profit: i128,
-    tax: u64,
-    commission: u64,
+    pub code: String,
+    pub open_price: u64,
+    pub time: DateTime<FixedOffset>,
+    pub selt_time: DateTime<FixedOffset>,
+    pub volume: usize,
+    pub sell_price: u64,
+    pub sell_arrive: DateTime<FixedOffset>, // 卖单到达交易所的时间，之前不能成交
+    pub sell_volume: usize,                 // 卖单挂出的数量，撤单后为0
+    pub amending: bool,                     // 撤单或改单已经发出，还没有到达交易所
+    pub cancelled: bool,                    // 卖单已经撤销，不再按订单改单
+    pub want_sell_all: bool,
+    pub sell_price_avg: u64,
+    pub left: usize,
+    pub profit: i128,
+    pub tax: u64,
+    pub commission: u64,
+    pub signal_mid: u64, // 产生买入信号时的中间价
+    pub exit_mid: u64,   // 到达卖出时间（或提前卖出）时的中间价
+}
+// 策略对涨跌的反应，可以同时配置多个
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    LongGapUp,    // 从最低价上涨超过buy_point时买入（突破）
+    ShortGapDown, // 从最高价下跌超过short_point时融券卖出，需要开启[margin]
+    ExitGapDown,  // 从最高价下跌超过drop_point时卖出全部持仓
+    LongGapDown,  // 从最高价下跌超过drop_point时买入（均值回归）
 }
+
 #[derive(Debug, Deserialize)]
 pub struct config {
     buy_point: f64,
@@ -43,6 +71,53 @@ pub struct config {
     log_count: u32,
     pub tick_data: String,
     pub trans_data: String,
+    #[serde(default)]
+    pub trade_date: u32, // 交易日，如20211030，数据和缓存中没有日期时使用，为0时使用2021-10-30
+    #[serde(default = "default_read_ahead")]
+    pub read_ahead: usize, // 读取tick数据时最多预读的条数
+    #[serde(default)]
+    pub order_data: String, // 逐笔委托数据，配置后用逐笔委托和逐笔成交重建委托簿代替tick数据
+    #[serde(default = "default_snapshot_interval")]
+    pub snapshot_interval: u64, // 重建委托簿时输出快照的间隔（毫秒）
+    #[serde(default = "default_equity_interval")]
+    equity_interval: i64, // 资金曲线的采样间隔（秒）
+    #[serde(default)]
+    pub parquet_output: String, // 交易记录和资金曲线输出为parquet的文件名前缀，为空时不输出
+    #[serde(default)]
+    pub html_report: String, // HTML回测报告的文件名，为空时不输出
+    #[serde(default)]
+    pub run_dir: String, // 每次运行在这个目录下建一个子目录，保存运行清单、配置快照和交易记录，为空时不保存
+    #[serde(default)]
+    pub seed: u64, // 随机数种子，记录在运行清单中
+    #[serde(default)]
+    st_symbols: Vec<String>, // ST股票，涨跌幅限制为5%
+    #[serde(default)]
+    pub risk: risk::RiskConfig,
+    #[serde(default)]
+    pub latency: engine::LatencyConfig,
+    #[serde(default)]
+    pub margin: margin::MarginConfig,
+    #[serde(default)]
+    pub filter: filter::FilterConfig,
+    #[serde(default)]
+    pub pyramid: position::PyramidConfig,
+    #[serde(default)]
+    pub benchmark: benchmark::BenchmarkConfig,
+    #[serde(default)]
+    pub validate: validate::ValidateConfig,
+    #[serde(default)]
+    pub schema: schema::SchemaConfig,
+    pub bar: Option<bar::BarConfig>, // 不配置时不聚合K线
+    #[serde(default)]
+    buy_filters: Vec<String>, // 买入前需要满足的指标条件，如 "price > vwap"
+    #[serde(default)]
+    indicator_source: indicator::Source, // 指标按tick还是按K线(bar)更新，默认按tick
+    #[serde(default)]
+    sell_follow_ask: bool, // 卖1低于卖单价格时，把卖单改到卖1
+    #[serde(default = "default_directions")]
+    directions: Vec<Direction>,
+    #[serde(default = "default_drop_point")]
+    drop_point: f64, // 下跌的阈值，用于exit_gap_down和long_gap_down
 }
 
 // 基本思路：
@@ -56,13 +131,98 @@ pub struct StockSys {
     //pub last_sell_order: usize,
     //pub last_sell_idx: usize,
     //pub last_buy_idx: usize,
-    pub gap_window: Vec<tick::Tick>,
+    pub gap_window: window::MinMaxWindow,
     pub gap_rate: f64,
+    pub drop_rate: f64, // 从时间窗最高价下跌的比例
     //pub min_idx: usize,
     //pub max_idx: usize,
     pub min: u64,
     pub max: u64,
-    pub trans: HashMap<u64, transaction::transaction>,
+    pub trans: Vec<transaction::transaction>, // 上一个tick之后到当前tick的逐笔成交，按时间排序
+    pub risk: risk::RiskManager,
+    pub bar_kind: Option<bar::BarKind>, // 不配置[bar]时不聚合K线
+    pub bar_aggregators: BTreeMap<String, bar::BarAggregator>, // 每个代码的K线分别聚合
+    pub bars: Vec<bar::Bar>,
+    pub indicators: HashMap<String, indicator::IndicatorSet>, // 每个代码的指标分别计算
+    pub filter: filter::SignalFilter,
+    pub equity: Vec<(DateTime<FixedOffset>, i128)>, // 资金曲线，(时间, 累计盈亏)
+    pub hold: BTreeMap<String, benchmark::BuyAndHold>, // 每个股票持有不动的基准，和资金曲线一起采样
+    pub events: engine::EventQueue, // 策略产生的订单回报和定时器事件
+    last_ticks: HashMap<String, Tick>, // 每个代码最新的tick，定时器触发时按这个盘口处理
+    pending_buys: Vec<PendingBuy>,     // 已经发出、还没有到达交易所撮合的买单
+    book_used: HashMap<String, BookUsage>, // 每个代码最新的快照中已经被自己的订单成交掉的数量
+    pub positions: HashMap<String, position::Position>, // 每个股票所有订单合并后的持仓
+    pub shorts: Vec<short>,            // 融券卖空
+    pub margin: margin::MarginAccount,
+    open_orders: Vec<usize>, // 可能还没有卖完的订单，统计持仓时顺便去掉已经卖完的
+    open_shorts: Vec<usize>, // 可能还没有平仓的融券
+    closed_pnl: i128,        // 已经去掉的订单和融券的盈亏（扣除税费）
+    day_start: Option<(NaiveDate, i128)>, // 当前交易日和前一个交易日结束时的总盈亏
+    last_pnl: i128,          // 最近一次计算的总盈亏
+}
+
+// 一个盘口快照中已经被自己的订单成交掉的数量，价格 -> 数量
+// 同一个快照上的后续订单和定时器只能使用剩下的部分，收到这个代码新的快照时清空
+#[derive(Default)]
+struct BookUsage {
+    bids: HashMap<u64, u64>,
+    asks: HashMap<u64, u64>,
+}
+
+fn levels_left(levels: [(u64, u64); 10], used: &HashMap<u64, u64>) -> [(u64, u64); 10] {
+    let mut res = levels;
+    for (p, v) in res.iter_mut() {
+        *v = v.saturating_sub(*used.get(p).unwrap_or(&0));
+    }
+    res
+}
+
+impl BookUsage {
+    fn bids(&self, tick: &tick::Tick) -> [(u64, u64); 10] {
+        levels_left(tick.bids(), &self.bids)
+    }
+
+    fn asks(&self, tick: &tick::Tick) -> [(u64, u64); 10] {
+        levels_left(tick.asks(), &self.asks)
+    }
+
+    fn use_bid(&mut self, price: u64, volume: u64) {
+        *self.bids.entry(price).or_insert(0) += volume;
+    }
+
+    fn use_ask(&mut self, price: u64, volume: u64) {
+        *self.asks.entry(price).or_insert(0) += volume;
+    }
+}
+
+// 有延迟时，买入信号产生后要等委托到达交易所才能撮合
+struct PendingBuy {
+    code: String,
+    signal: DateTime<FixedOffset>,
+    signal_mid: u64,
+    arrive: DateTime<FixedOffset>,
+    volume: u64, // 还没有成交的数量
+    rest: bool,  // 逐笔成交撮合后剩下的部分，等下一个快照撮合
+}
+
+fn default_read_ahead() -> usize {
+    1024
+}
+
+fn default_directions() -> Vec<Direction> {
+    vec![Direction::LongGapUp, Direction::ShortGapDown]
+}
+
+fn default_drop_point() -> f64 {
+    0.005
+}
+
+fn default_snapshot_interval() -> u64 {
+    1000
+}
+
+fn default_equity_interval() -> i64 {
+    60
 }
 
 pub fn new_config(path: &str) -> Result<config, Box<dyn Error>> {
@@ -71,40 +231,142 @@ pub fn new_config(path: &str) -> Result<config, Box<dyn Error>> {
     file.read_to_string(&mut contents)?;
 
     let conf: config = toml::from_str(&contents).unwrap();
+    tick::trade_date(conf.trade_date)?;
+    check_directions(&conf.directions)?;
     Ok(conf)
 }
 
+// 同一次下跌不能既买入又卖出：long_gap_down和exit_gap_down、short_gap_down同时配置时，
+// 同一个tick会买入后立即卖出，或者同时开多仓和空仓
+fn check_directions(directions: &[Direction]) -> Result<(), String> {
+    if !directions.contains(&Direction::LongGapDown) {
+        return Ok(());
+    }
+    let conflicts = [
+        (Direction::ExitGapDown, "exit_gap_down"),
+        (Direction::ShortGapDown, "short_gap_down"),
+    ];
+    for (d, name) in conflicts.iter() {
+        if directions.contains(d) {
+            return Err(format!("directions long_gap_down and {} are contradictory", name));
+        }
+    }
+    Ok(())
+}
+
+impl config {
+    // 配置的交易日，new_config中已经检查过格式
+    pub fn date(&self) -> NaiveDate {
+        tick::trade_date(self.trade_date).unwrap()
+    }
+}
+
 pub fn new_stock_sys(config: &str) -> Result<StockSys, Box<dyn Error>> {
+    let conf = new_config(config)?;
+    let risk = risk::new_risk_manager(conf.risk.clone());
+    let bar_kind = match &conf.bar {
+        Some(c) => Some(bar::new_bar_kind(c)?),
+        None => None,
+    };
+    // 每个代码的指标在第一次用到时创建，这里先检查配置
+    indicator::new_indicator_set(&conf.buy_filters, conf.indicator_source)?;
+    let gap_window = window::new_min_max_window(conf.gap_window);
+    let margin = margin::new_margin_account(conf.margin.clone())?;
+    conf.pyramid.check()?;
+    let filter = filter::new_signal_filter(conf.filter.clone(), conf.gap_window);
+    if conf.indicator_source == indicator::Source::Bar && bar_kind.is_none() {
+        return Err("indicator_source is bar but [bar] is not configured".into());
+    }
     Ok(StockSys {
-        conf: new_config(config)?,
+        conf,
         orders: Vec::new(),
         //last_buy_order: 0,
         //last_sell_order: 0,
         //last_sell_idx: 0,
         //last_buy_idx: 0,
-        gap_window: Vec::new(),
+        gap_window,
         gap_rate: 0.0,
+        drop_rate: 0.0,
         //max_idx: 0,
         //min_idx: 0,
         max: MIN,
         min: MAX,
-        trans: HashMap::new(),
+        trans: Vec::new(),
+        risk,
+        bar_kind,
+        bar_aggregators: BTreeMap::new(),
+        bars: Vec::new(),
+        indicators: HashMap::new(),
+        filter,
+        equity: Vec::new(),
+        hold: BTreeMap::new(),
+        events: engine::new_event_queue(),
+        last_ticks: HashMap::new(),
+        pending_buys: Vec::new(),
+        book_used: HashMap::new(),
+        open_orders: Vec::new(),
+        open_shorts: Vec::new(),
+        closed_pnl: 0,
+        day_start: None,
+        last_pnl: 0,
+        positions: HashMap::new(),
+        shorts: Vec::new(),
+        margin,
     })
 }
 
-pub fn read_tick_from_data(path: &str) -> Result<Vec<tick::Tick>, Box<dyn Error>> {
-    let mut res: Vec<tick::Tick> = Vec::new();
-    let f = File::open(path)?;
-    let reader = BufReader::new(f);
-    let mut rdr = csv::Reader::from_reader(reader);
-    for result in rdr.deserialize() {
-        // Notice that we need to provide a type hint for automatic
-        // deserialization.
-        let mut record: tick::Tick = result?;
-        record.dt = tick::get_time(record.nTime);
-        res.push(record);
-    }
-    Ok(res)
+// 空头的盈亏，未平仓的按当前价计算，已平仓的扣除税费和融券费用
+// 订单的盈亏，卖完后扣除税费，没有卖完的部分按price计算
+fn order_pnl(order: &order, price: u64) -> i128 {
+    if order.left > 0 {
+        order.profit + order.left as i128 * price as i128
+            - order.open_price as i128 * order.volume as i128
+    } else {
+        order.profit - (order.tax + order.commission) as i128
+    }
+}
+
+// 融券的盈亏，未平仓的部分按price计算
+fn short_pnl(s: &short, price: u64, dt: DateTime<FixedOffset>) -> i128 {
+    if s.left > 0 {
+        s.open_price as i128 * s.volume as i128 + s.profit
+            - s.left as i128 * price as i128
+            - s.fee_until(dt) as i128
+    } else {
+        s.profit - (s.tax + s.commission + s.fee) as i128
+    }
+}
+
+// 记录订单卖出volume股，全部卖完时计算盈亏和税费
+pub(crate) fn record_sell(order: &mut order, volume: u64, price: u64, dt: DateTime<FixedOffset>) {
+    order.profit += volume as i128 * price as i128; // 先计算总的收入
+    order.left -= volume as usize;
+    order.sell_volume = order.sell_volume.saturating_sub(volume as usize);
+    if order.left > 0 {
+        return;
+    }
+    order.sell_price_avg = order.profit as u64 / order.volume as u64; // 算出平均卖价
+    order.profit -= order.open_price as i128 * order.volume as i128; // 减去买入成本
+    order.tax = order.sell_price_avg * order.volume as u64 / 1000; // 减去印花税 1/1000
+    order.commission = max(order.sell_price_avg * order.volume as u64 * 3 / 10000, 50000)
+        + max(order.open_price * order.volume as u64 * 3 / 10000, 50000); // 减去佣金 3/10000
+    order.sell_volume = 0;
+    order.selt_time = dt;
+}
+
+// 改单生效，volume为0表示撤单
+fn apply_amend(
+    order: &mut order,
+    now: DateTime<FixedOffset>,
+    price: u64,
+    volume: usize,
+    market: bool,
+) {
+    order.sell_price = price;
+    order.sell_volume = volume.min(order.left);
+    order.sell_arrive = now;
+    order.want_sell_all = market;
+    order.cancelled = volume == 0;
 }
 
 impl Display for order {
@@ -155,27 +417,101 @@ impl StockSys {
         for order in lose_orders {
             info!("{}", order);
         }
+        if self.margin.conf.enabled {
+            let mut short_profit: i128 = 0;
+            let mut short_cost: u64 = 0;
+            for s in &self.shorts {
+                short_profit += s.profit;
+                short_cost += s.tax + s.commission + s.fee;
+            }
+            info!("short profit :{}", short_profit);
+            info!(
+                "short profit with tax commission fee:{}",
+                short_profit - short_cost as i128
+            );
+            for s in &self.shorts {
+                info!("{}", s);
+            }
+        }
+        for (code, pos) in &self.positions {
+            info!("position {} {}", code, pos);
+        }
+        let mut total = attribution::Attribution::default();
+        info!("attribution:");
+        for order in &self.orders {
+            if let Some(a) = attribution::of(order) {
+                info!("buy time:{} {}", order.time, a);
+                total.add(&a);
+            }
+        }
+        info!("attribution total: {}", total);
+        self.benchmark_statistics();
+        info!("risk breaches:");
+        for (breach, count) in &self.risk.breaches {
+            info!("{}: {}", breach, count);
+        }
     }
-    // 刷新最大涨幅
-    fn get_gap(&mut self, tick: &tick::Tick) {
-        let mut min = MAX;
-        let mut min_idx: usize = 0;
-
-        if self.gap_window.len() == 0 {
+    // 和基准比较收益
+    fn benchmark_statistics(&self) {
+        let conf = &self.conf.benchmark;
+        // 没有配置本金时按开盘价买入buy_volume股的金额
+        let capital = |open: u64| {
+            if conf.capital > 0 {
+                conf.capital
+            } else {
+                open * self.conf.buy_volume as u64
+            }
+        };
+        if conf.index_data.is_empty() {
+            for (code, h) in &self.hold {
+                let capital = capital(h.open);
+                match benchmark::compare(&self.equity, &h.prices, h.open, capital) {
+                    Some(c) => info!("benchmark buy and hold {} capital {}: {}", code, capital, c),
+                    None => info!("benchmark buy and hold {}: not enough data to compare", code),
+                }
+            }
             return;
         }
-        // gap rate = now price - min price / min price
-
-        for (idx, i) in self.gap_window.iter().enumerate() {
-            if min > i.nPrice {
-                min = i.nPrice;
-                min_idx = idx;
+        let index = match benchmark::read_index(
+            &conf.index_data,
+            self.conf.read_ahead,
+            &self.conf.schema,
+            self.conf.date(),
+        ) {
+            Ok(index) => index,
+            Err(e) => {
+                error!("read benchmark {} failed: {}", conf.index_data, e);
+                return;
             }
+        };
+        let prices: Vec<Option<u64>> = self
+            .equity
+            .iter()
+            .map(|(dt, _)| benchmark::price_at(&index, *dt))
+            .collect();
+        let base = index.first().map(|(_, p)| *p).unwrap_or(0);
+        // 指数没有开盘价，本金按第一个股票计算
+        let capital = capital(self.hold.values().next().map(|h| h.open).unwrap_or(0));
+        match benchmark::compare(&self.equity, &prices, base, capital) {
+            Some(c) => info!("benchmark {} capital {}: {}", conf.index_data, capital, c),
+            None => info!("benchmark {}: not enough data to compare", conf.index_data),
+        }
+    }
+    // 刷新最大涨幅和最大跌幅
+    // gap rate = now price - min price / min price
+    fn get_gap(&mut self, tick: &tick::Tick) {
+        if let Some((_, min)) = self.gap_window.min() {
+            // 价格为0的脏数据不能作为除数
+            if min == 0 {
+                return;
+            }
+            self.min = min;
+            self.gap_rate = (tick.nPrice as f64 - self.min as f64) / self.min as f64;
+        }
+        // drop rate = max price - now price / max price
+        if self.max > 0 && self.max != MIN {
+            self.drop_rate = (self.max as f64 - tick.nPrice as f64) / self.max as f64;
         }
-
-        self.min = min;
-        self.gap_window.drain(..min_idx);
-        self.gap_rate = (tick.nPrice as f64 - self.min as f64) / self.min as f64;
         //debug!(
         //    "{} new gap_rate:{} price {} min {}",
         //    tick.dt, self.gap_rate, tick.nPrice, self.min
@@ -185,143 +521,795 @@ impl StockSys {
     // 1. 是否在交易时间段
     // TODO:暂时不考虑T+0限制
     fn can_trade(&self, tick: &tick::Tick) -> bool {
-        return (tick.dt >= *tick::START_TIME_MORNINIG && tick.dt <= *tick::END_TIME_MORNINIG)
-            || (tick.dt >= *tick::START_TIME_AFTERNOON && tick.dt <= *tick::END_TIME_AFTERNOON);
+        let t = tick::TimeOfDay::of(tick.dt);
+        return (t >= *tick::START_TIME_MORNINIG && t <= *tick::END_TIME_MORNINIG)
+            || (t >= *tick::START_TIME_AFTERNOON && t <= *tick::END_TIME_AFTERNOON);
     }
 
     pub fn do_strategy(&mut self, tick: &tick::Tick) {
+        self.book_used.remove(&tick.chWindCode);
+        let finished = match self.bar_kind {
+            Some(kind) => self
+                .bar_aggregators
+                .entry(tick.chWindCode.clone())
+                .or_insert_with(|| bar::new_bar_aggregator(kind))
+                .update(tick),
+            None => None,
+        };
+        if let Some(bar) = finished {
+            self.on_bar(bar);
+        }
+        if self.conf.indicator_source == indicator::Source::Tick && !self.conf.buy_filters.is_empty() {
+            self.indicators(&tick.chWindCode).update_tick(tick);
+        }
+        self.filter.update(tick);
+        if !self.hold.contains_key(&tick.chWindCode) {
+            let open = if tick.Open > 0 { tick.Open } else { tick.nPrice };
+            self.hold.insert(
+                tick.chWindCode.clone(),
+                benchmark::new_buy_and_hold(open, self.equity.len()),
+            );
+        }
         if self.can_trade(tick) {
             self.update_gap(tick);
+            let exposure = self.exposure(tick, false);
+            self.risk.update_daily_pnl(tick.dt, exposure.daily_pnl);
+            if let Some(pending) = self.take_pending(&tick.chWindCode, tick.dt, false) {
+                self.buy(tick, None, pending.arrive, pending.signal_mid, pending.volume);
+            }
             self.process_order(tick);
+            self.update_equity(tick);
+        }
+        self.trans.clear();
+        self.last_ticks.insert(tick.chWindCode.clone(), tick.clone());
+    }
+    // 处理事件队列中到期的事件
+    pub fn on_action(&mut self, time: DateTime<FixedOffset>, action: Action) {
+        match action {
+            Action::Ack(idx) => debug!("{} order {} acknowledged", time, idx),
+            Action::Fill {
+                order,
+                buy,
+                price,
+                volume,
+            } => {
+                debug!(
+                    "{} order {} {} {} at price {}",
+                    time,
+                    order,
+                    if buy { "buy" } else { "sell" },
+                    volume,
+                    price
+                );
+                // 买入成交后，在卖出时间和全部卖出时间检查一次，不用等下一个tick
+                if buy {
+                    let start = self.orders[order].time + Duration::seconds(self.conf.sell_delay_time);
+                    let tick = Duration::milliseconds(1);
+                    self.events
+                        .schedule(start + tick, Action::Timer(Timer::SellCheck(order)));
+                    self.events.schedule(
+                        start + Duration::seconds(self.conf.sell_all_delay) + tick,
+                        Action::Timer(Timer::SellCheck(order)),
+                    );
+                }
+            }
+            Action::Amend {
+                order,
+                price,
+                volume,
+                market,
+            } => {
+                let o = &mut self.orders[order];
+                o.amending = false;
+                // 撤单或改单到达之前已经全部成交
+                if o.left == 0 {
+                    debug!("{} amend of order {} rejected: already filled", time, order);
+                    return;
+                }
+                debug!(
+                    "{} amend order {} to price {} volume {} market {}",
+                    time, order, price, volume, market
+                );
+                apply_amend(o, time, price, volume, market);
+            }
+            Action::Timer(Timer::SellCheck(idx)) => {
+                if self.orders[idx].left == 0 {
+                    return;
+                }
+                // 两个tick之间没有新的盘口，按最新的tick处理
+                let mut tick = match self.last_ticks.get(&self.orders[idx].code) {
+                    Some(tick) => tick.clone(),
+                    None => return,
+                };
+                tick.dt = time;
+                tick.nTime = tick::ntime_of(time);
+                // 只检查这个订单，盘口中已经成交掉的部分不能再用
+                if self.can_trade(&tick) {
+                    debug!("{} timer check sell for order {}", time, idx);
+                    self.sell_order(idx, &tick);
+                }
+            }
+        }
+    }
+    // 逐笔成交先于包含它的tick到达
+    pub fn on_transaction(&mut self, t: &transaction::transaction) {
+        self.trans.push(t.clone());
+        // 委托到达后的第一笔主动买入成交早于下一个tick时，按成交价撮合
+        // 撤单和主动卖出的成交价格不是卖盘价格，不能用来撮合
+        if t.is_cancel() || t.BSFlag != 'B' || t.Volume == 0 {
+            return;
+        }
+        let mut tick = match self.last_ticks.get(&t.Tkr) {
+            Some(tick) => tick.clone(),
+            None => return,
+        };
+        tick.dt = t.dt;
+        tick.nTime = t.Time;
+        tick.nPrice = t.Price;
+        if !self.can_trade(&tick) {
+            return;
+        }
+        if let Some(mut pending) = self.take_pending(&t.Tkr, t.dt, true) {
+            let filled = self.buy(&tick, Some(t), pending.arrive, pending.signal_mid, pending.volume);
+            // 成交量不够时剩下的部分仍然挂着，用下一个快照的卖盘撮合，风控拒绝的不再挂
+            if let Some(filled) = filled.filter(|f| *f < pending.volume) {
+                pending.volume -= filled;
+                pending.rest = true;
+                debug!(
+                    "{} buy order of {} left {} after print {} at {}",
+                    t.dt, t.Tkr, pending.volume, t.Volume, t.Price
+                );
+                self.pending_buys.push(pending);
+            }
+        }
+    }
+    // 撤销订单的卖单，撤单到达交易所之前卖单仍然可能成交
+    // 撤单后不再按订单改单，只有按持仓止盈或离场时才重新挂单
+    // 在卖出时间之前撤销的，到了卖出时间照常挂单
+    pub fn cancel_sell(&mut self, idx: usize, now: DateTime<FixedOffset>) {
+        self.amend_sell(idx, now, 0, 0, false);
+    }
+    // 修改订单的卖单价格和数量，market为true时以任意买价卖出
+    // 改单经过cancel延迟后生效，在此之前按原来的价格和数量撮合
+    pub fn amend_sell(
+        &mut self,
+        idx: usize,
+        now: DateTime<FixedOffset>,
+        price: u64,
+        volume: usize,
+        market: bool,
+    ) {
+        let order = &mut self.orders[idx];
+        if order.left == 0 {
+            return;
+        }
+        let cancel = self.conf.latency.cancel();
+        if cancel.is_zero() {
+            apply_amend(order, now, price, volume, market);
+            return;
+        }
+        order.amending = true;
+        self.events.schedule(
+            now + cancel,
+            Action::Amend {
+                order: idx,
+                price,
+                volume,
+                market,
+            },
+        );
+    }
+    // 取出已经到达交易所的买单，print为true时不取逐笔成交撮合后剩下的部分
+    fn take_pending(
+        &mut self,
+        code: &str,
+        now: DateTime<FixedOffset>,
+        print: bool,
+    ) -> Option<PendingBuy> {
+        let idx = self
+            .pending_buys
+            .iter()
+            .position(|p| p.code == code && p.arrive <= now && !(print && p.rest))?;
+        let pending = self.pending_buys.remove(idx);
+        debug!(
+            "{} buy order of {} arrived at {}, signal at {}",
+            now, code, pending.arrive, pending.signal
+        );
+        Some(pending)
+    }
+    // 按采样间隔记录累计盈亏（已平仓扣除税费 + 未平仓浮动盈亏）
+    fn update_equity(&mut self, tick: &tick::Tick) {
+        if let Some((dt, _)) = self.equity.last() {
+            if tick.dt - *dt < Duration::seconds(self.conf.equity_interval) {
+                return;
+            }
+        }
+        let pnl = self.total_pnl(tick);
+        self.equity.push((tick.dt, pnl));
+        let prices: Vec<Option<u64>> = self.hold.keys().map(|code| self.mark(code, tick)).collect();
+        for (h, price) in self.hold.values_mut().zip(prices) {
+            h.prices.push(price);
+        }
+    }
+    // 一根K线完成时触发，按K线交易的策略在这里处理
+    fn on_bar(&mut self, bar: bar::Bar) {
+        debug!("new bar {}", bar);
+        if self.conf.indicator_source == indicator::Source::Bar && !self.conf.buy_filters.is_empty() {
+            self.indicators(&bar.code).update_bar(&bar);
+        }
+        self.bars.push(bar);
+    }
+    // 数据回放结束，按代码顺序处理每个代码未完成的K线
+    pub fn finish(&mut self) {
+        let finished: Vec<bar::Bar> = self
+            .bar_aggregators
+            .values_mut()
+            .filter_map(|aggregator| aggregator.flush())
+            .collect();
+        for bar in finished {
+            self.on_bar(bar);
+        }
+    }
+    // 每个代码最新的价格，当前tick的代码用当前价
+    fn mark(&self, code: &str, tick: &tick::Tick) -> Option<u64> {
+        if code == tick.chWindCode {
+            return Some(tick.nPrice);
         }
+        self.last_ticks.get(code).map(|t| t.nPrice)
+    }
+    // 去掉已经卖完的订单和已经平仓的融券，它们的盈亏不会再变化
+    fn prune_closed(&mut self) {
+        let (orders, shorts) = (&self.orders, &self.shorts);
+        let mut closed = 0;
+        self.open_orders.retain(|idx| {
+            let open = orders[*idx].left > 0;
+            if !open {
+                closed += order_pnl(&orders[*idx], 0);
+            }
+            open
+        });
+        self.open_shorts.retain(|idx| {
+            let s = &shorts[*idx];
+            let open = s.left > 0;
+            if !open {
+                closed += short_pnl(s, 0, s.cover_time);
+            }
+            open
+        });
+        self.closed_pnl += closed;
+    }
+    // 所有订单和融券的总盈亏，未平仓的按各自代码最新的价格计算
+    fn total_pnl(&mut self, tick: &tick::Tick) -> i128 {
+        self.prune_closed();
+        let mut pnl = self.closed_pnl;
+        for idx in &self.open_orders {
+            let order = &self.orders[*idx];
+            pnl += order_pnl(order, self.mark(&order.code, tick).unwrap_or(order.open_price));
+        }
+        for idx in &self.open_shorts {
+            let s = &self.shorts[*idx];
+            pnl += short_pnl(s, self.mark(&s.code, tick).unwrap_or(s.open_price), tick.dt);
+        }
+        self.last_pnl = pnl;
+        pnl
+    }
+    // 当日盈亏 = 总盈亏 - 前一个交易日结束时的总盈亏
+    // 包括当天平仓的盈亏和未平仓部分当天的浮动盈亏，不包括以前交易日的浮动盈亏
+    fn daily_pnl(&mut self, tick: &tick::Tick) -> i128 {
+        let today = tick.dt.date_naive();
+        let start = match self.day_start {
+            Some((date, pnl)) if date == today => pnl,
+            _ => {
+                // 新的交易日，上一次计算的总盈亏就是前一个交易日结束时的
+                self.day_start = Some((today, self.last_pnl));
+                self.last_pnl
+            }
+        };
+        self.total_pnl(tick) - start
+    }
+    // 统计持仓，供风控检查，short为true时统计融券的订单数和持仓
+    // 总敞口按各自代码最新的价格计算
+    fn exposure(&mut self, tick: &tick::Tick, short: bool) -> risk::Exposure {
+        let daily_pnl = self.daily_pnl(tick);
+        let mut exposure = risk::Exposure {
+            open_orders: 0,
+            position: 0,
+            gross: 0,
+            daily_pnl,
+        };
+        for idx in &self.open_orders {
+            let order = &self.orders[*idx];
+            if !short && order.code == tick.chWindCode {
+                exposure.open_orders += 1;
+                exposure.position += order.left as u64;
+            }
+            exposure.gross +=
+                order.left as u64 * self.mark(&order.code, tick).unwrap_or(order.open_price);
+        }
+        for idx in &self.open_shorts {
+            let s = &self.shorts[*idx];
+            if short && s.code == tick.chWindCode {
+                exposure.open_orders += 1;
+                exposure.position += s.left as u64;
+            }
+            exposure.gross += s.left as u64 * self.mark(&s.code, tick).unwrap_or(s.open_price);
+        }
+        exposure
+    }
+    // 当前tick的涨跌停价格区间
+    fn price_limit(&self, tick: &tick::Tick) -> instrument::PriceLimit {
+        let st = self.conf.st_symbols.contains(&tick.chWindCode);
+        instrument::price_limit(tick, st)
+    }
+    // 这个代码的指标，配置已经在new_stock_sys中检查过
+    fn indicators(&mut self, code: &str) -> &mut indicator::IndicatorSet {
+        let (filters, source) = (&self.conf.buy_filters, self.conf.indicator_source);
+        self.indicators
+            .entry(code.to_string())
+            .or_insert_with(|| indicator::new_indicator_set(filters, source).unwrap())
+    }
+    // 这个代码最新快照中已经被自己的订单成交掉的数量
+    fn usage(&mut self, code: &str) -> &mut BookUsage {
+        self.book_used
+            .entry(code.to_string())
+            .or_default()
+    }
+    // 买入数量必须符合交易单位，否则交易所会拒单，为0时不下单
+    fn buy_volume(&self, tick: &tick::Tick) -> u64 {
+        let rule = instrument::board_of(&tick.chWindCode).lot_rule();
+        let volume = rule.round_buy(self.conf.buy_volume as u64);
+        if volume == 0 {
+            warn!(
+                "{} buy volume {} is less than min lot {} of {}",
+                tick.dt, self.conf.buy_volume, rule.min, tick.chWindCode
+            );
+        } else if !rule.is_valid_buy(self.conf.buy_volume as u64) {
+            debug!(
+                "{} round buy volume {} to {} for {}",
+                tick.dt, self.conf.buy_volume, volume, tick.chWindCode
+            );
+        }
+        volume
     }
     // 下单逻辑，买单需要考虑卖单的数量能否撮合
-    // TODO:价格是否应该参考trans里的内容
-    fn buy(&mut self, tick: &tick::Tick) {
+    // print不为空时，委托到达后先遇到的是逐笔成交，按成交价和成交量撮合
+    // arrive为委托到达交易所的时间，signal_mid为产生信号时的中间价
+    // 返回成交的数量，风控拒绝时返回None
+    fn buy(
+        &mut self,
+        tick: &tick::Tick,
+        print: Option<&transaction::transaction>,
+        arrive: DateTime<FixedOffset>,
+        signal_mid: u64,
+        volume: u64,
+    ) -> Option<u64> {
+        let rule = instrument::board_of(&tick.chWindCode).lot_rule();
+        // 所有订单都需要通过风控检查
+        let exposure = self.exposure(tick, false);
+        if !self.risk.check_buy(tick, volume, &exposure) {
+            return None;
+        }
+        // 按卖1~卖10依次撮合，超出涨跌停范围的价格不能成交
+        let limit = self.price_limit(tick);
         let mut value: u64 = 0;
-        let mut left: u64 = self.conf.buy_volume as u64;
-        for (p, v) in [
-            (tick.nAskPrice1, tick.nAskVolume1),
-            (tick.nAskPrice2, tick.nAskVolume2),
-            (tick.nAskPrice3, tick.nAskVolume3),
-            (tick.nAskPrice4, tick.nAskVolume4),
-            (tick.nAskPrice5, tick.nAskVolume5),
-            (tick.nAskPrice6, tick.nAskVolume6),
-            (tick.nAskPrice7, tick.nAskVolume7),
-            (tick.nAskPrice8, tick.nAskVolume8),
-            (tick.nAskPrice9, tick.nAskVolume9),
-            (tick.nAskPrice10, tick.nAskVolume10),
-        ]
-        .iter()
-        {
-            if left <= *v {
-                debug!("{} buy at {} price {} ", tick.dt, left, p);
-                value += left * (*p);
+        let mut left: u64 = volume;
+        // 逐笔成交不够全部成交时，成交的部分按交易单位取整，不足一手的不成交
+        let levels: Vec<(u64, u64)> = match print {
+            Some(t) if t.Volume >= volume => vec![(t.Price, volume)],
+            Some(t) => vec![(t.Price, rule.round_buy(t.Volume))],
+            None => self.usage(&tick.chWindCode).asks(tick).to_vec(),
+        };
+        let mut fills = Vec::new();
+        for (p, v) in levels.iter() {
+            if *p == 0 || *v == 0 || !limit.contains(*p) {
+                continue;
+            }
+            let filled = left.min(*v);
+            debug!("{} buy at {} price {}", tick.dt, filled, p);
+            value += filled * *p;
+            left -= filled;
+            fills.push((*p, filled));
+            if left == 0 {
                 break;
-            } else {
-                left = left - *v;
-                debug!("{} buy at {} price {}", tick.dt, *v, p);
-                value += *v * *p;
+            }
+        }
+        // 卖盘不足时只成交一部分
+        let volume = volume - left;
+        if volume == 0 {
+            debug!("{} no ask to buy for {}", tick.dt, tick.chWindCode);
+            return Some(0);
+        }
+        // 按逐笔成交撮合时没有用到快照的卖盘
+        if print.is_none() {
+            let usage = self.usage(&tick.chWindCode);
+            for (p, v) in fills {
+                usage.use_ask(p, v);
             }
         }
 
+        self.open_orders.push(self.orders.len());
         self.orders.push(order {
-            open_price: value / self.conf.buy_volume as u64,
-            time: tick::get_time(tick.nTime),
-            volume: self.conf.buy_volume,
+            code: tick.chWindCode.clone(),
+            open_price: value / volume,
+            time: tick.dt,
+            volume: volume as usize,
             sell_price: 0,
-            left: self.conf.buy_volume,
+            left: volume as usize,
             profit: 0,
             sell_price_avg: 0,
             tax: 0,
             commission: 0,
+            signal_mid,
+            exit_mid: 0,
+            sell_arrive: tick::default_dt(),
+            sell_volume: 0,
+            amending: false,
+            cancelled: false,
             want_sell_all: false,
             selt_time: tick::default_dt(),
         });
+        let idx = self.orders.len() - 1;
+        self.positions
+            .entry(tick.chWindCode.clone())
+            .or_default()
+            .add(idx, volume, value / volume, tick.dt);
+        self.rearm_targets(&tick.chWindCode, tick.dt);
+        self.events
+            .schedule(arrive + self.conf.latency.ack(), Action::Ack(idx));
+        self.events.schedule(
+            tick.dt + self.conf.latency.ack(),
+            Action::Fill {
+                order: idx,
+                buy: true,
+                price: value / volume,
+                volume,
+            },
+        );
         //self.last_buy_order = self.orders.len() - 1;
         self.gap_window.clear();
         self.min = MAX;
         self.max = MIN;
+        self.drop_rate = 0.0;
         //self.min_idx = 0;
         //self.max_idx = 0;
+        Some(volume)
     }
 
     // TODO:暂时不考虑买卖影响股价，不拆分订单
+    // 只检查这个代码的未卖完订单
     fn sell(&mut self, tick: &tick::Tick) {
+        for idx in 0..self.orders.len() {
+            if self.orders[idx].code == tick.chWindCode && self.orders[idx].left > 0 {
+                self.sell_order(idx, tick);
+            }
+        }
+    }
+    // 检查一个订单是否要挂单、改单，并用盘口中剩下的买单撮合
+    // 到了卖出时间按订单卖出，止盈等按持仓挂出的卖单在到达交易所后也在这里撮合
+    fn sell_order(&mut self, idx: usize, tick: &tick::Tick) {
+        let rule = instrument::board_of(&tick.chWindCode).lot_rule();
+        let limit = self.price_limit(tick);
+        let cancel = self.conf.latency.cancel();
+        // 有撤单延迟时，改单在撮合之后发出
+        let mut amend = None;
+        let order = &mut self.orders[idx];
+        if order.left == 0 {
+            return;
+        }
         // 挂单卖出不用考虑跌停的情况
-        for order in &mut self.orders {
-            if tick.dt - order.time > Duration::seconds(self.conf.sell_delay_time) && order.left > 0
+        // 下跌离场时不用等到卖出时间
+        if tick.dt - order.time > Duration::seconds(self.conf.sell_delay_time) || order.want_sell_all {
+            // 以卖1挂卖单，涨停封板时卖1为空，挂在涨停价
+            let ask = if tick.nAskPrice1 == 0 {
+                limit.high
+            } else {
+                limit.clamp(tick.nAskPrice1)
+            };
+            let delay = Duration::seconds(self.conf.sell_delay_time);
+            if order.sell_price == 0 && (!order.cancelled || order.sell_arrive - order.time <= delay) {
+                debug!(
+                    "{} begin to sell at price:{} (buy time:{}) after {}s",
+                    tick.dt, tick.nAskPrice1, order.time, self.conf.sell_delay_time
+                );
+                order.sell_price = ask;
+                order.sell_arrive = tick.dt + self.conf.latency.submit();
+                order.sell_volume = order.left;
+                order.cancelled = false;
+                if order.exit_mid == 0 {
+                    order.exit_mid = tick.mid_price();
+                }
+            }
+            // 超过时间没有卖完，需要尽量卖出
+            // 这里的想法是从当前tick开始，尝试所有的买单，直到卖完
+            // TODO: 是否成交需要参考trans
+            if tick.dt - order.time
+                > Duration::seconds(self.conf.sell_all_delay)
+                    + Duration::seconds(self.conf.sell_delay_time)
+                && !order.want_sell_all
+                && !order.amending
+                && !order.cancelled
             {
-                if order.sell_price == 0 {
-                    debug!(
-                        "{} begin to sell at price:{} (buy time:{}) after {}s",
-                        tick.dt, tick.nAskPrice1, order.time, self.conf.sell_delay_time
-                    );
-                    order.sell_price = tick.nAskPrice1; // 以卖1挂卖单
+                debug!(
+                    "{} change price to {} (buy time:{}) to sell left {} after {}s",
+                    tick.dt, tick.nPrice, order.time, order.left, self.conf.sell_all_delay
+                );
+                amend = Some((limit.clamp(tick.nPrice), order.left, true));
+            } else if !order.want_sell_all
+                && !order.amending
+                && !order.cancelled
+                && order.sell_volume < order.left
+            {
+                // 止盈只挂出了一部分，到了卖出时间剩下的也要卖出
+                debug!(
+                    "{} sell left {} of order {} at price {} after {}s",
+                    tick.dt, order.left, idx, ask, self.conf.sell_delay_time
+                );
+                amend = Some((ask.min(order.sell_price), order.left, false));
+            } else if self.conf.sell_follow_ask
+                && !order.want_sell_all
+                && !order.amending
+                && !order.cancelled
+                && order.sell_volume > 0
+                && tick.nAskPrice1 > 0
+                && limit.clamp(tick.nAskPrice1) < order.sell_price
+            {
+                debug!(
+                    "{} follow ask price {} from {} to sell left {}",
+                    tick.dt, tick.nAskPrice1, order.sell_price, order.left
+                );
+                amend = Some((limit.clamp(tick.nAskPrice1), order.left, false));
+            }
+            if cancel.is_zero() {
+                if let Some((price, volume, market)) = amend.take() {
+                    apply_amend(order, tick.dt, price, volume, market);
+                }
+            }
+        }
+        // 卖单还没有到达交易所或者已经撤单，跌停封板时买盘为空，卖不出
+        if tick.dt >= order.sell_arrive && order.sell_volume > 0 && !limit.is_sealed_down(tick) {
+            // 尝试所有的买价，争取一次卖出，已经被其他订单成交掉的部分不能再用
+            let bids = self.usage(&tick.chWindCode).bids(tick);
+            for (p, v) in bids.iter() {
+                let order = &self.orders[idx];
+                if order.sell_volume == 0 {
+                    break;
+                }
+                // 空档位和超出涨跌停范围的价格不能成交
+                if *p == 0 || *v == 0 || !limit.contains(*p) {
+                    continue;
                 }
-                // 超过时间没有卖完，需要尽量卖出
-                // 这里的想法是从当前tick开始，尝试所有的买单，直到卖完
-                // TODO: 是否成交需要参考trans
-                if tick.dt - order.time
-                    > Duration::seconds(self.conf.sell_all_delay)
-                        + Duration::seconds(self.conf.sell_delay_time)
-                    && order.want_sell_all == false
-                {
+                if *p < order.sell_price && !order.want_sell_all {
+                    continue;
+                }
+                // 部分成交也要按整手卖出，零股只能在最后一次性卖出
+                let offer = (*v).min(order.sell_volume as u64);
+                let volume = rule.round_sell(offer, order.left as u64);
+                if volume == 0 {
+                    continue;
+                }
+                debug!(
+                    "{} sell {} price {} want {}",
+                    tick.dt, volume, p, order.want_sell_all
+                );
+                self.fill_sell(idx, volume, *p, tick.dt);
+                if self.orders[idx].left == 0 {
+                    debug!("{} sell order:{}", tick.dt, self.orders[idx]);
+                }
+            }
+        }
+        if let Some((price, volume, market)) = amend {
+            self.amend_sell(idx, tick.dt, price, volume, market);
+        }
+    }
+    // 卖出成交，订单和所属的持仓只在这里更新
+    fn fill_sell(&mut self, idx: usize, volume: u64, price: u64, dt: DateTime<FixedOffset>) {
+        let order = &mut self.orders[idx];
+        record_sell(order, volume, price, dt);
+        if let Some(pos) = self.positions.get_mut(&order.code) {
+            pos.reduce(volume, price);
+        }
+        let code = order.code.clone();
+        self.usage(&code).use_bid(price, volume);
+        self.events.schedule(
+            dt + self.conf.latency.ack(),
+            Action::Fill {
+                order: idx,
+                buy: false,
+                price,
+                volume,
+            },
+        );
+    }
+    // 融券卖出的判断方法，和买入对称：
+    // 从时间窗最高价下跌超过阈值，只有融券标的可以卖空
+    // 跌停封板时不能卖空，两次卖空的间隔和买入一样使用 buy_cooldown_time
+    fn can_short(&self, tick: &tick::Tick) -> bool {
+        if !self.has_direction(Direction::ShortGapDown)
+            || !self.margin.is_marginable(&tick.chWindCode)
+            || self.price_limit(tick).is_sealed_down(tick)
+            || self.margin.conf.short_point >= self.drop_rate
+        {
+            return false;
+        }
+        if let Err(reason) = self.filter.check(tick, false) {
+            debug!(
+                "{} will not short price {} when {}",
+                tick.dt, tick.nPrice, reason
+            );
+            return false;
+        }
+        match self.shorts.last() {
+            Some(s) => {
+                let short = tick.dt - s.time > Duration::seconds(self.conf.buy_cooldown_time);
+                if short {
                     debug!(
-                        "{} change price to {} (buy time:{}) to sell left {} after {}s",
-                        tick.dt, tick.nPrice, order.time, order.left, self.conf.sell_all_delay
+                        "{} will short (max price {}, drop {}) price {}",
+                        tick.dt, self.max, self.drop_rate, tick.nPrice
                     );
-                    order.sell_price = tick.nPrice;
-                    order.want_sell_all = true;
                 }
-                // 尝试所有的卖价，争取一次卖出
-                for (p, v) in [
-                    (tick.nBidPrice1, tick.nBidVolume1),
-                    (tick.nBidPrice2, tick.nBidVolume2),
-                    (tick.nBidPrice3, tick.nBidVolume3),
-                    (tick.nBidPrice4, tick.nBidVolume4),
-                    (tick.nBidPrice5, tick.nBidVolume5),
-                    (tick.nBidPrice6, tick.nBidVolume6),
-                    (tick.nBidPrice7, tick.nBidVolume7),
-                    (tick.nBidPrice8, tick.nBidVolume8),
-                    (tick.nBidPrice9, tick.nBidVolume9),
-                    (tick.nBidPrice10, tick.nBidVolume10),
-                ]
-                .iter()
-                {
-                    // 跌停了不再交易
-                    if tick.nPrice == tick.LowLimited * 10 && order.want_sell_all {
-                        break;
-                    }
-                    if *p >= order.sell_price || order.want_sell_all {
-                        if *v >= order.left as u64 {
-                            debug!(
-                                "{} sell {} price {} want {}",
-                                tick.dt, order.left, p, order.want_sell_all
-                            );
-                            order.profit += order.left as i128 * *p as i128; // 先计算总的收入
-                            order.sell_price_avg = order.profit as u64 / order.volume as u64; // 算出平均卖价
-                            order.profit -= order.open_price as i128 * order.volume as i128; // 减去买入成本
-                            order.tax = order.sell_price_avg * order.volume as u64 / 1000; // 减去印花税 1/1000
-                            order.commission =
-                                max(
-                                    order.sell_price_avg * order.volume as u64 * 3 / 10000,
-                                    50000,
-                                ) + max(order.open_price * order.volume as u64 * 3 / 10000, 50000); // 减去佣金 3/10000
-                            order.left = 0;
-                            order.selt_time = tick.dt;
-                            debug!("{} sell order:{}", tick.dt, order);
-                            break;
-                        } else {
-                            debug!(
-                                "{} sell {} price {} want {}",
-                                tick.dt, v, p, order.want_sell_all
-                            );
-                            order.profit += *v as i128 * *p as i128;
-                            order.left -= *v as usize;
-                        }
-                        //debug!("sell order:{:?}", order);
-                    }
+                short
+            }
+            None => {
+                debug!(
+                    "{} first short price {} when drop is {}",
+                    tick.dt, tick.nPrice, self.drop_rate
+                );
+                true
+            }
+        }
+    }
+    // 融券卖出，按买1~买10依次撮合
+    // 融券卖出的价格不能低于最新成交价
+    fn short(&mut self, tick: &tick::Tick) {
+        let rule = instrument::board_of(&tick.chWindCode).lot_rule();
+        let available = self.margin.available(&tick.chWindCode);
+        let volume = rule.round_buy((self.margin.conf.short_volume as u64).min(available));
+        if volume == 0 {
+            debug!(
+                "{} no stock to borrow for {}, available {}",
+                tick.dt, tick.chWindCode, available
+            );
+            return;
+        }
+        let exposure = self.exposure(tick, true);
+        if !self.risk.check_short(tick, volume, &exposure) {
+            return;
+        }
+        let limit = self.price_limit(tick);
+        let mut value: u64 = 0;
+        let mut left: u64 = volume;
+        let mut fills = Vec::new();
+        for (p, v) in self.usage(&tick.chWindCode).bids(tick).iter() {
+            if *p == 0 || *v == 0 || !limit.contains(*p) || *p < tick.nPrice {
+                continue;
+            }
+            let filled = left.min(*v);
+            debug!("{} short at {} price {}", tick.dt, filled, p);
+            value += filled * *p;
+            left -= filled;
+            fills.push((*p, filled));
+            if left == 0 {
+                break;
+            }
+        }
+        // 买盘不足时只成交一部分，成交数量按整手取整
+        let filled = volume - left;
+        let volume = rule.round_buy(filled);
+        if volume == 0 {
+            debug!("{} no bid to short for {}", tick.dt, tick.chWindCode);
+            return;
+        }
+        let price = value / filled;
+        let margin = self.margin.required(price * volume);
+        if !self.margin.can_lock(margin) {
+            warn!(
+                "{} short margin {} + {} exceeds max margin {}",
+                tick.dt, self.margin.margin, margin, self.margin.conf.max_margin
+            );
+            return;
+        }
+        self.margin.borrow(&tick.chWindCode, volume, margin);
+        // 成交按整手取整后，多出的部分从最后的档位退回
+        let mut extra = filled - volume;
+        let usage = self.usage(&tick.chWindCode);
+        for (p, v) in fills.into_iter().rev() {
+            let back = extra.min(v);
+            extra -= back;
+            usage.use_bid(p, v - back);
+        }
+        self.open_shorts.push(self.shorts.len());
+        self.shorts.push(short {
+            code: tick.chWindCode.clone(),
+            open_price: price,
+            time: tick.dt,
+            cover_time: tick::default_dt(),
+            volume: volume as usize,
+            cover_price: 0,
+            want_cover_all: false,
+            cover_price_avg: 0,
+            left: volume as usize,
+            profit: 0,
+            tax: price * volume / 1000, // 卖出时收印花税 1/1000
+            commission: 0,
+            fee: 0,
+            margin,
+            rate: self.margin.rate(&tick.chWindCode),
+        });
+        self.gap_window.clear();
+        self.min = MAX;
+        self.max = MIN;
+        self.drop_rate = 0.0;
+    }
+    // 买券还券，和卖出对称：
+    // sell_delay_time之后以买1挂单，再过sell_all_delay没有买完时以任意卖价买入
+    fn cover(&mut self, tick: &tick::Tick) {
+        let rule = instrument::board_of(&tick.chWindCode).lot_rule();
+        let limit = self.price_limit(tick);
+        let usage = self
+            .book_used
+            .entry(tick.chWindCode.clone())
+            .or_default();
+        for s in self.shorts.iter_mut() {
+            if s.left == 0
+                || s.code != tick.chWindCode
+                || tick.dt - s.time <= Duration::seconds(self.conf.sell_delay_time)
+            {
+                continue;
+            }
+            if s.cover_price == 0 {
+                // 跌停封板时买1为空，挂在跌停价
+                s.cover_price = if tick.nBidPrice1 == 0 {
+                    limit.low
+                } else {
+                    limit.clamp(tick.nBidPrice1)
+                };
+                debug!(
+                    "{} begin to cover at price:{} (short time:{})",
+                    tick.dt, s.cover_price, s.time
+                );
+            }
+            if tick.dt - s.time
+                > Duration::seconds(self.conf.sell_all_delay)
+                    + Duration::seconds(self.conf.sell_delay_time)
+                && !s.want_cover_all
+            {
+                debug!(
+                    "{} change cover price to {} (short time:{}) to cover left {}",
+                    tick.dt, tick.nPrice, s.time, s.left
+                );
+                s.cover_price = limit.clamp(tick.nPrice);
+                s.want_cover_all = true;
+            }
+            // 涨停封板时卖盘为空，买不到
+            if limit.is_sealed_up(tick) {
+                continue;
+            }
+            for (p, v) in usage.asks(tick).iter() {
+                if *p == 0 || *v == 0 || !limit.contains(*p) {
+                    continue;
+                }
+                if *p > s.cover_price && !s.want_cover_all {
+                    continue;
+                }
+                let volume = rule.round_sell(*v, s.left as u64);
+                if volume == 0 {
+                    continue;
+                }
+                usage.use_ask(*p, volume);
+                debug!("{} cover {} price {}", tick.dt, volume, p);
+                s.profit -= volume as i128 * *p as i128;
+                s.left -= volume as usize;
+                if s.left == 0 {
+                    let cost = (-s.profit) as u64;
+                    s.cover_price_avg = cost / s.volume as u64;
+                    s.profit += s.open_price as i128 * s.volume as i128;
+                    s.commission = max(cost * 3 / 10000, 50000)
+                        + max(s.open_price * s.volume as u64 * 3 / 10000, 50000);
+                    s.fee = s.fee_until(tick.dt);
+                    s.cover_time = tick.dt;
+                    self.margin.give_back(&s.code, s.volume as u64, s.margin);
+                    debug!("{} cover short:{}", tick.dt, s);
+                    break;
                 }
             }
         }
@@ -329,12 +1317,63 @@ impl StockSys {
     // 能否下单的判断方法：
     // 在交易后的冷却时间内不能下单
     // 涨幅是达到阈值了才下单
-    // 涨停时不能买
+    // 涨停封板时不能买
     fn can_buy(&self, tick: &tick::Tick) -> bool {
-        if tick.nPrice == tick.HighLimited * 10 {
+        if self.price_limit(tick).is_sealed_up(tick) {
             return false;
         }
-        if self.conf.buy_point < self.gap_rate {
+        let gap_up = self.has_direction(Direction::LongGapUp) && self.conf.buy_point < self.gap_rate;
+        let gap_down =
+            self.has_direction(Direction::LongGapDown) && self.conf.drop_point < self.drop_rate;
+        if gap_up || gap_down {
+            // 还没有收到这个代码的数据时指标不足，视为不满足
+            let checked = match self.indicators.get(&tick.chWindCode) {
+                Some(set) => set.check(tick.nPrice),
+                None if self.conf.buy_filters.is_empty() => Ok(()),
+                None => Err(self.conf.buy_filters[0].clone()),
+            };
+            if let Err(filter) = checked {
+                debug!(
+                    "{} will not buy price {} when filter `{}` not passed",
+                    tick.dt, tick.nPrice, filter
+                );
+                return false;
+            }
+            if let Err(reason) = self.filter.check(tick, true) {
+                debug!(
+                    "{} will not buy price {} when {}",
+                    tick.dt, tick.nPrice, reason
+                );
+                return false;
+            }
+            // 已有持仓时只能按加仓规则买入
+            let pyramid = &self.conf.pyramid;
+            match self.positions.get(&tick.chWindCode) {
+                Some(pos) if pyramid.enabled && pos.volume > 0 => {
+                    if pos.tranches >= pyramid.max_tranches {
+                        debug!(
+                            "{} will not add to position of {} with {} tranches",
+                            tick.dt, tick.chWindCode, pos.tranches
+                        );
+                        return false;
+                    }
+                    if (tick.nPrice as f64) < pos.last_price as f64 * (1.0 + pyramid.add_step) {
+                        debug!(
+                            "{} will not add price {} when last add price is {}",
+                            tick.dt, tick.nPrice, pos.last_price
+                        );
+                        return false;
+                    }
+                    debug!(
+                        "{} will add tranche {} price {} to position {}",
+                        tick.dt,
+                        pos.tranches + 1,
+                        tick.nPrice,
+                        pos
+                    );
+                }
+                _ => {}
+            }
             match self.orders.last() {
                 Some(buy_order) => {
                     // 两次买入间隔大于 buy_cooldown_time 秒
@@ -343,7 +1382,7 @@ impl StockSys {
                     match buy {
                         true => debug!(
                             "{} will buy (min price time {}, gap {}) price {} when time after buy time:{} + cold time:{}",
-                            tick.dt, self.gap_window.get(0).unwrap().dt, self.gap_rate, tick.nPrice, buy_order.time, self.conf.buy_cooldown_time
+                            tick.dt, self.gap_window.min().unwrap().0, self.gap_rate, tick.nPrice, buy_order.time, self.conf.buy_cooldown_time
                         ),
                         false => debug!(
                             "{} will not buy price {} when time in buy time:{} + cold time:{}",
@@ -357,7 +1396,7 @@ impl StockSys {
                         "{} first buy price {} when min price time is {} gap is {}",
                         tick.dt,
                         tick.nPrice,
-                        self.gap_window.get(0).unwrap().dt,
+                        self.gap_window.min().unwrap().0,
                         self.gap_rate
                     );
                     return true;
@@ -366,44 +1405,539 @@ impl StockSys {
         }
         false
     }
+    // 分批止盈，达到目标价时按持仓计算要卖出的数量，按买入顺序分配到各个订单上挂卖单
+    // 卖单和其他卖单一样要等委托到达交易所后才能撮合
+    fn scale_out(&mut self, tick: &tick::Tick) {
+        let pyramid = &self.conf.pyramid;
+        if !pyramid.enabled {
+            return;
+        }
+        let pos = match self.positions.get_mut(&tick.chWindCode) {
+            Some(pos) if pos.volume > 0 => pos,
+            _ => return,
+        };
+        let i = pos.targets_hit;
+        if i >= pyramid.exit_targets.len() {
+            return;
+        }
+        let target = (pos.avg_cost() as f64 * (1.0 + pyramid.exit_targets[i])) as u64;
+        if tick.nPrice < target {
+            return;
+        }
+        pos.targets_hit += 1;
+        let mut want = ((pos.peak as f64 * pyramid.exit_fractions[i]) as u64).min(pos.volume);
+        debug!(
+            "{} reach target {} price {} to sell {} of position {}",
+            tick.dt, i + 1, target, want, pos
+        );
+        let members = pos.orders.clone();
+        let rule = instrument::board_of(&tick.chWindCode).lot_rule();
+        let price = self.price_limit(tick).clamp(target);
+        let submit = self.conf.latency.submit();
+        for idx in members {
+            if want == 0 {
+                break;
+            }
+            let order = &mut self.orders[idx];
+            // 已经在卖出的部分不用重复挂单，改单还没有到达时不能再改
+            let free = order.left.saturating_sub(order.sell_volume) as u64;
+            if free == 0 || order.amending || order.want_sell_all {
+                continue;
+            }
+            let volume = rule.round_sell(want.min(free), order.left as u64).min(free);
+            if volume == 0 {
+                continue;
+            }
+            want -= volume.min(want);
+            if order.exit_mid == 0 {
+                order.exit_mid = tick.mid_price();
+            }
+            debug!(
+                "{} scale out {} of order {} at price {}",
+                tick.dt, volume, idx, price
+            );
+            if order.sell_volume == 0 {
+                order.sell_price = price;
+                order.sell_volume = volume as usize;
+                order.sell_arrive = tick.dt + submit;
+                order.cancelled = false;
+            } else {
+                // 已经有卖单时改单，价格取两者中较低的
+                let (price, volume) = (price.min(order.sell_price), order.sell_volume + volume as usize);
+                self.amend_sell(idx, tick.dt, price, volume, false);
+            }
+        }
+    }
+    // 加仓后持仓均价变了，撤销还没有到卖出时间的止盈卖单，按新的均价重新计算止盈目标
+    fn rearm_targets(&mut self, code: &str, now: DateTime<FixedOffset>) {
+        if !self.conf.pyramid.enabled {
+            return;
+        }
+        let members = match self.positions.get_mut(code) {
+            Some(pos) if pos.tranches > 1 => {
+                pos.targets_hit = 0;
+                pos.orders.clone()
+            }
+            _ => return,
+        };
+        let delay = Duration::seconds(self.conf.sell_delay_time);
+        for idx in members {
+            let order = &self.orders[idx];
+            if order.sell_volume == 0
+                || order.amending
+                || order.want_sell_all
+                || now - order.time > delay
+            {
+                continue;
+            }
+            debug!("{} cancel scale out of order {} after adding to {}", now, idx, code);
+            self.cancel_sell(idx, now);
+        }
+    }
+    fn has_direction(&self, d: Direction) -> bool {
+        self.conf.directions.contains(&d)
+    }
+    // 从最高价下跌超过阈值时，不等卖出时间，以任意买价卖出这个股票的整个持仓
+    fn exit_on_gap_down(&mut self, tick: &tick::Tick) {
+        if !self.has_direction(Direction::ExitGapDown) || self.conf.drop_point >= self.drop_rate {
+            return;
+        }
+        let members = match self.positions.get(&tick.chWindCode) {
+            Some(pos) if pos.volume > 0 => pos.orders.clone(),
+            _ => return,
+        };
+        let limit = self.price_limit(tick);
+        for idx in members {
+            let order = &mut self.orders[idx];
+            if order.left == 0 || order.want_sell_all || order.amending {
+                continue;
+            }
+            if order.exit_mid == 0 {
+                order.exit_mid = tick.mid_price();
+            }
+            let left = order.left;
+            debug!(
+                "{} exit order {} on drop {} price {}",
+                tick.dt, idx, self.drop_rate, tick.nPrice
+            );
+            self.amend_sell(idx, tick.dt, limit.clamp(tick.nPrice), left, true);
+        }
+    }
     fn process_order(&mut self, tick: &tick::Tick) {
-        if self.can_buy(tick) {
-            self.buy(tick);
+        // 已经有买单在路上时不重复下单
+        let pending = self.pending_buys.iter().any(|p| p.code == tick.chWindCode);
+        let volume = if !pending && self.can_buy(tick) { self.buy_volume(tick) } else { 0 };
+        if volume > 0 {
+            if self.conf.latency.submit_is_zero() {
+                self.buy(tick, None, tick.dt, tick.mid_price(), volume);
+            } else {
+                let arrive = tick.dt + self.conf.latency.submit();
+                debug!("{} send buy order of {} arrive at {}", tick.dt, tick.chWindCode, arrive);
+                self.pending_buys.push(PendingBuy {
+                    code: tick.chWindCode.clone(),
+                    signal: tick.dt,
+                    signal_mid: tick.mid_price(),
+                    arrive,
+                    volume,
+                    rest: false,
+                });
+            }
+        }
+        self.exit_on_gap_down(tick);
+        self.scale_out(tick);
+        if self.margin.conf.enabled {
+            if self.can_short(tick) {
+                self.short(tick);
+            }
+            self.cover(tick);
         }
         self.sell(tick);
     }
     // 每次tick到达时，更新时间窗内的最大涨幅
     // 时间窗以最低价为起点，当前价为终点
-    // TODO：将一定量的tick聚合到一个bar结构里
     fn update_gap(&mut self, tick: &tick::Tick) {
-        //let mut need_update_gap = false;
-
-        self.gap_window.push(tick.clone());
-        if self.gap_window.last().unwrap().dt - self.gap_window.first().unwrap().dt
-            >= Duration::seconds(self.conf.gap_window)
-        {
-            self.gap_window.remove(0);
-            //if self.min_idx == 0 || self.max_idx == 0 {
-            //need_update_gap = true;
-            //debug!("remove {:?} min {} and low {} max {} and high {} ", first, first.Low, self.min, first.High, self.max);
-            //}
-        }
-        if tick.nPrice > self.max {
-            self.max = tick.nPrice;
-            //self.max_idx = self.gap_window.len() - 1;
-            //need_update_gap = true;
-        }
-        if tick.nPrice < self.min {
-            self.min = tick.nPrice;
-            //  need_update_gap = true;
-            self.gap_window.clear();
-            self.gap_window.push(tick.clone());
-            //self.min_idx = 0;
-        }
-        //if need_update_gap {
-        //debug!("for tick window:{:?}", &self.gap_window);
+        self.gap_window.push(tick.dt, tick.nPrice);
+        if let Some((_, max)) = self.gap_window.max() {
+            self.max = max;
+        }
         self.get_gap(tick);
-        //}
-        //debug!(":{:?}", &self.gap_window);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::book::empty_tick;
+    use crate::tick::{get_time, trade_date};
+
+    // 用临时配置文件创建策略，extra中的顶层配置必须写在表之前
+    pub(crate) fn new_sys(name: &str, extra: &str) -> StockSys {
+        let path = std::env::temp_dir().join(format!("strategy_test_{}_{}.toml", name, std::process::id()));
+        let text = format!(
+            "buy_point = 0.005\ngap_window = 600\nbuy_volume = 1000\nbuy_cooldown_time = 30\n\
+             sell_delay_time = 60\nsell_all_delay = 30\nlog_level = \"debug\"\nlog_file = \"\"\n\
+             log_size = 1\nlog_count = 1\ntick_data = \"\"\ntrans_data = \"\"\n{}",
+            extra
+        );
+        std::fs::write(&path, text).unwrap();
+        let sys = new_stock_sys(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        sys
+    }
+
+    // 以price为最新价、上下各10档、每档1000股的tick，昨收为400000
+    pub(crate) fn tick(code: &str, ntime: u64, price: u64, volume: u64) -> Tick {
+        let mut t = empty_tick(code);
+        t.nTime = ntime;
+        t.dt = get_time(trade_date(0).unwrap(), ntime).unwrap();
+        t.nPrice = price;
+        t.PreClose = 400000;
+        t.TotalVolume = volume;
+        t.TotalTurnover = volume * price / 10000;
+        for l in 1..=10 {
+            t.set_ask(l, price + 100 * l as u64, 1000);
+            t.set_bid(l, price - 100 * l as u64, 1000);
+        }
+        t
+    }
+
+    #[test]
+    fn bars_per_code() {
+        let mut sys = new_sys("bars_per_code", "[bar]\nkind = \"tick\"\nsize = 2\n");
+        sys.do_strategy(&tick("601012.SH", 93000000, 400000, 1000));
+        sys.do_strategy(&tick("600000.SH", 93000000, 80000, 50000));
+        sys.do_strategy(&tick("601012.SH", 93003000, 400100, 1500));
+        sys.do_strategy(&tick("600000.SH", 93003000, 80100, 52000));
+        sys.do_strategy(&tick("601012.SH", 93006000, 400200, 1600));
+        sys.finish();
+        let bars: Vec<(&str, u64, u64, u64)> = sys
+            .bars
+            .iter()
+            .map(|b| (b.code.as_str(), b.open, b.close, b.volume))
+            .collect();
+        assert_eq!(
+            bars,
+            vec![
+                ("601012.SH", 400000, 400100, 1500),
+                ("600000.SH", 80000, 80100, 52000),
+                ("601012.SH", 400200, 400200, 100),
+            ]
+        );
+    }
+
+    #[test]
+    fn indicators_per_code() {
+        let mut sys = new_sys("indicators_per_code", "buy_filters = [\"price > sma:2\"]\n");
+        sys.do_strategy(&tick("601012.SH", 93000000, 400000, 1000));
+        sys.do_strategy(&tick("600000.SH", 93000000, 80000, 50000));
+        sys.do_strategy(&tick("601012.SH", 93003000, 400200, 1500));
+        // 601012.SH的sma:2为400100，不受600000.SH的价格影响
+        assert_eq!(sys.indicators["601012.SH"].check(400150), Ok(()));
+        assert!(sys.indicators["600000.SH"].check(400150).is_err());
+    }
+
+    // 主动买入的逐笔成交
+    pub(crate) fn print(code: &str, ntime: u64, price: u64, volume: u64) -> transaction::transaction {
+        transaction::transaction {
+            Tkr: code.to_string(),
+            Time: ntime,
+            dt: get_time(trade_date(0).unwrap(), ntime).unwrap(),
+            Index: ntime,
+            Price: price,
+            Volume: volume,
+            Turnover: price * volume / 10000,
+            BSFlag: 'B',
+            OrderKind: 0,
+            FunctionCode: '0',
+            AskOrder: 0,
+            BidOrder: 0,
+        }
+    }
+
+    fn fills(sys: &StockSys) -> Vec<(u64, u64, usize)> {
+        sys.orders.iter().map(|o| (tick::ntime_of(o.time), o.open_price, o.volume)).collect()
+    }
+
+    // 9:30:03涨幅超过0.5%产生买入信号
+    fn signal(sys: &mut StockSys) {
+        sys.do_strategy(&tick("601012.SH", 93000000, 400000, 1000));
+        sys.do_strategy(&tick("601012.SH", 93003000, 402100, 2000));
+    }
+
+    #[test]
+    fn zero_latency_fills_on_signal_tick() {
+        let mut baseline = new_sys("zero_latency_baseline", "");
+        signal(&mut baseline);
+        assert_eq!(fills(&baseline), vec![(93003000, 402200, 1000)]);
+        // ack和cancel不影响在信号tick上撮合
+        let mut sys = new_sys("zero_latency", "[latency]\nack = 5\ncancel = 10\n");
+        signal(&mut sys);
+        assert_eq!(fills(&sys), fills(&baseline));
+    }
+
+    #[test]
+    fn latency_fills_at_first_event_after_arrival() {
+        let mut sys = new_sys("latency_after_arrival", "[latency]\ndecision = 200\nrouting = 300\n");
+        signal(&mut sys);
+        assert!(sys.orders.is_empty());
+        // 委托9:30:03.500到达，之前的成交和快照都不能撮合
+        sys.on_transaction(&print("601012.SH", 93003499, 402200, 5000));
+        assert!(sys.orders.is_empty());
+        sys.on_transaction(&print("601012.SH", 93003500, 402300, 5000));
+        assert_eq!(fills(&sys), vec![(93003500, 402300, 1000)]);
+
+        let mut sys = new_sys("latency_snapshot", "[latency]\nrouting = 500\n");
+        signal(&mut sys);
+        sys.do_strategy(&tick("601012.SH", 93003200, 402100, 2100));
+        assert!(sys.orders.is_empty());
+        sys.do_strategy(&tick("601012.SH", 93006000, 402500, 2200));
+        assert_eq!(fills(&sys), vec![(93006000, 402600, 1000)]);
+    }
+
+    #[test]
+    fn print_remainder_waits_for_snapshot() {
+        let mut sys = new_sys("print_remainder", "[latency]\nrouting = 500\n");
+        signal(&mut sys);
+        sys.on_transaction(&print("601012.SH", 93004000, 402200, 300));
+        assert_eq!(fills(&sys), vec![(93004000, 402200, 300)]);
+        // 剩下的700股不再用之后的逐笔成交撮合，等下一个快照
+        sys.on_transaction(&print("601012.SH", 93005000, 402200, 1000));
+        assert_eq!(sys.orders.len(), 1);
+        sys.do_strategy(&tick("601012.SH", 93006000, 402100, 3300));
+        assert_eq!(fills(&sys), vec![(93004000, 402200, 300), (93006000, 402200, 700)]);
+        assert_eq!(sys.positions["601012.SH"].volume, 1000);
+    }
+
+    #[test]
+    fn odd_lot_print_keeps_order() {
+        let mut sys = new_sys("odd_lot_print", "[latency]\nrouting = 500\n");
+        signal(&mut sys);
+        // 不足一手的成交不撮合，委托仍然挂着
+        sys.on_transaction(&print("601012.SH", 93004000, 402200, 50));
+        assert!(sys.orders.is_empty());
+        sys.do_strategy(&tick("601012.SH", 93006000, 402100, 2050));
+        assert_eq!(fills(&sys), vec![(93006000, 402200, 1000)]);
+    }
+
+    // 处理ntime之前到期的订单回报、改单和定时器事件
+    fn drain(sys: &mut StockSys, ntime: u64) {
+        let until = get_time(trade_date(0).unwrap(), ntime).unwrap();
+        while let Some((time, action)) = sys.events.pop_until(Some(until)) {
+            sys.on_action(time, action);
+        }
+    }
+
+    #[test]
+    fn fills_at_old_price_before_cancel_arrives() {
+        let at = |ntime| get_time(trade_date(0).unwrap(), ntime).unwrap();
+        for (ntime, filled) in [(93105500, true), (93106000, false)].iter() {
+            let mut sys = new_sys("cancel_latency", "[latency]\ncancel = 1000\n");
+            signal(&mut sys);
+            drain(&mut sys, 93104000);
+            sys.do_strategy(&tick("601012.SH", 93104000, 402100, 3000));
+            assert_eq!((sys.orders[0].sell_price, sys.orders[0].left), (402200, 1000));
+            sys.cancel_sell(0, at(93105000));
+            assert!(sys.orders[0].amending);
+            // 撤单9:31:06到达交易所，之前买1涨到原来的卖价时仍然按原价成交
+            drain(&mut sys, *ntime);
+            sys.do_strategy(&tick("601012.SH", *ntime, 402300, 3500));
+            drain(&mut sys, 93110000);
+            let o = &sys.orders[0];
+            if *filled {
+                assert_eq!((o.left, o.sell_price_avg, o.cancelled), (0, 402200, false));
+            } else {
+                // 卖出时间之后撤单的不再挂单
+                assert_eq!((o.left, o.sell_price, o.cancelled), (1000, 0, true));
+            }
+        }
+    }
+
+    #[test]
+    fn cancel_before_sell_time_replaces_at_sell_time() {
+        let mut sys = new_sys("cancel_before_sell_time", "[latency]\ncancel = 1000\n");
+        signal(&mut sys);
+        sys.cancel_sell(0, get_time(trade_date(0).unwrap(), 93010000).unwrap());
+        drain(&mut sys, 93011000);
+        assert!(sys.orders[0].cancelled);
+        assert_eq!(sys.orders[0].sell_price, 0);
+        // 到了卖出时间，定时器按最新的盘口挂卖1
+        drain(&mut sys, 93103001);
+        let o = &sys.orders[0];
+        assert_eq!((o.cancelled, o.sell_price, o.sell_volume), (false, 402200, 1000));
+        sys.do_strategy(&tick("601012.SH", 93104000, 402300, 3000));
+        assert_eq!((sys.orders[0].left, sys.orders[0].sell_price_avg), (0, 402200));
+    }
+
+    // 开启融券，券源文件写在临时目录中
+    fn new_short_sys(name: &str, borrow: &str, margin: &str) -> StockSys {
+        let path = std::env::temp_dir().join(format!("strategy_test_{}_{}.csv", name, std::process::id()));
+        std::fs::write(&path, format!("code,volume,rate\n{}", borrow)).unwrap();
+        let extra = format!(
+            "[margin]\nenabled = true\nborrow_file = \"{}\"\n{}",
+            path.to_str().unwrap(),
+            margin
+        );
+        let sys = new_sys(name, &extra);
+        std::fs::remove_file(&path).unwrap();
+        sys
+    }
+
+    // 买1和最新价相同的tick，融券卖出的价格不能低于最新价
+    fn short_tick(ntime: u64, price: u64, bid_volume: u64) -> Tick {
+        let mut t = tick("601012.SH", ntime, price, 1000);
+        t.set_bid(1, price, bid_volume);
+        t
+    }
+
+    #[test]
+    fn short_and_cover() {
+        let mut sys = new_short_sys("short_and_cover", "601012.SH,1500,0.18\n", "");
+        sys.do_strategy(&short_tick(93000000, 400000, 1000));
+        sys.do_strategy(&short_tick(93003000, 397900, 1000));
+        assert_eq!(sys.shorts.len(), 1);
+        let s = &sys.shorts[0];
+        assert_eq!((s.open_price, s.volume, s.left, s.tax), (397900, 1000, 1000, 397900));
+        assert_eq!((s.margin, s.rate), (198950000, 0.18));
+        assert_eq!((sys.margin.margin, sys.margin.available("601012.SH")), (198950000, 500));
+        assert!(sys.orders.is_empty());
+        // sell_delay_time之后以买1挂单，卖1不高于挂单价时买入还券
+        sys.do_strategy(&tick("601012.SH", 93104000, 396000, 2000));
+        assert_eq!((sys.shorts[0].cover_price, sys.shorts[0].left), (395900, 1000));
+        sys.do_strategy(&tick("601012.SH", 93105000, 395700, 3000));
+        let s = &sys.shorts[0];
+        assert_eq!((s.left, s.cover_price_avg), (0, 395800));
+        assert_eq!(s.profit, (397900 - 395800) * 1000);
+        assert_eq!(s.commission, 118740 + 119370);
+        assert_eq!((s.fee, s.fee_until(s.cover_time)), (198950, 198950));
+        assert_eq!(
+            short_pnl(s, 0, s.cover_time),
+            s.profit - (s.tax + s.commission + s.fee) as i128
+        );
+        // 平仓后释放保证金，归还券源
+        assert_eq!((sys.margin.margin, sys.margin.available("601012.SH")), (0, 1500));
+    }
+
+    #[test]
+    fn short_refused_without_borrow() {
+        let mut sys = new_short_sys("borrow_exhausted", "601012.SH,1000,\n", "");
+        sys.do_strategy(&short_tick(93000000, 400000, 1000));
+        sys.do_strategy(&short_tick(93003000, 397900, 1000));
+        assert_eq!(sys.margin.available("601012.SH"), 0);
+        // 冷却时间之后再次下跌，但券已经借完
+        sys.do_strategy(&short_tick(93040000, 397900, 1000));
+        sys.do_strategy(&short_tick(93041000, 395800, 1000));
+        assert_eq!(sys.shorts.len(), 1);
+        // 不在券源文件中的股票不能融券
+        sys.do_strategy(&tick("600000.SH", 93042000, 80000, 1000));
+        sys.do_strategy(&tick("600000.SH", 93043000, 79000, 2000));
+        assert_eq!(sys.shorts.len(), 1);
+    }
+
+    #[test]
+    fn short_refused_over_max_margin() {
+        let mut sys = new_short_sys("max_margin", "601012.SH,1000,\n", "max_margin = 100000000\n");
+        sys.do_strategy(&short_tick(93000000, 400000, 1000));
+        sys.do_strategy(&short_tick(93003000, 397900, 1000));
+        assert!(sys.shorts.is_empty());
+        assert_eq!((sys.margin.margin, sys.margin.available("601012.SH")), (0, 1000));
+    }
+
+    #[test]
+    fn short_uptick_rule() {
+        let mut sys = new_short_sys("uptick", "601012.SH,1000,\n", "");
+        sys.do_strategy(&short_tick(93000000, 400000, 1000));
+        // 买盘都低于最新价时不能融券卖出
+        sys.do_strategy(&tick("601012.SH", 93003000, 397900, 2000));
+        assert!(sys.shorts.is_empty());
+        // 只有和最新价相同的买1可以成交
+        sys.do_strategy(&short_tick(93004000, 397800, 500));
+        assert_eq!(sys.shorts.len(), 1);
+        assert_eq!((sys.shorts[0].open_price, sys.shorts[0].volume), (397800, 500));
+        assert_eq!(sys.margin.available("601012.SH"), 500);
+    }
+
+    #[test]
+    fn contradictory_directions() {
+        use Direction::*;
+        assert!(check_directions(&[LongGapUp, ShortGapDown, ExitGapDown]).is_ok());
+        assert!(check_directions(&[LongGapUp, LongGapDown]).is_ok());
+        assert_eq!(
+            check_directions(&[LongGapDown, ExitGapDown]),
+            Err("directions long_gap_down and exit_gap_down are contradictory".to_string())
+        );
+        assert_eq!(
+            check_directions(&[ShortGapDown, LongGapDown]),
+            Err("directions long_gap_down and short_gap_down are contradictory".to_string())
+        );
+        let path = std::env::temp_dir().join(format!("strategy_test_directions_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "buy_point = 0.005\ngap_window = 600\nbuy_volume = 1000\nbuy_cooldown_time = 30\n\
+             sell_delay_time = 60\nsell_all_delay = 30\nlog_level = \"debug\"\nlog_file = \"\"\n\
+             log_size = 1\nlog_count = 1\ntick_data = \"\"\ntrans_data = \"\"\n\
+             directions = [\"long_gap_down\", \"exit_gap_down\"]\n",
+        )
+        .unwrap();
+        assert!(new_config(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn buy_resets_drop_rate() {
+        let mut sys = new_sys("buy_resets_drop_rate", "directions = [\"long_gap_down\"]\n");
+        sys.do_strategy(&tick("601012.SH", 93000000, 400000, 1000));
+        sys.do_strategy(&tick("601012.SH", 93003000, 397900, 2000));
+        assert_eq!(fills(&sys), vec![(93003000, 398000, 1000)]);
+        // 买入后时间窗和下跌幅度都重新计算
+        assert_eq!(sys.drop_rate, 0.0);
+        sys.do_strategy(&tick("601012.SH", 93040000, 397900, 2100));
+        assert_eq!(sys.orders.len(), 1);
+    }
+
+    #[test]
+    fn pyramid_add_limits() {
+        let mut sys = new_sys("pyramid_add", "[pyramid]\nenabled = true\nmax_tranches = 2\nadd_step = 0.003\n");
+        signal(&mut sys);
+        // 涨幅足够但没有比上一次买入价再上涨add_step，不加仓
+        sys.do_strategy(&tick("601012.SH", 93035000, 400000, 2100));
+        sys.do_strategy(&tick("601012.SH", 93040000, 402100, 2200));
+        assert_eq!(sys.orders.len(), 1);
+        sys.do_strategy(&tick("601012.SH", 93041000, 404200, 2300));
+        assert_eq!(fills(&sys), vec![(93003000, 402200, 1000), (93041000, 404300, 1000)]);
+        let pos = &sys.positions["601012.SH"];
+        assert_eq!((pos.volume, pos.avg_cost(), pos.tranches, pos.orders.clone()), (2000, 403250, 2, vec![0, 1]));
+        // 达到max_tranches后不再加仓
+        sys.do_strategy(&tick("601012.SH", 93115000, 404200, 2400));
+        sys.do_strategy(&tick("601012.SH", 93120000, 406300, 2500));
+        assert_eq!(sys.orders.len(), 2);
+    }
+
+    #[test]
+    fn scale_out_by_lot() {
+        let mut sys = new_sys(
+            "scale_out",
+            "[pyramid]\nenabled = true\nmax_tranches = 2\nexit_targets = [0.01]\nexit_fractions = [0.575]\n",
+        );
+        signal(&mut sys);
+        sys.do_strategy(&tick("601012.SH", 93035000, 402100, 2100));
+        sys.do_strategy(&tick("601012.SH", 93040000, 404200, 2200));
+        assert_eq!(sys.positions["601012.SH"].avg_cost(), 403250);
+        // 按均价计算止盈价403250 * 1.01，卖出最大持仓的57.5%即1150股
+        // 先分配给第一个订单1000股，第二个订单剩下的150股按整手取整为100股
+        sys.do_strategy(&tick("601012.SH", 93045000, 407300, 2300));
+        let sells: Vec<(u64, usize)> = sys.orders.iter().map(|o| (o.sell_price, o.sell_volume)).collect();
+        assert_eq!(sells, vec![(407282, 1000), (407282, 100)]);
+        assert_eq!(sys.positions["601012.SH"].targets_hit, 1);
+        // 止盈目标只触发一次
+        sys.do_strategy(&tick("601012.SH", 93046000, 407300, 2400));
+        assert_eq!(sys.orders[1].sell_volume, 100);
+        assert_eq!(sys.orders[0].left, 1000);
+        // 买1达到止盈价后成交，持仓减少
+        let mut t = tick("601012.SH", 93047000, 407400, 2500);
+        t.set_bid(1, 407300, 2000);
+        sys.do_strategy(&t);
+        assert_eq!(sys.orders[0].left, 0);
+        assert_eq!(sys.orders[1].left, 900);
+        assert_eq!(sys.positions["601012.SH"].volume, 900);
     }
 }
@@ -1,35 +1,122 @@
-use chrono::{DateTime, FixedOffset, TimeZone};
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Timelike};
 use lazy_static::lazy_static;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
 
 lazy_static! {
-    // 随便编的日期，没有找到只包含Hour:Minute:Second的日期库
-    static ref YEAR: i32 = 2021;
-    static ref MONTH: i32 = 10;
-    static ref DAY: i32 = 30;
-    pub static ref START_TIME_MORNINIG: DateTime<FixedOffset> = FixedOffset::east(8 * 60 * 60)
-        .ymd(*YEAR, *MONTH as u32, *DAY as u32)
-        .and_hms(9, 30, 0);
-    pub static ref END_TIME_MORNINIG: DateTime<FixedOffset> = FixedOffset::east(8 * 60 * 60)
-        .ymd(*YEAR, *MONTH as u32, *DAY as u32)
-        .and_hms(11, 30, 0);
-    pub static ref START_TIME_AFTERNOON: DateTime<FixedOffset> = FixedOffset::east(8 * 60 * 60)
-        .ymd(*YEAR, *MONTH as u32, *DAY as u32)
-        .and_hms(13, 0, 0);
-    pub static ref END_TIME_AFTERNOON: DateTime<FixedOffset> = FixedOffset::east(8 * 60 * 60)
-        .ymd(*YEAR, *MONTH as u32, *DAY as u32)
-        .and_hms(15, 0, 0);
+    // 数据中没有日期、也没有配置trade_date时使用的日期
+    static ref DEFAULT_DATE: NaiveDate = NaiveDate::from_ymd_opt(2021, 10, 30).unwrap();
+    pub static ref START_TIME_MORNINIG: TimeOfDay = TimeOfDay::from_hms_milli(9, 30, 0, 0).unwrap();
+    pub static ref END_TIME_MORNINIG: TimeOfDay = TimeOfDay::from_hms_milli(11, 30, 0, 0).unwrap();
+    pub static ref START_TIME_AFTERNOON: TimeOfDay = TimeOfDay::from_hms_milli(13, 0, 0, 0).unwrap();
+    pub static ref END_TIME_AFTERNOON: TimeOfDay = TimeOfDay::from_hms_milli(15, 0, 0, 0).unwrap();
 }
-pub fn get_time(ntime: u64) -> DateTime<FixedOffset> {
-    // 91003000 = 9:10:03
-    let pst = FixedOffset::east(8 * 60 * 60);
-    //println!("hms is :{} {} {}", (ntime/10000000) as u32, (ntime%10000000/100000) as u32, (ntime%100000/1000) as u32);
-    let dt = pst.ymd(*YEAR, *MONTH as u32, *DAY as u32).and_hms(
-        (ntime / 10000000) as u32,
-        (ntime % 10000000 / 100000) as u32,
-        (ntime % 100000 / 1000) as u32,
-    );
-    dt
+// 交易所当地时间（北京时间）的当天时刻，精确到毫秒
+// nTime这样的整数和DateTime都通过它转换
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimeOfDay {
+    millis: u32, // 当天的毫秒数
+}
+
+impl TimeOfDay {
+    pub fn from_hms_milli(hour: u32, minute: u32, second: u32, milli: u32) -> Option<TimeOfDay> {
+        if hour >= 24 || minute >= 60 || second >= 60 || milli >= 1000 {
+            return None;
+        }
+        Some(TimeOfDay {
+            millis: ((hour * 60 + minute) * 60 + second) * 1000 + milli,
+        })
+    }
+
+    // 91003500 = 9:10:03.500
+    pub fn from_ntime(ntime: u64) -> Result<TimeOfDay, String> {
+        if ntime >= 240000000 {
+            return Err(format!("invalid nTime {}", ntime));
+        }
+        TimeOfDay::from_hms_milli(
+            (ntime / 10000000) as u32,
+            (ntime % 10000000 / 100000) as u32,
+            (ntime % 100000 / 1000) as u32,
+            (ntime % 1000) as u32,
+        )
+        .ok_or_else(|| format!("invalid nTime {}", ntime))
+    }
+
+    pub fn of(dt: DateTime<FixedOffset>) -> TimeOfDay {
+        TimeOfDay {
+            millis: dt.num_seconds_from_midnight() * 1000 + dt.nanosecond() / 1000000 % 1000,
+        }
+    }
+
+    pub fn hour(&self) -> u32 {
+        self.millis / 3600000
+    }
+
+    pub fn minute(&self) -> u32 {
+        self.millis / 60000 % 60
+    }
+
+    pub fn second(&self) -> u32 {
+        self.millis / 1000 % 60
+    }
+
+    pub fn millisecond(&self) -> u32 {
+        self.millis % 1000
+    }
+
+    pub fn millis(&self) -> u32 {
+        self.millis
+    }
+
+    pub fn ntime(&self) -> u64 {
+        self.hour() as u64 * 10000000
+            + self.minute() as u64 * 100000
+            + self.second() as u64 * 1000
+            + self.millisecond() as u64
+    }
+
+    // 某个交易日的这个时刻
+    pub fn on(&self, date: NaiveDate) -> DateTime<FixedOffset> {
+        let t = date
+            .and_hms_milli_opt(self.hour(), self.minute(), self.second(), self.millisecond())
+            .unwrap();
+        FixedOffset::east_opt(8 * 60 * 60)
+            .unwrap()
+            .from_local_datetime(&t)
+            .unwrap()
+    }
+}
+
+impl Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:03}",
+            self.hour(),
+            self.minute(),
+            self.second(),
+            self.millisecond()
+        )
+    }
+}
+
+// 交易日，如20211030，为0时使用默认日期
+pub fn trade_date(yyyymmdd: u32) -> Result<NaiveDate, String> {
+    if yyyymmdd == 0 {
+        return Ok(*DEFAULT_DATE);
+    }
+    NaiveDate::from_ymd_opt((yyyymmdd / 10000) as i32, yyyymmdd / 100 % 100, yyyymmdd % 100)
+        .ok_or_else(|| format!("invalid date {}, expect yyyymmdd", yyyymmdd))
+}
+
+// date这一天的91003500 = 9:10:03.500，格式不对时返回错误
+pub fn get_time(date: NaiveDate, ntime: u64) -> Result<DateTime<FixedOffset>, String> {
+    Ok(TimeOfDay::from_ntime(ntime)?.on(date))
+}
+
+// get_time的逆运算，9:10:03.500 -> 91003500
+pub fn ntime_of(dt: DateTime<FixedOffset>) -> u64 {
+    TimeOfDay::of(dt).ntime()
 }
 
 pub fn default_dt() -> DateTime<FixedOffset> {
@@ -38,7 +125,7 @@ pub fn default_dt() -> DateTime<FixedOffset> {
         .and_hms(0, 0, 1)
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Tick {
     pub chWindCode: String,
     pub nTime: u64,
@@ -99,7 +186,132 @@ pub struct Tick {
     pub YieldToMaturity: u64,
     pub HighLimited: u64, // tick数据中的涨停价比普通值少了一位，需要特殊处理
     pub LowLimited: u64,  //tick数据中的跌停价比普通值少了一位，需要特殊处理
-    #[serde(skip_deserializing)]
+    #[serde(skip)]
     #[serde(default = "default_dt")]
     pub dt: DateTime<FixedOffset>,
 }
+
+impl Tick {
+    // 买1和卖1的中间价，一侧为空时取最新价
+    pub fn mid_price(&self) -> u64 {
+        if self.nAskPrice1 == 0 || self.nBidPrice1 == 0 {
+            return self.nPrice;
+        }
+        (self.nAskPrice1 + self.nBidPrice1) / 2
+    }
+
+    // 卖1~卖10的(价格, 数量)
+    pub fn asks(&self) -> [(u64, u64); 10] {
+        [
+            (self.nAskPrice1, self.nAskVolume1),
+            (self.nAskPrice2, self.nAskVolume2),
+            (self.nAskPrice3, self.nAskVolume3),
+            (self.nAskPrice4, self.nAskVolume4),
+            (self.nAskPrice5, self.nAskVolume5),
+            (self.nAskPrice6, self.nAskVolume6),
+            (self.nAskPrice7, self.nAskVolume7),
+            (self.nAskPrice8, self.nAskVolume8),
+            (self.nAskPrice9, self.nAskVolume9),
+            (self.nAskPrice10, self.nAskVolume10),
+        ]
+    }
+    // 买1~买10的(价格, 数量)
+    pub fn bids(&self) -> [(u64, u64); 10] {
+        [
+            (self.nBidPrice1, self.nBidVolume1),
+            (self.nBidPrice2, self.nBidVolume2),
+            (self.nBidPrice3, self.nBidVolume3),
+            (self.nBidPrice4, self.nBidVolume4),
+            (self.nBidPrice5, self.nBidVolume5),
+            (self.nBidPrice6, self.nBidVolume6),
+            (self.nBidPrice7, self.nBidVolume7),
+            (self.nBidPrice8, self.nBidVolume8),
+            (self.nBidPrice9, self.nBidVolume9),
+            (self.nBidPrice10, self.nBidVolume10),
+        ]
+    }
+    // 设置卖level档（从1开始）的价格和数量
+    pub fn set_ask(&mut self, level: usize, price: u64, volume: u64) {
+        let (p, v) = match level {
+            1 => (&mut self.nAskPrice1, &mut self.nAskVolume1),
+            2 => (&mut self.nAskPrice2, &mut self.nAskVolume2),
+            3 => (&mut self.nAskPrice3, &mut self.nAskVolume3),
+            4 => (&mut self.nAskPrice4, &mut self.nAskVolume4),
+            5 => (&mut self.nAskPrice5, &mut self.nAskVolume5),
+            6 => (&mut self.nAskPrice6, &mut self.nAskVolume6),
+            7 => (&mut self.nAskPrice7, &mut self.nAskVolume7),
+            8 => (&mut self.nAskPrice8, &mut self.nAskVolume8),
+            9 => (&mut self.nAskPrice9, &mut self.nAskVolume9),
+            10 => (&mut self.nAskPrice10, &mut self.nAskVolume10),
+            _ => return,
+        };
+        *p = price;
+        *v = volume;
+    }
+    // 设置买level档（从1开始）的价格和数量
+    pub fn set_bid(&mut self, level: usize, price: u64, volume: u64) {
+        let (p, v) = match level {
+            1 => (&mut self.nBidPrice1, &mut self.nBidVolume1),
+            2 => (&mut self.nBidPrice2, &mut self.nBidVolume2),
+            3 => (&mut self.nBidPrice3, &mut self.nBidVolume3),
+            4 => (&mut self.nBidPrice4, &mut self.nBidVolume4),
+            5 => (&mut self.nBidPrice5, &mut self.nBidVolume5),
+            6 => (&mut self.nBidPrice6, &mut self.nBidVolume6),
+            7 => (&mut self.nBidPrice7, &mut self.nBidVolume7),
+            8 => (&mut self.nBidPrice8, &mut self.nBidVolume8),
+            9 => (&mut self.nBidPrice9, &mut self.nBidVolume9),
+            10 => (&mut self.nBidPrice10, &mut self.nBidVolume10),
+            _ => return,
+        };
+        *p = price;
+        *v = volume;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ntime_with_millis() {
+        let t = TimeOfDay::from_ntime(91003500).unwrap();
+        assert_eq!((t.hour(), t.minute(), t.second(), t.millisecond()), (9, 10, 3, 500));
+        assert_eq!(t.millis(), ((9 * 60 + 10) * 60 + 3) * 1000 + 500);
+        assert_eq!(t.ntime(), 91003500);
+        assert_eq!(t.to_string(), "09:10:03.500");
+        // 午夜和一天的最后一毫秒
+        assert_eq!(TimeOfDay::from_ntime(0).unwrap().millis(), 0);
+        assert_eq!(TimeOfDay::from_ntime(235959999).unwrap().to_string(), "23:59:59.999");
+        assert_eq!(TimeOfDay::from_ntime(150000001).unwrap().millisecond(), 1);
+    }
+
+    #[test]
+    fn ntime_out_of_range() {
+        for ntime in [240000000, 246000000, 96000000, 90060000, 999999999, u64::MAX].iter() {
+            assert_eq!(TimeOfDay::from_ntime(*ntime), Err(format!("invalid nTime {}", ntime)));
+        }
+        assert_eq!(TimeOfDay::from_hms_milli(24, 0, 0, 0), None);
+        assert_eq!(TimeOfDay::from_hms_milli(9, 60, 0, 0), None);
+        assert_eq!(TimeOfDay::from_hms_milli(9, 30, 60, 0), None);
+        assert_eq!(TimeOfDay::from_hms_milli(9, 30, 0, 1000), None);
+    }
+
+    #[test]
+    fn get_time_on_date() {
+        let date = trade_date(20211101).unwrap();
+        let dt = get_time(date, 93000250).unwrap();
+        assert_eq!(dt.to_rfc3339(), "2021-11-01T09:30:00.250+08:00");
+        assert_eq!(ntime_of(dt), 93000250);
+        assert_eq!(TimeOfDay::of(dt), TimeOfDay::from_hms_milli(9, 30, 0, 250).unwrap());
+        assert_eq!(get_time(date, 96000000), Err("invalid nTime 96000000".to_string()));
+        assert_eq!(get_time(date, 240000000), Err("invalid nTime 240000000".to_string()));
+    }
+
+    #[test]
+    fn trade_dates() {
+        assert_eq!(trade_date(0).unwrap(), NaiveDate::from_ymd_opt(2021, 10, 30).unwrap());
+        assert_eq!(trade_date(20211101).unwrap(), NaiveDate::from_ymd_opt(2021, 11, 1).unwrap());
+        assert_eq!(trade_date(20211301), Err("invalid date 20211301, expect yyyymmdd".to_string()));
+        assert!(trade_date(20210230).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+use chrono::{DateTime, FixedOffset};
+use std::collections::{HashMap, VecDeque};
+
+use super::feed::FeedError;
+use super::tick::Tick;
+use super::transaction::{transaction, TransStore};
+
+// 把tick和逐笔成交合并成一条按时间排序的事件流
+// tick快照包含了截止到快照时间的成交，所以同一代码在(上一个tick, 当前tick]之间的成交先于当前tick输出
+// 时间按带日期的dt比较，多日数据中不同交易日的成交不会交错
+pub enum Event<'a> {
+    Tick(Box<Tick>), // tick比成交大得多，装箱后事件队列不会太占内存
+    Trade(&'a transaction),
+}
+
+pub struct Timeline<'a, I> {
+    ticks: I,
+    store: &'a TransStore,
+    last_time: HashMap<String, DateTime<FixedOffset>>, // 每个代码已经输出的成交截止时间
+    pending: VecDeque<Event<'a>>,
+    done: bool,
+}
+
+pub fn new_timeline<I>(ticks: I, store: &TransStore) -> Timeline<'_, I>
+where
+    I: Iterator<Item = Result<Tick, FeedError>>,
+{
+    Timeline {
+        ticks,
+        store,
+        last_time: HashMap::new(),
+        pending: VecDeque::new(),
+        done: false,
+    }
+}
+
+impl<'a, I> Timeline<'a, I>
+where
+    I: Iterator<Item = Result<Tick, FeedError>>,
+{
+    // tick数据结束后，输出所有代码剩下的成交
+    fn drain(&mut self) {
+        let mut rest: Vec<&'a transaction> = Vec::new();
+        for code in self.store.codes() {
+            let from = self.last_time.get(code).copied();
+            let trans = self.store.get(code);
+            let start = match from {
+                Some(from) => trans.partition_point(|t| t.dt <= from),
+                None => 0,
+            };
+            rest.extend(&trans[start..]);
+        }
+        rest.sort_by_key(|t| (t.dt, t.Index));
+        self.pending.extend(rest.into_iter().map(Event::Trade));
+    }
+}
+
+impl<'a, I> Iterator for Timeline<'a, I>
+where
+    I: Iterator<Item = Result<Tick, FeedError>>,
+{
+    type Item = Result<Event<'a>, FeedError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(Ok(event));
+        }
+        if self.done {
+            return None;
+        }
+        match self.ticks.next() {
+            Some(Ok(tick)) => {
+                let store = self.store;
+                let (trans, until) = match self.last_time.get(&tick.chWindCode) {
+                    // tick时间倒退时不再输出成交，截止时间也不后退，避免重复输出
+                    Some(from) => (
+                        store.range(&tick.chWindCode, *from, tick.dt),
+                        tick.dt.max(*from),
+                    ),
+                    None => {
+                        let all = store.get(&tick.chWindCode);
+                        (&all[..all.partition_point(|t| t.dt <= tick.dt)], tick.dt)
+                    }
+                };
+                self.last_time.insert(tick.chWindCode.clone(), until);
+                self.pending.extend(trans.iter().map(Event::Trade));
+                self.pending.push_back(Event::Tick(Box::new(tick)));
+            }
+            Some(Err(e)) => return Some(Err(e)),
+            None => {
+                self.done = true;
+                self.drain();
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::empty_tick;
+    use crate::tick::{get_time, trade_date};
+    use crate::transaction::new_trans_store;
+
+    fn tick(code: &str, yyyymmdd: u32, ntime: u64) -> Result<Tick, FeedError> {
+        let mut t = empty_tick(code);
+        t.nTime = ntime;
+        t.dt = get_time(trade_date(yyyymmdd).unwrap(), ntime).unwrap();
+        Ok(t)
+    }
+
+    fn trade(code: &str, yyyymmdd: u32, ntime: u64, index: u64) -> transaction {
+        transaction {
+            Tkr: code.to_string(),
+            Time: ntime,
+            dt: get_time(trade_date(yyyymmdd).unwrap(), ntime).unwrap(),
+            Index: index,
+            Price: 180000,
+            Volume: 100,
+            Turnover: 1800,
+            BSFlag: 'B',
+            OrderKind: 0,
+            FunctionCode: '0',
+            AskOrder: 0,
+            BidOrder: 0,
+        }
+    }
+
+    // 事件序列，tick为 代码@nTime，成交为 #Index
+    fn events(ticks: Vec<Result<Tick, FeedError>>, store: &TransStore) -> Vec<String> {
+        new_timeline(ticks.into_iter(), store)
+            .map(|e| match e.unwrap() {
+                Event::Tick(t) => format!("{}@{}", t.chWindCode, t.nTime),
+                Event::Trade(t) => format!("#{}", t.Index),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn trades_before_their_tick() {
+        let store = new_trans_store(vec![
+            trade("A", 20211101, 93000000, 1),
+            trade("B", 20211101, 93000500, 2),
+            trade("A", 20211101, 93001000, 3),
+            trade("A", 20211101, 93003000, 4),
+            trade("A", 20211101, 93003000, 5),
+            trade("B", 20211101, 93010000, 6),
+        ]);
+        let ticks = vec![
+            tick("A", 20211101, 93000000),
+            tick("B", 20211101, 93001000),
+            tick("A", 20211101, 93003000),
+            tick("A", 20211101, 93002000), // 时间倒退的tick不再输出成交
+        ];
+        assert_eq!(
+            events(ticks, &store),
+            vec!["#1", "A@93000000", "#2", "B@93001000", "#3", "#4", "#5", "A@93003000", "A@93002000", "#6"]
+        );
+    }
+
+    #[test]
+    fn trades_stay_on_their_day() {
+        // 第二天开盘的成交nTime比第一天收盘的tick小，不能在第一天输出
+        let store = new_trans_store(vec![
+            trade("A", 20211101, 145900000, 1),
+            trade("A", 20211102, 93000000, 2),
+            trade("A", 20211102, 93001000, 3),
+        ]);
+        let ticks = vec![
+            tick("A", 20211101, 93000000),
+            tick("A", 20211101, 150000000),
+            tick("A", 20211102, 93000000),
+        ];
+        assert_eq!(events(ticks, &store), vec!["A@93000000", "#1", "A@150000000", "#2", "A@93000000", "#3"]);
+    }
+}
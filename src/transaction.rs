@@ -1,5 +1,8 @@
-use chrono::{DateTime, FixedOffset, TimeZone};
+use chrono::{DateTime, FixedOffset, NaiveDate};
 use serde::Deserialize;
+use super::cache;
+#[cfg(feature = "parquet")]
+use super::parquet_io;
 use super::tick::{default_dt, get_time};
 use std::collections::HashMap;
 use std::io::BufReader;
@@ -19,13 +22,33 @@ pub struct transaction {
     pub Turnover: u64,
     pub BSFlag: char,
     pub OrderKind: u64,
-    pub FunctionCode: u64,
+    pub FunctionCode: char, // 深交所撤单为'C'，成交为'0'或'F'
     pub AskOrder: u64,
     pub BidOrder: u64,
 }
 
-pub fn read_trans_data_from_file(path :&str) -> Result<HashMap<u64, transaction>, Box<dyn Error>> {
-    let mut res: HashMap<u64, transaction> = HashMap::new();
+impl transaction {
+    pub fn is_cancel(&self) -> bool {
+        self.FunctionCode == 'C'
+    }
+}
+
+// 按文件中的顺序读取所有逐笔成交，date为交易日
+pub fn read_trans_records(path: &str, date: NaiveDate) -> Result<Vec<transaction>, Box<dyn Error>> {
+    let mut res: Vec<transaction> = Vec::new();
+    if cache::is_cache(path, cache::TRANS_MAGIC) {
+        let c = cache::open_trans_cache(path, date)?;
+        for i in 0..c.len() {
+            res.push(c.transaction(i));
+        }
+        return Ok(res);
+    }
+    #[cfg(feature = "parquet")]
+    {
+        if parquet_io::is_parquet(path) {
+            return parquet_io::read_trans_parquet(path, date);
+        }
+    }
     let f = File::open(path)?;
     let reader = BufReader::new(f);
     let mut rdr = csv::Reader::from_reader(reader);
@@ -33,8 +56,125 @@ pub fn read_trans_data_from_file(path :&str) -> Result<HashMap<u64, transaction>
         // Notice that we need to provide a type hint for automatic
         // deserialization.
         let mut record: transaction = result?;
-        record.dt = get_time(record.Time);
-        res.insert(record.Time, record);
+        record.dt = get_time(date, record.Time)?;
+        res.push(record);
     }
     Ok(res)
-}
\ No newline at end of file
+}
+
+// 按代码分组、按时间排序的逐笔成交
+// 多日数据中nTime会在每天重复，按带日期的dt排序；同一毫秒内可能有多笔成交，再按Index排序
+pub struct TransStore {
+    records: HashMap<String, Vec<transaction>>,
+}
+
+pub fn new_trans_store(trans: Vec<transaction>) -> TransStore {
+    let mut records: HashMap<String, Vec<transaction>> = HashMap::new();
+    for t in trans {
+        records.entry(t.Tkr.clone()).or_default().push(t);
+    }
+    for v in records.values_mut() {
+        v.sort_by_key(|t| (t.dt, t.Index));
+    }
+    TransStore { records }
+}
+
+pub fn read_trans_store(path: &str, date: NaiveDate) -> Result<TransStore, Box<dyn Error>> {
+    Ok(new_trans_store(read_trans_records(path, date)?))
+}
+
+impl TransStore {
+    pub fn len(&self) -> usize {
+        self.records.values().map(|v| v.len()).sum()
+    }
+
+    pub fn codes(&self) -> impl Iterator<Item = &String> {
+        self.records.keys()
+    }
+
+    // 一个代码的所有逐笔成交
+    pub fn get(&self, code: &str) -> &[transaction] {
+        self.records.get(code).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    // 时间在(from, to]之间的逐笔成交，即上一个tick之后到当前tick为止的成交
+    pub fn range(
+        &self,
+        code: &str,
+        from: DateTime<FixedOffset>,
+        to: DateTime<FixedOffset>,
+    ) -> &[transaction] {
+        let trans = self.get(code);
+        let start = trans.partition_point(|t| t.dt <= from);
+        let end = trans.partition_point(|t| t.dt <= to);
+        &trans[start..end.max(start)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick::trade_date;
+
+    fn trade(code: &str, yyyymmdd: u32, time: u64, index: u64) -> transaction {
+        transaction {
+            Tkr: code.to_string(),
+            Time: time,
+            dt: get_time(trade_date(yyyymmdd).unwrap(), time).unwrap(),
+            Index: index,
+            Price: 180000,
+            Volume: 100,
+            Turnover: 1800,
+            BSFlag: 'B',
+            OrderKind: 0,
+            FunctionCode: '0',
+            AskOrder: 0,
+            BidOrder: 0,
+        }
+    }
+
+    fn indexes(trans: &[transaction]) -> Vec<u64> {
+        trans.iter().map(|t| t.Index).collect()
+    }
+
+    #[test]
+    fn range_by_time() {
+        let store = new_trans_store(vec![
+            trade("000001.SZ", 20211101, 93001000, 4),
+            trade("000001.SZ", 20211101, 93000000, 2),
+            trade("000001.SZ", 20211101, 93000000, 1),
+            trade("000002.SZ", 20211101, 93000500, 3),
+            trade("000001.SZ", 20211101, 93002000, 5),
+        ]);
+        assert_eq!(store.len(), 5);
+        let at = |ntime| get_time(trade_date(20211101).unwrap(), ntime).unwrap();
+        // 同一毫秒内按Index排序，区间左开右闭
+        assert_eq!(indexes(store.range("000001.SZ", at(92959000), at(93000000))), vec![1, 2]);
+        assert_eq!(indexes(store.range("000001.SZ", at(93000000), at(93002000))), vec![4, 5]);
+        assert!(store.range("000001.SZ", at(93002000), at(93100000)).is_empty());
+        // 时间倒退时为空
+        assert!(store.range("000001.SZ", at(93002000), at(93000000)).is_empty());
+        assert_eq!(indexes(store.range("000002.SZ", at(0), at(150000000))), vec![3]);
+        assert!(store.range("000003.SZ", at(0), at(150000000)).is_empty());
+    }
+
+    #[test]
+    fn range_across_days() {
+        // 第二天的成交nTime更小，但排在第一天之后
+        let store = new_trans_store(vec![
+            trade("000001.SZ", 20211102, 93000000, 1),
+            trade("000001.SZ", 20211101, 145000000, 2),
+            trade("000001.SZ", 20211101, 93000000, 3),
+        ]);
+        assert_eq!(indexes(store.get("000001.SZ")), vec![3, 2, 1]);
+        let first = trade_date(20211101).unwrap();
+        let second = trade_date(20211102).unwrap();
+        let range = |from, to| indexes(store.range("000001.SZ", from, to));
+        assert_eq!(range(get_time(first, 0).unwrap(), get_time(first, 150000000).unwrap()), vec![3, 2]);
+        assert_eq!(
+            range(get_time(first, 150000000).unwrap(), get_time(second, 93000000).unwrap()),
+            vec![1]
+        );
+        assert!(range(get_time(second, 93000000).unwrap(), get_time(second, 150000000).unwrap()).is_empty());
+    }
+}
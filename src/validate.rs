@@ -0,0 +1,396 @@
+use chrono::NaiveDate;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+
+use super::tick::Tick;
+
+// tick数据的检查和清洗
+// 每条规则可以配置处理方式：
+// keep: 只记录，不处理
+// drop: 丢弃这条tick
+// repair: 修复后保留，无法修复的规则按drop处理
+// 时间和成交量和同一个代码同一个交易日的上一条保留下来的tick比较
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Keep,
+    Drop,
+    Repair,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ValidateConfig {
+    pub enabled: bool,              // 回测前是否检查和清洗数据
+    pub zero_price: Action,         // nPrice为0，修复为上一条的价格
+    pub crossed_book: Action,       // 买1价不低于卖1价，无法修复
+    pub time_backwards: Action,     // nTime比上一条小，无法修复
+    pub duplicate_time: Action,     // nTime和上一条相同，无法修复
+    pub volume_backwards: Action,   // TotalVolume/TotalTurnover减少，修复为上一条的值
+    pub zero_padded_levels: Action, // 档位价格和数量不一致为0，或空档之后还有报价，修复为清空这些档位
+}
+
+impl Default for ValidateConfig {
+    fn default() -> Self {
+        ValidateConfig {
+            enabled: false,
+            zero_price: Action::Drop,
+            crossed_book: Action::Drop,
+            time_backwards: Action::Drop,
+            duplicate_time: Action::Keep,
+            volume_backwards: Action::Repair,
+            zero_padded_levels: Action::Repair,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Anomaly {
+    ZeroPrice,
+    CrossedBook,
+    TimeBackwards,
+    DuplicateTime,
+    VolumeBackwards,
+    ZeroPaddedLevels,
+}
+
+impl Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Anomaly::ZeroPrice => "zero price",
+            Anomaly::CrossedBook => "crossed book",
+            Anomaly::TimeBackwards => "time backwards",
+            Anomaly::DuplicateTime => "duplicate time",
+            Anomaly::VolumeBackwards => "volume backwards",
+            Anomaly::ZeroPaddedLevels => "zero padded levels",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+const MAX_EXAMPLES: usize = 5;
+
+// 一个文件的检查结果
+#[derive(Default)]
+pub struct Report {
+    pub rows: u64,
+    pub dropped: u64,
+    pub repaired: u64,
+    pub anomalies: BTreeMap<Anomaly, u64>,
+    pub examples: BTreeMap<Anomaly, Vec<u64>>, // 每种异常的前几条nTime
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rows:{} dropped:{} repaired:{}",
+            self.rows, self.dropped, self.repaired
+        )?;
+        for (anomaly, count) in &self.anomalies {
+            write!(f, "\n  {}: {} e.g. nTime {:?}", anomaly, count, self.examples[anomaly])?;
+        }
+        Ok(())
+    }
+}
+
+// 上一条保留下来的tick中用于检查的字段
+struct Last {
+    date: NaiveDate,
+    time: u64,
+    price: u64,
+    volume: u64,
+    turnover: u64,
+}
+
+pub struct Validator {
+    pub conf: ValidateConfig,
+    pub report: Report,
+    last: HashMap<String, Last>, // 每个代码上一条保留下来的tick
+}
+
+pub fn new_validator(conf: ValidateConfig) -> Validator {
+    Validator {
+        conf,
+        report: Report::default(),
+        last: HashMap::new(),
+    }
+}
+
+// 第一个有问题的档位：价格和数量只有一个为0，或者空档之后还有报价
+fn bad_level(levels: &[(u64, u64); 10]) -> Option<usize> {
+    let mut empty = false;
+    for (idx, (p, v)) in levels.iter().enumerate() {
+        if (*p == 0) != (*v == 0) || (empty && *p != 0) {
+            return Some(idx + 1);
+        }
+        empty = *p == 0;
+    }
+    None
+}
+
+impl Validator {
+    fn record(&mut self, anomaly: Anomaly, tick: &Tick) {
+        *self.report.anomalies.entry(anomaly).or_insert(0) += 1;
+        let examples = self.report.examples.entry(anomaly).or_default();
+        if examples.len() < MAX_EXAMPLES {
+            examples.push(tick.nTime);
+        }
+    }
+
+    fn action(&self, anomaly: Anomaly) -> Action {
+        match anomaly {
+            Anomaly::ZeroPrice => self.conf.zero_price,
+            Anomaly::CrossedBook => self.conf.crossed_book,
+            Anomaly::TimeBackwards => self.conf.time_backwards,
+            Anomaly::DuplicateTime => self.conf.duplicate_time,
+            Anomaly::VolumeBackwards => self.conf.volume_backwards,
+            Anomaly::ZeroPaddedLevels => self.conf.zero_padded_levels,
+        }
+    }
+
+    // 修复tick，无法修复时返回false
+    fn repair(&self, anomaly: Anomaly, tick: &mut Tick) -> bool {
+        match (anomaly, self.last.get(&tick.chWindCode)) {
+            (Anomaly::ZeroPrice, Some(last)) => {
+                tick.nPrice = last.price;
+                true
+            }
+            (Anomaly::VolumeBackwards, Some(last)) => {
+                tick.TotalVolume = tick.TotalVolume.max(last.volume);
+                tick.TotalTurnover = tick.TotalTurnover.max(last.turnover);
+                true
+            }
+            (Anomaly::ZeroPaddedLevels, _) => {
+                if let Some(level) = bad_level(&tick.asks()) {
+                    for l in level..=10 {
+                        tick.set_ask(l, 0, 0);
+                    }
+                }
+                if let Some(level) = bad_level(&tick.bids()) {
+                    for l in level..=10 {
+                        tick.set_bid(l, 0, 0);
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // 检查一条tick，返回None表示丢弃
+    pub fn check(&mut self, mut tick: Tick) -> Option<Tick> {
+        self.report.rows += 1;
+        // 新的交易日时间和累计成交量从头开始
+        let date = tick.dt.date_naive();
+        if self.last.get(&tick.chWindCode).is_some_and(|last| last.date != date) {
+            self.last.remove(&tick.chWindCode);
+        }
+        let mut found = Vec::new();
+        if tick.nPrice == 0 {
+            found.push(Anomaly::ZeroPrice);
+        }
+        // 涨跌停封板时一侧为空，不算交叉
+        if tick.nBidPrice1 > 0 && tick.nAskPrice1 > 0 && tick.nBidPrice1 >= tick.nAskPrice1 {
+            found.push(Anomaly::CrossedBook);
+        }
+        if let Some(last) = self.last.get(&tick.chWindCode) {
+            if tick.nTime < last.time {
+                found.push(Anomaly::TimeBackwards);
+            } else if tick.nTime == last.time {
+                found.push(Anomaly::DuplicateTime);
+            }
+            if tick.TotalVolume < last.volume || tick.TotalTurnover < last.turnover {
+                found.push(Anomaly::VolumeBackwards);
+            }
+        }
+        if bad_level(&tick.asks()).is_some() || bad_level(&tick.bids()).is_some() {
+            found.push(Anomaly::ZeroPaddedLevels);
+        }
+
+        let mut drop = false;
+        let mut repaired = false;
+        for anomaly in found {
+            self.record(anomaly, &tick);
+            match self.action(anomaly) {
+                Action::Keep => {}
+                Action::Drop => drop = true,
+                Action::Repair => {
+                    if self.repair(anomaly, &mut tick) {
+                        repaired = true;
+                    } else {
+                        drop = true;
+                    }
+                }
+            }
+        }
+        if drop {
+            self.report.dropped += 1;
+            return None;
+        }
+        if repaired {
+            self.report.repaired += 1;
+        }
+        self.last.insert(
+            tick.chWindCode.clone(),
+            Last {
+                date,
+                time: tick.nTime,
+                price: tick.nPrice,
+                volume: tick.TotalVolume,
+                turnover: tick.TotalTurnover,
+            },
+        );
+        Some(tick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::book::empty_tick;
+    use crate::tick::{get_time, trade_date};
+
+    fn tick(code: &str, time: u64, price: u64, volume: u64) -> Tick {
+        let mut t = empty_tick(code);
+        t.nTime = time;
+        t.nPrice = price;
+        t.TotalVolume = volume;
+        t.nBidPrice1 = price - 100;
+        t.nBidVolume1 = 100;
+        t.nAskPrice1 = price + 100;
+        t.nAskVolume1 = 100;
+        t
+    }
+
+    fn enabled() -> ValidateConfig {
+        ValidateConfig {
+            enabled: true,
+            ..ValidateConfig::default()
+        }
+    }
+
+    #[test]
+    fn clean_ticks_pass() {
+        let mut v = new_validator(enabled());
+        assert!(v.check(tick("601012.SH", 93000000, 400000, 100)).is_some());
+        assert!(v.check(tick("601012.SH", 93003000, 400100, 200)).is_some());
+        assert_eq!(v.report.rows, 2);
+        assert!(v.report.anomalies.is_empty());
+    }
+
+    #[test]
+    fn time_backwards_and_duplicate() {
+        let mut v = new_validator(enabled());
+        v.check(tick("601012.SH", 93003000, 400000, 100));
+        // 时间倒退默认丢弃，相同时间默认只记录
+        assert!(v.check(tick("601012.SH", 93000000, 400000, 100)).is_none());
+        assert!(v.check(tick("601012.SH", 93003000, 400000, 100)).is_some());
+        assert_eq!(v.report.anomalies[&Anomaly::TimeBackwards], 1);
+        assert_eq!(v.report.anomalies[&Anomaly::DuplicateTime], 1);
+        assert_eq!(v.report.examples[&Anomaly::TimeBackwards], vec![93000000]);
+        assert_eq!(v.report.dropped, 1);
+    }
+
+    #[test]
+    fn symbols_are_checked_separately() {
+        let mut v = new_validator(enabled());
+        v.check(tick("601012.SH", 93003000, 400000, 1000));
+        // 不同代码的时间相同、成交量更小都是正常的
+        assert!(v.check(tick("600000.SH", 93003000, 80000, 100)).is_some());
+        assert!(v.check(tick("600000.SH", 93000000, 80000, 100)).is_none());
+        assert_eq!(v.report.anomalies.len(), 1);
+        assert_eq!(v.report.anomalies[&Anomaly::TimeBackwards], 1);
+    }
+
+    #[test]
+    fn new_trade_date_starts_over() {
+        let mut v = new_validator(ValidateConfig {
+            volume_backwards: Action::Repair,
+            ..enabled()
+        });
+        let mut day1 = tick("601012.SH", 150000000, 400000, 50000);
+        day1.dt = get_time(trade_date(0).unwrap(), 150000000).unwrap();
+        assert!(v.check(day1).is_some());
+        // 第二天的时间和累计成交量都比前一天收盘时小
+        for (ntime, volume) in [(93000000, 100), (93003000, 300)] {
+            let mut t = tick("601012.SH", ntime, 401000, volume);
+            t.dt = get_time(trade_date(20211101).unwrap(), ntime).unwrap();
+            let t = v.check(t).unwrap();
+            assert_eq!(t.TotalVolume, volume);
+        }
+        assert_eq!((v.report.dropped, v.report.repaired), (0, 0));
+        assert!(v.report.anomalies.is_empty());
+    }
+
+    #[test]
+    fn repair_zero_price_and_volume() {
+        let mut v = new_validator(ValidateConfig {
+            zero_price: Action::Repair,
+            ..enabled()
+        });
+        v.check(tick("601012.SH", 93000000, 400000, 500));
+        let mut t = tick("601012.SH", 93003000, 400100, 400);
+        t.nPrice = 0;
+        let t = v.check(t).unwrap();
+        assert_eq!(t.nPrice, 400000);
+        assert_eq!(t.TotalVolume, 500);
+        assert_eq!(v.report.repaired, 1);
+        assert_eq!(v.report.anomalies[&Anomaly::ZeroPrice], 1);
+        assert_eq!(v.report.anomalies[&Anomaly::VolumeBackwards], 1);
+    }
+
+    #[test]
+    fn zero_price_without_last_is_dropped() {
+        let mut v = new_validator(ValidateConfig {
+            zero_price: Action::Repair,
+            ..enabled()
+        });
+        let mut t = tick("601012.SH", 93000000, 400000, 100);
+        t.nPrice = 0;
+        assert!(v.check(t).is_none());
+    }
+
+    #[test]
+    fn crossed_book() {
+        let mut v = new_validator(enabled());
+        let mut t = tick("601012.SH", 93000000, 400000, 100);
+        t.nBidPrice1 = t.nAskPrice1;
+        assert!(v.check(t).is_none());
+        // 封板时一侧为空不算交叉
+        let mut t = tick("601012.SH", 93003000, 440000, 100);
+        t.nAskPrice1 = 0;
+        t.nAskVolume1 = 0;
+        assert!(v.check(t).is_some());
+        assert_eq!(v.report.anomalies[&Anomaly::CrossedBook], 1);
+    }
+
+    #[test]
+    fn zero_padded_levels() {
+        let mut v = new_validator(enabled());
+        let mut t = tick("601012.SH", 93000000, 400000, 100);
+        // 第2档为空，第3档还有报价
+        t.nAskPrice3 = 400300;
+        t.nAskVolume3 = 100;
+        t.nBidPrice2 = 399800;
+        let t = v.check(t).unwrap();
+        assert_eq!((t.nAskPrice1, t.nAskPrice3, t.nAskVolume3), (400100, 0, 0));
+        assert_eq!((t.nBidPrice1, t.nBidPrice2), (399900, 0));
+        assert_eq!(v.report.repaired, 1);
+    }
+
+    #[test]
+    fn keep_only_records() {
+        let mut v = new_validator(ValidateConfig {
+            crossed_book: Action::Keep,
+            ..enabled()
+        });
+        let mut t = tick("601012.SH", 93000000, 400000, 100);
+        t.nBidPrice1 = t.nAskPrice1;
+        assert!(v.check(t).is_some());
+        assert_eq!((v.report.dropped, v.report.repaired), (0, 0));
+        assert_eq!(v.report.anomalies[&Anomaly::CrossedBook], 1);
+    }
+}
@@ -0,0 +1,139 @@
+use chrono::{DateTime, Duration, FixedOffset};
+use std::collections::VecDeque;
+
+// 按时间滑动的价格窗口，用单调队列维护窗口内的最低价和最高价
+// 每次更新均摊O(1)，只保存(时间, 价格)，不保存整个tick
+// mins中价格单调不减，队首为最低价；maxs中价格单调不增，队首为最高价
+// 价格相同时保留较早的，和时间窗以最低价出现的时间为起点一致
+pub struct MinMaxWindow {
+    span: Duration,
+    mins: VecDeque<(DateTime<FixedOffset>, u64)>,
+    maxs: VecDeque<(DateTime<FixedOffset>, u64)>,
+}
+
+pub fn new_min_max_window(seconds: i64) -> MinMaxWindow {
+    MinMaxWindow {
+        span: Duration::seconds(seconds),
+        mins: VecDeque::new(),
+        maxs: VecDeque::new(),
+    }
+}
+
+impl MinMaxWindow {
+    pub fn push(&mut self, dt: DateTime<FixedOffset>, price: u64) {
+        // 移除超出时间窗的价格
+        while let Some((t, _)) = self.mins.front() {
+            if dt - *t < self.span {
+                break;
+            }
+            self.mins.pop_front();
+        }
+        while let Some((t, _)) = self.maxs.front() {
+            if dt - *t < self.span {
+                break;
+            }
+            self.maxs.pop_front();
+        }
+        // 比新价格高的不可能再成为最低价，反之亦然
+        while let Some((_, p)) = self.mins.back() {
+            if *p <= price {
+                break;
+            }
+            self.mins.pop_back();
+        }
+        while let Some((_, p)) = self.maxs.back() {
+            if *p >= price {
+                break;
+            }
+            self.maxs.pop_back();
+        }
+        self.mins.push_back((dt, price));
+        self.maxs.push_back((dt, price));
+    }
+
+    // 窗口内的最低价及其时间
+    pub fn min(&self) -> Option<(DateTime<FixedOffset>, u64)> {
+        self.mins.front().copied()
+    }
+
+    // 窗口内的最高价及其时间
+    pub fn max(&self) -> Option<(DateTime<FixedOffset>, u64)> {
+        self.maxs.front().copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.mins.clear();
+        self.maxs.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick::{get_time, trade_date};
+
+    fn at(ntime: u64) -> DateTime<FixedOffset> {
+        get_time(trade_date(0).unwrap(), ntime).unwrap()
+    }
+
+    #[test]
+    fn empty() {
+        let w = new_min_max_window(60);
+        assert_eq!(w.min(), None);
+        assert_eq!(w.max(), None);
+    }
+
+    #[test]
+    fn min_max_and_expire() {
+        let mut w = new_min_max_window(10);
+        w.push(at(93000000), 400000);
+        w.push(at(93003000), 390000);
+        w.push(at(93006000), 410000);
+        assert_eq!(w.min(), Some((at(93003000), 390000)));
+        assert_eq!(w.max(), Some((at(93006000), 410000)));
+        // 正好10秒之前的价格移出窗口
+        w.push(at(93013000), 395000);
+        assert_eq!(w.min(), Some((at(93013000), 395000)));
+        assert_eq!(w.max(), Some((at(93006000), 410000)));
+        w.push(at(93016000), 405000);
+        assert_eq!(w.min(), Some((at(93013000), 395000)));
+        assert_eq!(w.max(), Some((at(93016000), 405000)));
+    }
+
+    #[test]
+    fn equal_prices_keep_earliest() {
+        let mut w = new_min_max_window(60);
+        w.push(at(93000000), 400000);
+        w.push(at(93003000), 400000);
+        assert_eq!(w.min(), Some((at(93000000), 400000)));
+        assert_eq!(w.max(), Some((at(93000000), 400000)));
+    }
+
+    #[test]
+    fn same_as_brute_force() {
+        let mut w = new_min_max_window(9);
+        let mut all = Vec::new();
+        let mut price = 400000u64;
+        for i in 0..200u64 {
+            // 伪随机价格，3秒一个tick
+            price = price + (i * 7919 % 13) * 100 - 600;
+            let dt = at(93000000) + Duration::seconds(i as i64 * 3);
+            w.push(dt, price);
+            all.push((dt, price));
+            let inside: Vec<_> = all.iter().filter(|(t, _)| dt - *t < Duration::seconds(9)).collect();
+            let min = inside.iter().map(|(_, p)| *p).min();
+            let max = inside.iter().map(|(_, p)| *p).max();
+            assert_eq!(w.min().map(|(_, p)| p), min);
+            assert_eq!(w.max().map(|(_, p)| p), max);
+        }
+    }
+
+    #[test]
+    fn clear() {
+        let mut w = new_min_max_window(60);
+        w.push(at(93000000), 400000);
+        w.clear();
+        assert_eq!(w.min(), None);
+        assert_eq!(w.max(), None);
+    }
+}